@@ -1,16 +1,52 @@
 use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Deserializer};
 use serde_derive::*;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use crate::gravatar::gravatar_url_for_email;
-use crate::markdown::md_to_html;
-use crate::utils::calculate_hash;
+use crate::markdown::{comment_to_html, MarkdownOptions};
+use crate::utils::base32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationStatus
+{
+    Pending,
+    #[default]
+    Approved,
+    Rejected,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Editor
+{
+    Author,
+    Admin,
+}
+
+
+/// A previous version of a comment's text, together with the time it was replaced and who
+/// replaced it.
+#[derive(Clone, Debug, Serialize, Deserialize, Hash)]
+pub struct Revision
+{
+    pub text: String,
+    pub timestamp: DateTime<Utc>,
+    pub editor: Editor,
+}
+
 
 #[derive(Clone, Debug, Serialize, Deserialize, Hash)]
 pub struct Comment
 {
     pub id: Uuid,
-    pub idh: u64,
+    #[serde(deserialize_with = "deserialize_idh")]
+    pub idh: String,
+    #[serde(default)]
+    pub legacy_idh: Option<u64>,
     pub timestamp: DateTime<Utc>,
     pub path: String,
     pub author_name: Option<String>,
@@ -18,27 +54,120 @@ pub struct Comment
     pub author_gravatar: String,
     pub text: String,
     pub text_html: String,
+    /// The fingerprint of the Markdown options `text_html` was rendered with.
+    #[serde(default)]
+    pub renderer: Option<String>,
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub status: ModerationStatus,
+    #[serde(default)]
+    pub edited: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
 }
 
 
 impl Comment
 {
     pub fn new(path: &str, text: &str, author_name: Option<&str>, author_email: Option<&str>) -> Comment {
+        Self::with_markdown(path, text, author_name, author_email, &MarkdownOptions::default(), false)
+    }
+
+    /// Creates a comment whose HTML is rendered with the options. Links in comments by
+    /// first-time authors are handled as set in the options.
+    pub fn with_markdown(path: &str, text: &str, author_name: Option<&str>, author_email: Option<&str>,
+                         options: &MarkdownOptions, first_time_author: bool) -> Comment {
         let id = Uuid::new_v4();
-        let mut timestamp = Utc::now();
-        timestamp = timestamp - Duration::microseconds(timestamp.timestamp_subsec_micros() as i64);
-        Comment {
+        let mut comment = Comment {
             id,
-            idh: calculate_hash(&id),
-            timestamp,
+            idh: public_id(&id),
+            legacy_idh: None,
+            timestamp: now(),
             path: path.to_owned(),
             author_name: author_name.map(|n| n.to_owned()),
             author_email: author_email.map(|e| e.to_owned()),
             author_gravatar: gravatar_url_for_email(author_email),
             text: text.to_owned(),
-            text_html: md_to_html(text),
+            text_html: String::new(),
+            renderer: None,
+            parent_id: None,
+            deleted: false,
+            status: ModerationStatus::Approved,
+            edited: None,
+            revisions: Vec::new(),
+        };
+        comment.render(options, first_time_author);
+        comment
+    }
+
+    /// Replaces an idh created by earlier versions with the public id derived from the comment's
+    /// id. The old idh is kept so that existing permalinks can still be resolved. Returns true
+    /// if the comment was changed.
+    pub fn migrate_idh(&mut self) -> bool {
+        let idh = public_id(&self.id);
+        if self.idh == idh {
+            return false;
         }
+        self.legacy_idh = self.idh.parse().ok();
+        self.idh = idh;
+        true
+    }
+
+    /// Converts the text to HTML again, for example after the Markdown options have changed.
+    /// Links in comments by first-time authors are handled as set in the options.
+    pub fn render(&mut self, options: &MarkdownOptions, first_time_author: bool) {
+        self.text_html = comment_to_html(&self.text, options, &self.idh, first_time_author);
+        self.renderer = Some(options.fingerprint());
+    }
+
+    pub fn edit(&mut self, text: &str, editor: Editor, options: &MarkdownOptions, first_time_author: bool) {
+        let timestamp = now();
+        let previous = std::mem::replace(&mut self.text, text.to_owned());
+        self.revisions.push(Revision { text: previous, timestamp, editor });
+        self.render(options, first_time_author);
+        self.edited = Some(timestamp);
+    }
+
+    pub fn mark_deleted(&mut self) {
+        self.author_name = None;
+        self.author_email = None;
+        self.author_gravatar = gravatar_url_for_email(None);
+        self.text = String::new();
+        self.text_html = String::new();
+        self.revisions.clear();
+        self.deleted = true;
+    }
+}
+
+
+/// Derives the public id of a comment, which is used in permalinks and the API. It consists of
+/// the first 80 bits of the SHA-256 hash of the UUID's bytes, encoded as 16 lower case base 32
+/// characters.
+pub fn public_id(id: &Uuid) -> String {
+    let hash = Sha256::digest(id.as_bytes());
+    base32(&hash[..10])
+}
+
+/// Reads an idh written by earlier versions, which was a number, as well as current public ids.
+fn deserialize_idh<'de, D>(deserializer: D) -> Result<String, D::Error> where D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredIdh {
+        Legacy(u64),
+        Public(String),
     }
+    Ok(match StoredIdh::deserialize(deserializer)? {
+        StoredIdh::Legacy(idh) => idh.to_string(),
+        StoredIdh::Public(idh) => idh,
+    })
+}
+
+fn now() -> DateTime<Utc> {
+    let timestamp = Utc::now();
+    timestamp - Duration::microseconds(timestamp.timestamp_subsec_micros() as i64)
 }
 
 
@@ -70,4 +199,84 @@ mod tests {
         let comment = Comment::new("", "_foo_", None, None);
         assert_eq!("<p><em>foo</em></p>", comment.text_html.trim());
     }
+
+    #[test]
+    fn editing_updates_html_and_records_time() {
+        let mut comment = Comment::new("", "_foo_", None, None);
+
+        comment.edit("_bar_", Editor::Author, &MarkdownOptions::default(), false);
+
+        assert_eq!("_bar_", comment.text);
+        assert_eq!("<p><em>bar</em></p>", comment.text_html.trim());
+        assert_eq!(0, (Utc::now() - comment.edited.expect("expected edit time")).num_seconds());
+    }
+
+    #[test]
+    fn editing_keeps_previous_versions() {
+        let mut comment = Comment::new("", "first", None, None);
+
+        comment.edit("second", Editor::Author, &MarkdownOptions::default(), false);
+        comment.edit("third", Editor::Admin, &MarkdownOptions::default(), false);
+
+        assert_eq!(2, comment.revisions.len());
+        assert_eq!("first", comment.revisions[0].text);
+        assert_eq!(Editor::Author, comment.revisions[0].editor);
+        assert_eq!("second", comment.revisions[1].text);
+        assert_eq!(Editor::Admin, comment.revisions[1].editor);
+        assert_eq!(comment.edited, Some(comment.revisions[1].timestamp));
+    }
+
+    #[test]
+    fn marking_as_deleted_removes_content_but_keeps_identity() {
+        let mut comment = Comment::new("/a/", "_foo_", Some("Joe Bloggs"), Some("joe@example.org"));
+        comment.edit("_bar_", Editor::Author, &MarkdownOptions::default(), false);
+        let (id, idh) = (comment.id, comment.idh.clone());
+
+        comment.mark_deleted();
+
+        assert!(comment.deleted);
+        assert_eq!((id, idh), (comment.id, comment.idh));
+        assert_eq!("", comment.text);
+        assert_eq!("", comment.text_html);
+        assert_eq!(None, comment.author_name);
+        assert_eq!(None, comment.author_email);
+        assert!(comment.revisions.is_empty());
+    }
+
+    #[test]
+    fn derives_public_id_from_uuid() {
+        let id = Uuid::parse_str("b9a3c7a2-4f5e-4a6b-9c1d-2e3f4a5b6c7d").unwrap();
+        let comment = Comment::new("/a/", "text", None, None);
+
+        assert_eq!(public_id(&id), public_id(&id));
+        assert_eq!(16, public_id(&id).len());
+        assert_eq!(public_id(&comment.id), comment.idh);
+    }
+
+    #[test]
+    fn migrates_numeric_idh_to_public_id() {
+        let comment = Comment::new("/a/", "text", None, None);
+        let mut json: serde_json::Value = serde_json::to_value(&comment).unwrap();
+        json["idh"] = serde_json::json!(1234567890123456789u64);
+        json.as_object_mut().unwrap().remove("legacy_idh");
+
+        let mut migrated: Comment = serde_json::from_value(json).unwrap();
+        assert_eq!("1234567890123456789", migrated.idh);
+
+        assert!(migrated.migrate_idh());
+        assert_eq!(comment.idh, migrated.idh);
+        assert_eq!(Some(1234567890123456789), migrated.legacy_idh);
+        assert!(!migrated.migrate_idh());
+    }
+
+    #[test]
+    fn treats_comments_without_status_as_approved() {
+        let comment = Comment::new("/a/", "text", None, None);
+        let mut json: serde_json::Value = serde_json::to_value(&comment).unwrap();
+        json.as_object_mut().unwrap().remove("status");
+
+        let comment: Comment = serde_json::from_value(json).unwrap();
+
+        assert_eq!(ModerationStatus::Approved, comment.status);
+    }
 }
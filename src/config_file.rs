@@ -0,0 +1,55 @@
+use std::fs;
+
+use serde_derive::Deserialize;
+
+use crate::sanitizer::SanitizerConfig;
+
+
+/// The settings that are too structured for command-line options. The file is in JSON format
+/// and every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile
+{
+    #[serde(default)]
+    pub sanitizer: SanitizerConfig,
+}
+
+impl ConfigFile
+{
+    /// Reads and validates a configuration file. The error describes what is wrong, including
+    /// the position of syntax errors.
+    pub fn read(filename: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(filename)
+            .map_err(|e| format!("Failed to read configuration file {}: {}", filename, e))?;
+        ConfigFile::parse(&contents).map_err(|e| format!("Error in configuration file {}: {}", filename, e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let config: ConfigFile = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        config.sanitizer.validate().map_err(|e| format!("sanitizer: {}", e))?;
+        Ok(config)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sanitizer_section() {
+        let config = ConfigFile::parse(r#"{ "sanitizer": { "tags": ["p", "em"] } }"#).unwrap();
+
+        assert_eq!(Some(vec!["p".to_owned(), "em".to_owned()]), config.sanitizer.tags);
+    }
+
+    #[test]
+    fn reports_unknown_fields_and_invalid_sanitizer_settings() {
+        let error = ConfigFile::parse(r#"{ "sanitiser": {} }"#).unwrap_err();
+        assert!(error.starts_with("unknown field `sanitiser`"), "{}", error);
+
+        let error = ConfigFile::parse(r#"{ "sanitizer": { "tags": ["iframe"] } }"#).unwrap_err();
+        assert_eq!("sanitizer: The tag \"iframe\" cannot be allowed in comments", error);
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::comment::{Comment, ModerationStatus};
+use crate::mail::Message;
+use crate::moderation_links::ModerationLinks;
+use crate::notifier::{Event, MailTransport, Notifier};
+use crate::utils::{self, escape_markup, page_url};
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DigestInterval
+{
+    Hourly,
+    Daily,
+}
+
+impl DigestInterval
+{
+    pub fn duration(&self) -> Duration {
+        match self {
+            DigestInterval::Hourly => Duration::hours(1),
+            DigestInterval::Daily => Duration::days(1),
+        }
+    }
+}
+
+impl FromStr for DigestInterval
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(DigestInterval::Hourly),
+            "daily" => Ok(DigestInterval::Daily),
+            _ => Err(format!("Unknown digest interval: {}", s)),
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct DigestState
+{
+    last_sent: DateTime<Utc>,
+    comments: Vec<Comment>,
+}
+
+
+/// Collects comments until a digest is due. If a file is given, the collected comments and
+/// the time the last digest was sent are kept in it, so that no comment is lost or sent twice
+/// when the server restarts.
+pub struct Digest
+{
+    filename: Option<String>,
+    interval: Duration,
+    state: Mutex<DigestState>,
+    /// Held while a digest is sent, so that the same comments are not sent twice.
+    sending: Mutex<()>,
+}
+
+impl Digest
+{
+    pub fn open(filename: &str, interval: Duration) -> Result<Self, String> {
+        let state = match fs::read_to_string(filename) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Failed to read digest from file {}: {}", filename, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => DigestState { last_sent: Utc::now(), comments: Vec::new() },
+            Err(e) => return Err(format!("Failed to read file {}: {}", filename, e)),
+        };
+        let digest = Digest { filename: Some(filename.to_owned()), interval, state: Mutex::new(state), sending: Mutex::new(()) };
+        digest.save(&digest.state.lock().unwrap())?;
+        Ok(digest)
+    }
+
+    /// Creates a digest that is only kept in memory.
+    pub fn new(interval: Duration) -> Self {
+        let state = DigestState { last_sent: Utc::now(), comments: Vec::new() };
+        Digest { filename: None, interval, state: Mutex::new(state), sending: Mutex::new(()) }
+    }
+
+    /// Adds a comment to the next digest. Comments that are already collected are ignored.
+    pub fn add(&self, comment: &Comment) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.comments.iter().any(|c| c.id == comment.id) {
+            state.comments.push(comment.clone());
+            self.save(&state)?;
+        }
+        Ok(())
+    }
+
+    /// Passes the collected comments to the send function if the interval has passed since
+    /// the last digest. The comments are only removed if they were sent successfully. Comments
+    /// can be added while the digest is sent; they are kept for the next one. Returns whether
+    /// a digest was sent.
+    pub fn send_if_due<F>(&self, now: DateTime<Utc>, send: F) -> Result<bool, String>
+        where F: FnOnce(&[Comment]) -> Result<(), String> {
+        let _sending = self.sending.lock().unwrap();
+        let comments = {
+            let state = self.state.lock().unwrap();
+            if state.comments.is_empty() || now < state.last_sent + self.interval {
+                return Ok(false);
+            }
+            state.comments.clone()
+        };
+        send(&comments)?;
+        let sent: HashSet<_> = comments.iter().map(|c| c.id).collect();
+        let mut state = self.state.lock().unwrap();
+        state.comments.retain(|c| !sent.contains(&c.id));
+        state.last_sent = now;
+        self.save(&state)?;
+        Ok(true)
+    }
+
+    fn save(&self, state: &DigestState) -> Result<(), String> {
+        match &self.filename {
+            Some(filename) => utils::write_file_atomically(Path::new(filename), &utils::to_json(state))
+                .map_err(|e| format!("Failed to write file {}: {}", filename, e)),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// Sends one mail with all comments posted in an interval, grouped by page, instead of one
+/// mail per comment.
+pub struct DigestNotifier
+{
+    digest: Digest,
+    recipient: String,
+    sender: String,
+    site_url: Option<String>,
+    transport: MailTransport,
+    moderation_links: Option<ModerationLinks>,
+}
+
+impl DigestNotifier
+{
+    pub fn new(digest: Digest, recipient: &str, sender: &str, site_url: &Option<String>, transport: MailTransport) -> Self {
+        DigestNotifier {
+            digest,
+            recipient: recipient.to_owned(),
+            sender: sender.to_owned(),
+            site_url: site_url.clone(),
+            transport,
+            moderation_links: None,
+        }
+    }
+
+    /// Adds links to approve and delete each comment to the digest.
+    pub fn with_moderation_links(mut self, links: ModerationLinks) -> Self {
+        self.moderation_links = Some(links);
+        self
+    }
+
+    /// Starts a thread that checks every minute whether a digest is due.
+    pub fn start(notifier: &Arc<DigestNotifier>) {
+        let notifier = Arc::clone(notifier);
+        thread::spawn(move || loop {
+            thread::sleep(std::time::Duration::from_secs(60));
+            let result = notifier.digest.send_if_due(Utc::now(), |comments| {
+                notifier.transport.send(&notifier.message_for(comments))
+            });
+            if let Err(message) = result {
+                println!("Failed to send digest; will try again: {}", message);
+            }
+        });
+    }
+
+    fn message_for(&self, comments: &[Comment]) -> Message {
+        let mut by_path: BTreeMap<&str, Vec<&Comment>> = BTreeMap::new();
+        for comment in comments {
+            by_path.entry(&comment.path).or_default().push(comment);
+        }
+        let mut text = String::new();
+        let mut html = String::new();
+        for (path, comments) in by_path {
+            let url = page_url(&self.site_url, path);
+            text.push_str(&format!("{} ({})\n\n", url, count(comments.len())));
+            html.push_str(&format!("<h2><a href=\"{}\">{}</a> ({})</h2>\n", escape_markup(&url), escape_markup(path), count(comments.len())));
+            for comment in comments {
+                let author = comment.author_name.as_deref().unwrap_or("Anonymous");
+                let link = format!("{}#comment-{}", url, comment.idh);
+                let pending = if comment.status == ModerationStatus::Pending { " (awaiting moderation)" } else { "" };
+                text.push_str(&format!("{} at {}{}:\n{}\n{}\n", author, comment.timestamp.to_rfc2822(), pending, comment.text, link));
+                html.push_str(&format!("<h3><a href=\"{}\">{} at {}</a>{}</h3>\n{}",
+                                       escape_markup(&link), escape_markup(author), comment.timestamp.to_rfc2822(), pending,
+                                       comment.text_html));
+                if let Some(links) = &self.moderation_links {
+                    links.append_to(comment, &mut text, &mut html);
+                }
+                text.push('\n');
+            }
+        }
+        Message {
+            from: self.sender.clone(),
+            to: self.recipient.clone(),
+            subject: format!("Digest: {}", count(comments.len())),
+            text,
+            html: Some(html),
+        }
+    }
+}
+
+impl Notifier for DigestNotifier
+{
+    fn name(&self) -> String {
+        format!("mail:{}", self.recipient)
+    }
+
+    fn notify(&self, event: Event, comment: &Comment) -> Result<(), String> {
+        if event == Event::Posted {
+            self.digest.add(comment)?;
+        }
+        Ok(())
+    }
+}
+
+fn count(n: usize) -> String {
+    if n == 1 { "1 new comment".to_owned() } else { format!("{} new comments", n) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_groups_comments_by_page() {
+        let digest = Digest::new(Duration::hours(1));
+        let notifier = DigestNotifier::new(digest, "joe@example.org", "quvyn@example.org",
+                                           &Some("https://example.org".to_owned()), MailTransport::Sendmail);
+        let comments = vec![Comment::new("/b/", "First", None, None), Comment::new("/a/", "Second", None, None),
+                            Comment::new("/b/", "Third", None, None)];
+
+        let message = notifier.message_for(&comments);
+
+        assert_eq!("Digest: 3 new comments", message.subject);
+        let a = message.text.find("https://example.org/a/ (1 new comment)").unwrap();
+        let b = message.text.find("https://example.org/b/ (2 new comments)").unwrap();
+        assert!(a < b && message.text.find("Second").unwrap() < b);
+        assert!(message.text.find("First").unwrap() < message.text.find("Third").unwrap());
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::comment::Comment;
+use crate::utils::{escape_markup as escape, page_url};
+
+/// Describes a feed. Links are built from the site URL, if one is known, and the path of
+/// the page the comments belong to.
+pub struct FeedInfo<'a> {
+    pub title: String,
+    pub site_url: &'a Option<String>,
+    pub path: &'a str,
+    /// When the comments in the feed were last changed, if there are any.
+    pub updated: Option<DateTime<Utc>>,
+}
+
+impl FeedInfo<'_> {
+    fn page_url(&self, path: &str) -> String {
+        page_url(self.site_url, path)
+    }
+
+    fn comment_url(&self, comment: &Comment) -> String {
+        format!("{}#comment-{}", self.page_url(&comment.path), comment.idh)
+    }
+
+    /// The feed id must be an absolute IRI. Without a site URL the page URL is only a path,
+    /// so a UUID is derived from the path instead.
+    fn feed_id(&self) -> String {
+        match self.site_url {
+            Some(_) => self.page_url(self.path),
+            None => format!("urn:uuid:{}", path_uuid(self.path)),
+        }
+    }
+}
+
+
+/// Derives a name-based UUID (version 8, see RFC 9562) from the SHA-256 hash of the path.
+fn path_uuid(path: &str) -> Uuid {
+    let hash = Sha256::digest(path.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Uuid::from_bytes(bytes)
+}
+
+
+fn updated(comment: &Comment) -> DateTime<Utc> {
+    comment.edited.unwrap_or(comment.timestamp)
+}
+
+fn author(comment: &Comment) -> &str {
+    comment.author_name.as_deref().unwrap_or("Anonymous")
+}
+
+
+pub fn atom(info: &FeedInfo, comments: &[Comment]) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <id>{}</id>\n", escape(&info.feed_id())));
+    xml.push_str(&format!("  <title>{}</title>\n", escape(&info.title)));
+    xml.push_str(&format!("  <link href=\"{}\"/>\n", escape(&info.page_url(info.path))));
+    let updated_all = info.updated.unwrap_or_else(Utc::now);
+    xml.push_str(&format!("  <updated>{}</updated>\n", updated_all.to_rfc3339()));
+    for comment in comments {
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!("    <id>urn:uuid:{}</id>\n", comment.id));
+        xml.push_str(&format!("    <title>Comment by {}</title>\n", escape(author(comment))));
+        xml.push_str(&format!("    <link href=\"{}\"/>\n", escape(&info.comment_url(comment))));
+        xml.push_str(&format!("    <published>{}</published>\n", comment.timestamp.to_rfc3339()));
+        xml.push_str(&format!("    <updated>{}</updated>\n", updated(comment).to_rfc3339()));
+        xml.push_str(&format!("    <author><name>{}</name></author>\n", escape(author(comment))));
+        xml.push_str(&format!("    <content type=\"html\">{}</content>\n", escape(&comment.text_html)));
+        xml.push_str("  </entry>\n");
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+
+pub fn rss(info: &FeedInfo, comments: &[Comment]) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n");
+    xml.push_str("  <channel>\n");
+    xml.push_str(&format!("    <title>{}</title>\n", escape(&info.title)));
+    xml.push_str(&format!("    <link>{}</link>\n", escape(&info.page_url(info.path))));
+    xml.push_str(&format!("    <description>{}</description>\n", escape(&info.title)));
+    if let Some(timestamp) = info.updated {
+        xml.push_str(&format!("    <lastBuildDate>{}</lastBuildDate>\n", timestamp.to_rfc2822()));
+    }
+    for comment in comments {
+        xml.push_str("    <item>\n");
+        xml.push_str(&format!("      <guid isPermaLink=\"false\">urn:uuid:{}</guid>\n", comment.id));
+        xml.push_str(&format!("      <title>Comment by {}</title>\n", escape(author(comment))));
+        xml.push_str(&format!("      <link>{}</link>\n", escape(&info.comment_url(comment))));
+        xml.push_str(&format!("      <pubDate>{}</pubDate>\n", comment.timestamp.to_rfc2822()));
+        xml.push_str(&format!("      <dc:creator>{}</dc:creator>\n", escape(author(comment))));
+        xml.push_str(&format!("      <description>{}</description>\n", escape(&comment.text_html)));
+        xml.push_str("    </item>\n");
+    }
+    xml.push_str("  </channel>\n");
+    xml.push_str("</rss>\n");
+    xml
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(site_url: &Option<String>) -> FeedInfo<'_> {
+        FeedInfo { title: "Comments on /a/".to_owned(), site_url, path: "/a/", updated: None }
+    }
+
+    #[test]
+    fn atom_feed_contains_entry_for_comment() {
+        let comment = Comment::new("/a/", "_foo_", Some("Joe Bloggs"), None);
+
+        let xml = atom(&info(&Some("https://example.org/".to_owned())), std::slice::from_ref(&comment));
+
+        assert!(xml.contains(&format!("<id>urn:uuid:{}</id>", comment.id)));
+        assert!(xml.contains(&format!("<link href=\"https://example.org/a/#comment-{}\"/>", comment.idh)));
+        assert!(xml.contains("<name>Joe Bloggs</name>"));
+        assert!(xml.contains("<content type=\"html\">&lt;p&gt;&lt;em&gt;foo&lt;/em&gt;&lt;/p&gt;"));
+    }
+
+    #[test]
+    fn atom_feed_id_is_absolute_without_site_url() {
+        let with_site = atom(&info(&Some("https://example.org/".to_owned())), &[]);
+        let without_site = atom(&info(&None), &[]);
+
+        assert!(with_site.contains("<id>https://example.org/a/</id>"));
+        assert!(without_site.contains(&format!("<id>urn:uuid:{}</id>", path_uuid("/a/"))));
+        assert_eq!(path_uuid("/a/"), path_uuid("/a/"));
+        assert_ne!(path_uuid("/a/"), path_uuid("/b/"));
+    }
+
+    #[test]
+    fn rss_feed_contains_item_for_comment() {
+        let comment = Comment::new("/a/", "_foo_", None, None);
+
+        let xml = rss(&info(&None), std::slice::from_ref(&comment));
+
+        assert!(xml.contains(&format!("<guid isPermaLink=\"false\">urn:uuid:{}</guid>", comment.id)));
+        assert!(xml.contains(&format!("<link>/a/#comment-{}</link>", comment.idh)));
+        assert!(xml.contains("<dc:creator>Anonymous</dc:creator>"));
+        assert!(xml.contains(&format!("<pubDate>{}</pubDate>", comment.timestamp.to_rfc2822())));
+    }
+}
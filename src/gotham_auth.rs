@@ -0,0 +1,124 @@
+use std::pin::Pin;
+use futures_util::future;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::{Body, HeaderMap, Response, StatusCode};
+use gotham::hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use gotham::middleware::Middleware;
+use gotham::state::{FromState, State};
+
+use crate::problem::Problem;
+use crate::utils::constant_time_eq;
+
+
+/// The bearer token sent with a request, if any, and whether it is the admin token.
+#[derive(Clone, StateData)]
+pub struct Credentials {
+    pub bearer_token: Option<String>,
+    pub is_admin: bool,
+}
+
+
+/// Records the credentials of every request in the state. Doesn't reject any requests.
+#[derive(Clone, NewMiddleware)]
+pub struct BearerAuthMiddleware {
+    admin_token: Option<String>,
+}
+
+impl BearerAuthMiddleware {
+    pub fn new(admin_token: &Option<String>) -> Self {
+        Self {
+            admin_token: admin_token.clone()
+        }
+    }
+
+    fn credentials(&self, headers: &HeaderMap) -> Credentials {
+        let bearer_token = headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| v.trim().to_owned());
+        let is_admin = match (&self.admin_token, &bearer_token) {
+            (Some(admin_token), Some(given)) => constant_time_eq(admin_token.as_bytes(), given.as_bytes()),
+            _ => false
+        };
+        Credentials { bearer_token, is_admin }
+    }
+}
+
+impl Middleware for BearerAuthMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+        where
+            Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        let credentials = self.credentials(HeaderMap::borrow_from(&state));
+        state.put(credentials);
+        chain(state)
+    }
+}
+
+
+/// Only lets requests pass that carry the admin token. Must be used after the
+/// `BearerAuthMiddleware`. If no admin token is configured all requests are rejected.
+#[derive(Clone, NewMiddleware)]
+pub struct RequireAdminMiddleware;
+
+impl Middleware for RequireAdminMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+        where
+            Chain: FnOnce(State) -> Pin<Box<HandlerFuture>>,
+    {
+        if Credentials::try_borrow_from(&state).is_some_and(|c| c.is_admin) {
+            return chain(state);
+        }
+        let response = unauthorized_response(&state);
+        Box::pin(future::ok((state, response)))
+    }
+}
+
+
+pub fn unauthorized_response(state: &State) -> Response<Body> {
+    let mut response = Problem::new(StatusCode::UNAUTHORIZED, "unauthorized", "A valid bearer token is required").to_response(state);
+    response.headers_mut().insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    response
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn recognises_admin_token() {
+        let middleware = BearerAuthMiddleware::new(&Some("s3cret".to_owned()));
+        let credentials = middleware.credentials(&headers("Bearer s3cret"));
+        assert!(credentials.is_admin);
+    }
+
+    #[test]
+    fn records_other_bearer_tokens() {
+        let middleware = BearerAuthMiddleware::new(&Some("s3cret".to_owned()));
+        let credentials = middleware.credentials(&headers("Bearer s3cre"));
+        assert!(!credentials.is_admin);
+        assert_eq!(Some("s3cre".to_owned()), credentials.bearer_token);
+    }
+
+    #[test]
+    fn ignores_other_schemes() {
+        let middleware = BearerAuthMiddleware::new(&Some("s3cret".to_owned()));
+        let credentials = middleware.credentials(&headers("Basic s3cret"));
+        assert!(!credentials.is_admin);
+        assert_eq!(None, credentials.bearer_token);
+        assert!(!middleware.credentials(&HeaderMap::new()).is_admin);
+    }
+
+    #[test]
+    fn never_recognises_admin_without_configured_token() {
+        let middleware = BearerAuthMiddleware::new(&None);
+        assert!(!middleware.credentials(&headers("Bearer ")).is_admin);
+    }
+}
@@ -29,7 +29,7 @@ impl Middleware for CorsMiddleware {
                 let headers = response.headers_mut();
                 headers.insert("Access-Control-Allow-Origin", origin.parse().unwrap());
                 headers.insert("Access-Control-Allow-Methods", "*".parse().unwrap());
-                headers.insert("Access-Control-Allow-Headers", "*".parse().unwrap());
+                headers.insert("Access-Control-Allow-Headers", "authorization, *".parse().unwrap());
                 headers.insert("Access-Control-Expose-Headers", "location".parse().unwrap());
             };
             Ok((state, response))
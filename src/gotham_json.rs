@@ -3,39 +3,77 @@ extern crate serde;
 extern crate serde_json;
 
 use std::collections::HashMap;
-use std::future::Future;
 use std::pin::Pin;
-use futures_util::{future, FutureExt};
+use std::str;
+use futures_util::FutureExt;
 
 use gotham::state::{FromState, State};
 use gotham::helpers::http::response::create_response;
-use gotham::handler::{HandlerError};
-use gotham::hyper::{body, Body, Response, StatusCode};
+use gotham::handler::HandlerFuture;
+use gotham::hyper::{Body, HeaderMap, Response, StatusCode};
+use gotham::hyper::body::HttpBody;
+use gotham::hyper::header::CONTENT_LENGTH;
 
 use serde::{Serialize};
 use serde::de::{DeserializeOwned};
+use serde_json::error::Category;
 
+use crate::problem::Problem;
 
-pub fn take_json_body<'de, T: 'de>(mut state: State) -> Pin<Box<dyn Future<Output=Result<(State, T), (State, HandlerError)>> + Send + 'de>>
-    where T: Sized + Send + DeserializeOwned {
-
-    let f = body::to_bytes(Body::take_from(&mut state)).then(|result|
-        match result {
-            Ok(as_bytes) => {
-                let as_string = String::from_utf8(as_bytes.to_vec()).unwrap(); // TODO: will this be a 500 for invalid UTF?
-                match serde_json::from_str::<T>(&as_string) {
-                    Ok(obj) => future::ok((state, obj)),
-                    Err(e) => future::err((state, HandlerError::from(e).with_status(StatusCode::BAD_REQUEST))),
-                }
-            },
-            Err(e) => {
-                future::err((state, e.into())) // TODO: should this be a bad request, too?
+
+/// Parses the body of the request as JSON and passes the document to the handler. If the
+/// body is larger than `max_size` bytes, or cannot be read or parsed, the handler is not called
+/// and a problem is sent instead. Reading stops as soon as the body exceeds the limit.
+pub fn with_json_body<T, F>(mut state: State, max_size: usize, handler: F) -> Pin<Box<HandlerFuture>>
+    where T: DeserializeOwned + Send + 'static,
+          F: FnOnce(State, T) -> (State, Response<Body>) + Send + 'static {
+
+    let declared_size = HeaderMap::borrow_from(&state).get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let body = Body::take_from(&mut state);
+    let f = async move {
+        let bytes = match declared_size {
+            Some(size) if size > max_size as u64 => Err(body_too_large(max_size)),
+            _ => read_body(body, max_size).await,
+        };
+        let (state, response) = match bytes.and_then(|bytes| parse_json_body(&bytes)) {
+            Ok(doc) => handler(state, doc),
+            Err(problem) => {
+                let response = problem.to_response(&state);
+                (state, response)
             }
-        }
-    );
+        };
+        Ok((state, response))
+    };
     f.boxed()
 }
 
+async fn read_body(mut body: Body, max_size: usize) -> Result<Vec<u8>, Problem> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Problem::bad_request("unreadable-body", &format!("The body could not be read: {}", e)))?;
+        if bytes.len() + chunk.len() > max_size {
+            return Err(body_too_large(max_size));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn body_too_large(max_size: usize) -> Problem {
+    Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "body-too-large", &format!("The body must not be larger than {} bytes", max_size))
+}
+
+fn parse_json_body<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Problem> {
+    let text = str::from_utf8(bytes)
+        .map_err(|e| Problem::bad_request("invalid-utf8", &format!("The body is not valid UTF-8: {}", e)))?;
+    serde_json::from_str(text).map_err(|e| match e.classify() {
+        Category::Data => Problem::bad_request("invalid-document", &format!("The document does not have the expected fields: {}", e)),
+        _ => Problem::bad_request("malformed-json", &format!("The body is not valid JSON: {}", e)),
+    })
+}
+
 
 pub fn create_json_response<S: Serialize>(state: &State, status: StatusCode, data: &S)
                                           -> Result<Response<Body>, serde_json::Error> {
@@ -52,3 +90,38 @@ pub fn create_json_response_with_headers<S: Serialize>(state: &State, status: St
         response
     })
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde_derive::Deserialize)]
+    struct Doc {
+        #[allow(dead_code)]
+        text: String,
+    }
+
+    fn code_for(body: &[u8]) -> String {
+        let problem = serde_json::to_value(parse_json_body::<Doc>(body).unwrap_err()).unwrap();
+        problem["code"].as_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn stops_reading_body_over_limit() {
+        let read = |limit| read_body(Body::from("0123456789"), limit).now_or_never().unwrap();
+
+        assert_eq!(b"0123456789".to_vec(), read(10).unwrap());
+        let problem = serde_json::to_value(read(9).unwrap_err()).unwrap();
+        assert_eq!(Some(&serde_json::json!("body-too-large")), problem.get("code"));
+        assert_eq!(Some(&serde_json::json!(413)), problem.get("status"));
+    }
+
+    #[test]
+    fn tells_malformed_json_from_unexpected_document() {
+        assert!(parse_json_body::<Doc>(br#"{"text": "x"}"#).is_ok());
+        assert_eq!("malformed-json", code_for(br#"{"text": "#));
+        assert_eq!("invalid-document", code_for(br#"{"txt": "x"}"#));
+        assert_eq!("invalid-utf8", code_for(b"{\"text\": \"\xff\"}"));
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::utils::escape_markup;
+
+/// The classes of the spans around highlighted tokens. Colours are left to the stylesheet.
+pub const CLASSES: [&str; 5] = ["qv-hl-comment", "qv-hl-string", "qv-hl-number", "qv-hl-keyword", "qv-hl-literal"];
+
+const C_COMMENTS: &str = r"//[^\n]*|/\*(?s:.*?)\*/";
+const HASH_COMMENTS: &str = r"#[^\n]*";
+const DOUBLE_QUOTED: &str = r#""(?:[^"\\]|\\.)*""#;
+const SINGLE_QUOTED: &str = r"'(?:[^'\\]|\\.)*'";
+const CHAR_LITERAL: &str = r"'(?:[^'\\]|\\.)'";
+const BACKTICK_QUOTED: &str = r"`(?:[^`\\]|\\.)*`";
+
+struct Language
+{
+    names: &'static [&'static str],
+    comments: Option<&'static str>,
+    strings: &'static [&'static str],
+    keywords: &'static [&'static str],
+    literals: &'static [&'static str],
+}
+
+const LANGUAGES: [Language; 8] = [
+    Language {
+        names: &["rust", "rs"],
+        comments: Some(C_COMMENTS),
+        strings: &[DOUBLE_QUOTED, CHAR_LITERAL],
+        keywords: &["as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+            "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return",
+            "self", "Self", "static", "struct", "super", "trait", "type", "unsafe", "use", "where", "while"],
+        literals: &["true", "false", "None", "Some", "Ok", "Err"],
+    },
+    Language {
+        names: &["javascript", "js", "typescript", "ts"],
+        comments: Some(C_COMMENTS),
+        strings: &[DOUBLE_QUOTED, SINGLE_QUOTED, BACKTICK_QUOTED],
+        keywords: &["async", "await", "break", "case", "catch", "class", "const", "continue", "default", "delete",
+            "do", "else", "export", "extends", "finally", "for", "function", "if", "import", "in", "instanceof",
+            "interface", "let", "new", "of", "return", "static", "switch", "this", "throw", "try", "type", "typeof",
+            "var", "void", "while", "yield"],
+        literals: &["true", "false", "null", "undefined", "NaN"],
+    },
+    Language {
+        names: &["python", "py"],
+        comments: Some(HASH_COMMENTS),
+        strings: &[DOUBLE_QUOTED, SINGLE_QUOTED],
+        keywords: &["and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif",
+            "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is", "lambda", "nonlocal",
+            "not", "or", "pass", "raise", "return", "try", "while", "with", "yield"],
+        literals: &["True", "False", "None"],
+    },
+    Language {
+        names: &["shell", "sh", "bash", "zsh"],
+        comments: Some(HASH_COMMENTS),
+        strings: &[DOUBLE_QUOTED, SINGLE_QUOTED],
+        keywords: &["case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if", "in",
+            "local", "return", "then", "until", "while"],
+        literals: &["true", "false"],
+    },
+    Language {
+        names: &["c", "cpp", "c++", "h"],
+        comments: Some(C_COMMENTS),
+        strings: &[DOUBLE_QUOTED, CHAR_LITERAL],
+        keywords: &["auto", "break", "case", "char", "class", "const", "continue", "default", "do", "double", "else",
+            "enum", "extern", "float", "for", "if", "int", "long", "namespace", "private", "public", "return",
+            "short", "signed", "sizeof", "static", "struct", "switch", "template", "typedef", "union", "unsigned",
+            "using", "void", "volatile", "while"],
+        literals: &["true", "false", "NULL", "nullptr"],
+    },
+    Language {
+        names: &["java", "kotlin", "kt"],
+        comments: Some(C_COMMENTS),
+        strings: &[DOUBLE_QUOTED, CHAR_LITERAL],
+        keywords: &["abstract", "boolean", "break", "case", "catch", "class", "continue", "default", "do", "double",
+            "else", "enum", "extends", "final", "finally", "float", "for", "fun", "if", "implements", "import",
+            "int", "interface", "long", "new", "package", "private", "protected", "public", "return", "static",
+            "super", "switch", "this", "throw", "throws", "try", "val", "var", "void", "when", "while"],
+        literals: &["true", "false", "null"],
+    },
+    Language {
+        names: &["go", "golang"],
+        comments: Some(C_COMMENTS),
+        strings: &[DOUBLE_QUOTED, BACKTICK_QUOTED, CHAR_LITERAL],
+        keywords: &["break", "case", "chan", "const", "continue", "default", "defer", "else", "fallthrough", "for",
+            "func", "go", "goto", "if", "import", "interface", "map", "package", "range", "return", "select",
+            "struct", "switch", "type", "var"],
+        literals: &["true", "false", "nil", "iota"],
+    },
+    Language {
+        names: &["json"],
+        comments: None,
+        strings: &[DOUBLE_QUOTED],
+        keywords: &[],
+        literals: &["true", "false", "null"],
+    },
+];
+
+
+/// Wraps the tokens of the code in spans with the classes above. Returns `None` if the
+/// language is not known. The result is HTML; everything in the code is escaped.
+pub fn highlight(code: &str, language: &str) -> Option<String> {
+    let language = language.to_lowercase();
+    let index = LANGUAGES.iter().position(|l| l.names.contains(&language.as_str()))?;
+    let language = &LANGUAGES[index];
+    let regex = token_regex(index);
+    let mut html = String::new();
+    let mut last = 0;
+    for captures in regex.captures_iter(code) {
+        let token = captures.get(0).unwrap();
+        let class = if captures.name("comment").is_some() {
+            Some("qv-hl-comment")
+        } else if captures.name("string").is_some() {
+            Some("qv-hl-string")
+        } else if captures.name("number").is_some() {
+            Some("qv-hl-number")
+        } else if language.keywords.contains(&token.as_str()) {
+            Some("qv-hl-keyword")
+        } else if language.literals.contains(&token.as_str()) {
+            Some("qv-hl-literal")
+        } else {
+            None
+        };
+        html.push_str(&escape_markup(&code[last..token.start()]));
+        match class {
+            Some(class) => html.push_str(&format!("<span class=\"{}\">{}</span>", class, escape_markup(token.as_str()))),
+            None => html.push_str(&escape_markup(token.as_str())),
+        }
+        last = token.end();
+    }
+    html.push_str(&escape_markup(&code[last..]));
+    Some(html)
+}
+
+fn token_regex(index: usize) -> &'static Regex {
+    static REGEXES: OnceLock<Vec<Regex>> = OnceLock::new();
+    let regexes = REGEXES.get_or_init(|| LANGUAGES.iter().map(|language| {
+        let mut alternatives = Vec::new();
+        if let Some(comments) = language.comments {
+            alternatives.push(format!("(?P<comment>{})", comments));
+        }
+        alternatives.push(format!("(?P<string>{})", language.strings.join("|")));
+        alternatives.push(r"(?P<number>\b(?:0[xX][0-9a-fA-F_]+|\d[\d_]*(?:\.\d+)?(?:[eE][+-]?\d+)?)[A-Za-z0-9_]*)".to_owned());
+        alternatives.push(r"(?P<word>[A-Za-z_][A-Za-z0-9_]*)".to_owned());
+        Regex::new(&alternatives.join("|")).unwrap()
+    }).collect());
+    &regexes[index]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_tokens_in_spans() {
+        let html = highlight("fn main() { let x = 42; } // \"done\"", "rust").unwrap();
+
+        assert_eq!(concat!(
+            r#"<span class="qv-hl-keyword">fn</span> main() { <span class="qv-hl-keyword">let</span> x = "#,
+            r#"<span class="qv-hl-number">42</span>; } <span class="qv-hl-comment">// &quot;done&quot;</span>"#), html);
+    }
+
+    #[test]
+    fn escapes_markup_in_code() {
+        let html = highlight("x = '<script>' # <b>", "Python").unwrap();
+
+        assert_eq!(r#"x = <span class="qv-hl-string">&apos;&lt;script&gt;&apos;</span> <span class="qv-hl-comment"># &lt;b&gt;</span>"#, html);
+    }
+
+    #[test]
+    fn does_not_treat_parts_of_words_as_keywords_or_numbers() {
+        let html = highlight("iffy x2 null", "js").unwrap();
+
+        assert_eq!(r#"iffy x2 <span class="qv-hl-literal">null</span>"#, html);
+    }
+
+    #[test]
+    fn returns_none_for_unknown_language() {
+        assert_eq!(None, highlight("x", "cobol"));
+    }
+}
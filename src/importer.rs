@@ -5,6 +5,7 @@ use std::io::BufReader;
 use serde_derive::Deserialize;
 
 use crate::comment::Comment;
+use crate::markdown::MarkdownOptions;
 use crate::repository::CommentRepository;
 use chrono::DateTime;
 
@@ -20,7 +21,7 @@ struct CommentRecord
 }
 
 
-pub fn run(filename: &str, repo: CommentRepository) -> Result<(), Box<dyn Error>>
+pub fn run(filename: &str, repo: CommentRepository, options: &MarkdownOptions) -> Result<(), Box<dyn Error>>
 {
     let file = File::open(filename)?;
     let mut reader = csv::ReaderBuilder::new().delimiter(b',').from_reader(BufReader::new(file));
@@ -29,10 +30,11 @@ pub fn run(filename: &str, repo: CommentRepository) -> Result<(), Box<dyn Error>
     for result in reader.deserialize() {
         let r: CommentRecord = result?;
         let timestamp = DateTime::from(DateTime::parse_from_rfc3339(&r.timestamp).unwrap()); // TODO: there must be a better way
-        let author_name: Option<&str> = if r.author_name == "" { None } else { Some(&r.author_name) };
-        let author_email: Option<&str> = if r.author_email == "" { None } else { Some(&r.author_email) };
+        let author_name: Option<&str> = if r.author_name.is_empty() { None } else { Some(&r.author_name) };
+        let author_email: Option<&str> = if r.author_email.is_empty() { None } else { Some(&r.author_email) };
         let mut comment = Comment::new(&r.path, &r.text, author_name, author_email);
         comment.timestamp = timestamp;
+        comment.render(options, false);
         println!("{:?}", comment);
         repo.save_comment(&comment);
     }
@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use glob::glob;
+use uuid::Uuid;
+
+use crate::comment::Comment;
+use crate::storage::{CommentStore, prepare_directory};
+use crate::utils;
+
+/// Stores each comment as a JSON file in a directory. The filenames are the comment ids.
+pub struct JsonDirectoryStore {
+    path: String,
+}
+
+impl JsonDirectoryStore {
+    pub fn new(path: &str, reset: bool) -> Self {
+        prepare_directory(path, reset);
+        Self {
+            path: path.to_owned(),
+        }
+    }
+
+    fn filename(&self, id: Uuid) -> String {
+        format!("{}/{}.json", self.path, id.as_simple())
+    }
+
+    fn load_comment(&self, path: &Path) -> Comment {
+        println!("Loading comment from file: {}", path.display());
+        let mut file = File::open(path).unwrap_or_else(|_| panic!("Failed to open file {}", path.display()));
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap_or_else(|_| panic!("Failed to read file {}", path.display()));
+        utils::from_json(&contents)
+    }
+}
+
+impl CommentStore for JsonDirectoryStore {
+    fn load_all(&self) -> Vec<Comment> {
+        glob(&format!("{}/*.json", self.path)).unwrap()
+            .flatten() // TODO: report unreadable entries
+            .map(|path| self.load_comment(&path))
+            .collect()
+    }
+
+    fn save(&self, comment: &Comment) {
+        let filename = self.filename(comment.id);
+        println!("Saving comment to file: {}", filename);
+        let mut file = File::create(&filename).unwrap_or_else(|_| panic!("Failed to create file {}", &filename));
+        let _result = file.write_all(utils::to_json(comment).as_ref());
+    }
+
+    fn delete(&self, comment: &Comment) {
+        let filename = self.filename(comment.id);
+        println!("Deleting comment in file: {}", filename);
+        std::fs::remove_file(&filename).unwrap_or_else(|_| panic!("Failed to delete comment in file {}", &filename));
+    }
+
+    fn find_by_id(&self, id: Uuid) -> Option<Comment> {
+        let filename = self.filename(id);
+        let path = Path::new(&filename);
+        if path.exists() { Some(self.load_comment(path)) } else { None }
+    }
+}
@@ -4,7 +4,18 @@ extern crate gotham_derive;
 use std::{process, thread};
 
 use crate::repository::CommentRepository;
-use crate::notifier::Notifier;
+use crate::digest::{Digest, DigestInterval, DigestNotifier};
+use crate::notification_queue::{NotificationQueue, QueueSettings};
+use crate::markdown::MarkdownOptions;
+use crate::moderation_links::ModerationLinks;
+use crate::notifier::{MailNotifier, MailTransport, Notifier, ReplyNotifier};
+use crate::subscriptions::Subscriptions;
+use crate::webhook::{WebhookNotifier, WebhookSettings};
+use crate::storage::StorageType;
+use crate::validation::CommentLimits;
+use crate::webapi::ApiSettings;
+use chrono::Duration;
+use uuid::Uuid;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use signal_hook::iterator::Signals;
@@ -12,50 +23,180 @@ use signal_hook::consts::SIGHUP;
 
 pub mod comment;
 pub mod repository;
+pub mod storage;
+pub mod json_storage;
+pub mod sqlite_storage;
 pub mod utils;
+pub mod validation;
 pub mod webapi;
 pub mod importer;
 
 mod gotham_json;
 mod gotham_cors;
+mod gotham_auth;
+mod problem;
+mod feed;
 mod gravatar;
-mod markdown;
-mod notifier;
+mod highlight;
+pub mod markdown;
+pub mod sanitizer;
+pub mod config_file;
+pub mod signing;
+pub mod digest;
+mod mail;
+pub mod moderation_links;
+pub mod notifier;
+pub mod notification_queue;
 mod sendmail;
+pub mod smtp;
+pub mod subscriptions;
+pub mod webhook;
 
 
-pub fn run(repo_path: String, repo_reset: bool, app_path: String, bind_addr: String,
-           cors_origin: Option<String>, notify_addr: Option<String>)
+pub struct Config {
+    pub repo_path: String,
+    pub repo_reset: bool,
+    pub storage: StorageType,
+    pub app_path: String,
+    pub site_url: Option<String>,
+    pub bind_addr: String,
+    pub cors_origin: Option<String>,
+    pub notify_addr: Option<String>,
+    pub mail_transport: MailTransport,
+    pub mail_from: String,
+    pub digest: Option<DigestInterval>,
+    pub webhooks: Vec<String>,
+    pub webhook_secret: Option<String>,
+    pub notify_replies: bool,
+    pub public_url: Option<String>,
+    pub moderation: bool,
+    pub admin_token: Option<String>,
+    pub secret: Option<String>,
+    pub edit_window: Duration,
+    pub markdown: MarkdownOptions,
+    pub limits: CommentLimits,
+    pub max_body_size: usize,
+}
+
+
+pub fn run(config: Config)
 {
-    let mut repository = CommentRepository::new(&repo_path, repo_reset);
+    let mut repository = CommentRepository::with_store(config.storage.open(&config.repo_path, config.repo_reset));
 
     let reload_flag = Arc::new(AtomicBool::new(true));
     run_signal_handler(&reload_flag);
     repository.set_reload_flag(&reload_flag);
+    repository.set_moderated(config.moderation);
     repository.all_comments();
+    let stale = repository.count_stale_renders(&config.markdown);
+    if stale > 0 {
+        println!("{} comments were rendered with other Markdown settings or another version; run with --rerender to update them, and add --restamp to mark the unchanged ones as current", stale);
+    }
+
+    let secret = config.secret.unwrap_or_else(|| {
+        println!("No secret configured; edit tokens will become invalid when the server restarts");
+        Uuid::new_v4().as_simple().to_string()
+    });
 
-    if let Some(addr) = notify_addr {
-        repository.set_notifier(Notifier::new(&addr))
+    // without moderation all comments are visible at once, so there is nothing to approve
+    let moderation_links = match (&config.public_url, config.moderation) {
+        (Some(url), true) => Some(ModerationLinks::new(&secret, url, Duration::days(7))),
+        _ => None
+    };
+    let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+    match (&config.notify_addr, config.digest) {
+        (Some(addr), Some(interval)) => {
+            let digest = Digest::open(&format!("{}-digest.json", config.repo_path.trim_end_matches('/')), interval.duration())
+                .unwrap_or_else(|message| {
+                    println!("{}", message);
+                    std::process::exit(1);
+                });
+            let mut notifier = DigestNotifier::new(digest, addr, &config.mail_from, &config.site_url, config.mail_transport.clone());
+            if let Some(links) = &moderation_links {
+                notifier = notifier.with_moderation_links(links.clone());
+            }
+            let notifier = Arc::new(notifier);
+            DigestNotifier::start(&notifier);
+            notifiers.push(notifier)
+        }
+        (Some(addr), None) => {
+            let mut notifier = MailNotifier::new(addr, &config.mail_from, &config.site_url, config.mail_transport.clone());
+            if let Some(links) = &moderation_links {
+                notifier = notifier.with_moderation_links(links.clone());
+            }
+            notifiers.push(Arc::new(notifier))
+        }
+        (None, _) => {}
+    }
+    for url in &config.webhooks {
+        let settings = WebhookSettings::new(url, config.webhook_secret.clone());
+        notifiers.push(Arc::new(WebhookNotifier::new(settings, &config.site_url)))
+    }
+    if let (true, Some(public_url)) = (config.notify_replies, &config.public_url) {
+        let subscriptions = Subscriptions::open(&format!("{}-subscriptions.json", config.repo_path.trim_end_matches('/')))
+            .unwrap_or_else(|message| {
+                println!("{}", message);
+                std::process::exit(1);
+            });
+        repository.set_subscriptions(subscriptions.clone());
+        notifiers.push(Arc::new(ReplyNotifier::new(subscriptions, &secret, public_url, &config.mail_from, &config.site_url,
+                                                   config.mail_transport.clone())))
+    }
+    if !notifiers.is_empty() {
+        let spool_path = format!("{}-spool", config.repo_path.trim_end_matches('/'));
+        repository.add_notifier(Arc::new(NotificationQueue::start(&spool_path, notifiers, QueueSettings::default())))
     }
 
-    webapi::run(repository, &app_path, &bind_addr, &cors_origin);
+    let settings = ApiSettings {
+        app_path: config.app_path,
+        site_url: config.site_url,
+        cors_origin: config.cors_origin,
+        admin_token: config.admin_token,
+        secret,
+        edit_window: config.edit_window,
+        markdown: config.markdown,
+        limits: config.limits,
+        max_body_size: config.max_body_size,
+    };
+    webapi::run(repository, &config.bind_addr, &settings);
 }
 
 
-pub fn import(repo_path: String, repo_reset: bool, filename: String)
+pub fn import(config: Config, filename: String)
 {
-    let repository = CommentRepository::new(&repo_path, repo_reset);
-    let result = importer::run(&filename, repository);
+    let repository = CommentRepository::with_store(config.storage.open(&config.repo_path, config.repo_reset));
+    let result = importer::run(&filename, repository, &config.markdown);
     if let Err(message) = result {
         println!("Error during import: {}", message);
         process::exit(1);
     }
 }
 
+pub fn rerender(config: Config, restamp: bool)
+{
+    let repository = CommentRepository::with_store(config.storage.open(&config.repo_path, false));
+    let summary = repository.rerender(&config.markdown, restamp);
+    for changed in &summary.changed {
+        println!("Changed comment {} on {}:", changed.comment.idh, changed.comment.path);
+        for line in utils::line_diff(&changed.previous_html, &changed.comment.text_html) {
+            println!("  {}", line);
+        }
+    }
+    println!("Rendered comments again: {} changed, {} unchanged, {} unchanged but marked as current, {} unchanged with an outdated fingerprint, {} deleted skipped",
+             summary.changed.len(), summary.unchanged, summary.stamped, summary.outdated, summary.deleted);
+}
+
+pub fn migrate(config: Config)
+{
+    let repository = CommentRepository::with_store(config.storage.open(&config.repo_path, false));
+    let count = repository.migrate_idhs();
+    println!("Migrated {} comments", count);
+}
+
 fn run_signal_handler(reload_flag: &Arc<AtomicBool>)
 {
     let reload_flag = Arc::clone(reload_flag);
-    let mut signals = Signals::new(&[SIGHUP]).expect("Failed to create signal handler");
+    let mut signals = Signals::new([SIGHUP]).expect("Failed to create signal handler");
     thread::spawn(move || {
         for _ in signals.forever() {
             println!("Will reload comments on next request");
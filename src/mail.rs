@@ -0,0 +1,207 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::Utc;
+use uuid::Uuid;
+
+const MAX_ENCODED_WORD_LEN: usize = 75;
+const BODY_LINE_LEN: usize = 76;
+
+
+/// An email with a plain text body and, optionally, an HTML alternative.
+pub struct Message {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+impl Message {
+    /// Formats the message according to RFC 5322 and MIME. Header values are stripped of line
+    /// breaks and encoded if they are not plain ASCII, and the bodies are base64 encoded, which
+    /// means that nothing in the values can add headers or end the message early. Lines are
+    /// separated by LF, as expected by sendmail; the SMTP transport converts them to CRLF.
+    /// Sendmail takes the recipients from the To header, so it must be a single plain address.
+    pub fn format(&self) -> Result<String, String> {
+        if !is_plain_address(&self.to) {
+            return Err(format!("Not a plain email address: {:?}", self.to));
+        }
+        let mut headers = vec![
+            ("From", header_value(&self.from)),
+            ("To", header_value(&self.to)),
+            ("Subject", header_value(&self.subject)),
+            ("Date", Utc::now().to_rfc2822()),
+            ("Message-ID", format!("<{}@{}>", Uuid::new_v4().as_simple(), domain_of(&self.from))),
+            ("MIME-Version", "1.0".to_owned()),
+        ];
+        let body = match &self.html {
+            None => {
+                headers.extend(part_headers("text/plain"));
+                base64_lines(&self.text)
+            }
+            Some(html) => {
+                let boundary = format!("quvyn-{}", Uuid::new_v4().as_simple());
+                headers.push(("Content-Type", format!("multipart/alternative; boundary=\"{}\"", boundary)));
+                let mut body = String::new();
+                for (content_type, content) in [("text/plain", &self.text), ("text/html", html)] {
+                    body.push_str(&format!("--{}\n", boundary));
+                    for (name, value) in part_headers(content_type) {
+                        body.push_str(&format!("{}: {}\n", name, value));
+                    }
+                    body.push('\n');
+                    body.push_str(&base64_lines(content));
+                }
+                body.push_str(&format!("--{}--\n", boundary));
+                body
+            }
+        };
+        let mut message = String::new();
+        for (name, value) in headers {
+            message.push_str(&format!("{}: {}\n", name, value));
+        }
+        message.push('\n');
+        message.push_str(&body);
+        Ok(message)
+    }
+}
+
+/// Whether the address is a single address without a display name, which cannot add
+/// recipients, headers or SMTP commands.
+pub fn is_plain_address(address: &str) -> bool {
+    !address.is_empty() && !address.chars().any(|c| c.is_control() || c.is_whitespace() || matches!(c, ',' | ';' | '<' | '>'))
+}
+
+fn part_headers(content_type: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("Content-Type", format!("{}; charset=utf-8", content_type)),
+        ("Content-Transfer-Encoding", "base64".to_owned()),
+    ]
+}
+
+fn domain_of(address: &str) -> &str {
+    address.rsplit_once('@').map(|(_, domain)| domain.trim_end_matches('>')).unwrap_or("localhost")
+}
+
+/// Replaces control characters, including line breaks, with spaces and encodes the value as
+/// RFC 2047 encoded words, folded over several lines, if it contains non-ASCII characters.
+fn header_value(value: &str) -> String {
+    let value: String = value.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    if value.is_ascii() {
+        return value;
+    }
+    // each encoded word must be at most 75 characters long, which leaves 63 characters for
+    // the base64 text, which in turn encodes 45 bytes
+    let max_bytes = (MAX_ENCODED_WORD_LEN - "=?utf-8?b??=".len()) / 4 * 3;
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        if chunk.len() + c.len_utf8() > max_bytes {
+            words.push(encoded_word(&chunk));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(encoded_word(&chunk));
+    words.join("\n ")
+}
+
+fn encoded_word(text: &str) -> String {
+    format!("=?utf-8?b?{}?=", BASE64.encode(text))
+}
+
+fn base64_lines(text: &str) -> String {
+    let encoded = BASE64.encode(text);
+    let mut result = String::with_capacity(encoded.len() + encoded.len() / BODY_LINE_LEN + 1);
+    for line in encoded.as_bytes().chunks(BODY_LINE_LEN) {
+        result.push_str(std::str::from_utf8(line).unwrap());
+        result.push('\n');
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(subject: &str, html: Option<&str>) -> Message {
+        Message {
+            from: "quvyn@example.org".to_owned(),
+            to: "joe@example.org".to_owned(),
+            subject: subject.to_owned(),
+            text: "Hello\nBcc: someone@example.org\n.\n".to_owned(),
+            html: html.map(|h| h.to_owned()),
+        }
+    }
+
+    fn headers(formatted: &str) -> Vec<&str> {
+        formatted.split("\n\n").next().unwrap().lines().collect()
+    }
+
+    #[test]
+    fn contains_required_headers() {
+        let formatted = message("New comment", None).format().unwrap();
+        let headers = headers(&formatted);
+
+        assert!(headers.contains(&"From: quvyn@example.org"));
+        assert!(headers.contains(&"To: joe@example.org"));
+        assert!(headers.contains(&"Subject: New comment"));
+        assert!(headers.contains(&"MIME-Version: 1.0"));
+        assert!(headers.contains(&"Content-Type: text/plain; charset=utf-8"));
+        assert!(headers.iter().any(|h| h.starts_with("Date: ")));
+        assert!(headers.iter().any(|h| h.starts_with("Message-ID: <") && h.ends_with("@example.org>")));
+    }
+
+    #[test]
+    fn line_breaks_in_header_values_cannot_add_headers() {
+        let formatted = message("Hi\r\nBcc: someone@example.org", None).format().unwrap();
+
+        assert!(headers(&formatted).contains(&"Subject: Hi  Bcc: someone@example.org"));
+        assert!(!headers(&formatted).iter().any(|h| h.starts_with("Bcc:")));
+    }
+
+    #[test]
+    fn refuses_recipients_that_are_not_a_plain_address() {
+        for to in ["joe@example.org\nBcc: someone@example.org", "joe@example.org, ann@example.org", "Joe <joe@example.org>", ""] {
+            let mut message = message("New comment", None);
+            message.to = to.to_owned();
+
+            assert!(message.format().is_err(), "accepted {:?}", to);
+        }
+    }
+
+    #[test]
+    fn body_cannot_add_headers_or_end_message() {
+        let formatted = message("New comment", None).format().unwrap();
+
+        assert!(!formatted.contains("Bcc:"));
+        assert!(!formatted.lines().any(|l| l == "."));
+    }
+
+    #[test]
+    fn encodes_non_ascii_header_values_as_folded_encoded_words() {
+        let subject = "Neuer Kommentar von Jürgen ".repeat(3);
+
+        let value = header_value(&subject);
+
+        assert!(value.starts_with("=?utf-8?b?"));
+        assert!(value.lines().all(|l| l.trim_start().len() <= MAX_ENCODED_WORD_LEN));
+        let decoded: String = value.lines()
+            .map(|l| l.trim_start().trim_start_matches("=?utf-8?b?").trim_end_matches("?="))
+            .map(|w| String::from_utf8(BASE64.decode(w).unwrap()).unwrap())
+            .collect();
+        assert_eq!(subject, decoded);
+    }
+
+    #[test]
+    fn adds_html_alternative() {
+        let formatted = message("New comment", Some("<p>Hello</p>")).format().unwrap();
+
+        let content_type = headers(&formatted).into_iter().find(|h| h.starts_with("Content-Type:")).unwrap();
+        let boundary = content_type.split("boundary=\"").nth(1).unwrap().trim_end_matches('"');
+        assert_eq!(3, formatted.matches(&format!("--{}", boundary)).count());
+        assert!(formatted.contains("Content-Type: text/html; charset=utf-8"));
+        assert!(formatted.contains(&BASE64.encode("<p>Hello</p>")));
+        assert!(formatted.ends_with(&format!("--{}--\n", boundary)));
+    }
+}
@@ -1,26 +1,79 @@
 extern crate getopts;
 extern crate quvyn;
 
-use std::{env};
+use std::{env, fs};
 use std::process::exit;
 
+use chrono::Duration;
 use getopts::Options;
 
+use quvyn::Config;
+use quvyn::config_file::ConfigFile;
+use quvyn::digest::DigestInterval;
+use quvyn::markdown::{self, FirstTimeLinks, MarkdownOptions};
+use quvyn::notifier::MailTransport;
+use quvyn::smtp::{SmtpSecurity, SmtpSettings};
+use quvyn::storage::StorageType;
+use quvyn::validation::CommentLimits;
+
 const DEFAULT_BIND_ADDR: &str = "localhost:8080";
 const DEFAULT_REPO_PATH: &str = "/var/lib/quvyn/repository";
 const DEFAULT_APP_PATH: &str = "vue";
+const DEFAULT_STORAGE: &str = "json";
+const DEFAULT_EDIT_WINDOW: i64 = 15;
+const DEFAULT_SMTP_PORT: u16 = 25;
+const DEFAULT_MAIL_FROM: &str = "quvyn@localhost";
+const DEFAULT_MD_EXTENSIONS: &str = "tables";
+const DEFAULT_MD_HEADING_OFFSET: usize = 1;
+const DEFAULT_URL_SCHEMES: &str = "http,https,mailto";
+const DEFAULT_MAX_BODY_SIZE: usize = 128 * 1024;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
+    opts.optopt("c", "config", "Read further settings, e.g. the HTML allowed in comments, from a configuration file in JSON format.", "PATH");
     opts.optopt("r", "repo", &format!("Specify path for the repository. By default the repository is stored in {}.", DEFAULT_REPO_PATH), "PATH");
+    opts.optopt("s", "storage", &format!("Specify how comments are stored in the repository, either json or sqlite. By default the storage is {}.", DEFAULT_STORAGE), "TYPE");
     opts.optflag("", "reset", "Reset the repository. Or in other words, delete all comments. USE WITH EXTREME CAUTION!");
     opts.optopt("a", "app", &format!("Specify path for the frontend app. By default the app is assumed in {}.", DEFAULT_APP_PATH), "PATH");
     opts.optopt("b", "bind", &format!("Specify address and port for the server. By default the server binds to {}. ", DEFAULT_BIND_ADDR), "HOST:PORT");
-    opts.optopt("o", "origin", &format!("Specify an origin allowed for CORS. By default no CORS headers are sent."), "URL");
-    opts.optopt("n", "notify", &format!("Specify an email address to be notified of new comments."), "EMAIL-ADDRESS");
-    opts.optopt("", "import", &format!("Imports comments from a CSV file."), "PATH");
+    opts.optopt("", "site", "Specify the URL of the website that displays the comments. It is used to create links to the pages with the comments.", "URL");
+    opts.optopt("o", "origin", "Specify an origin allowed for CORS. By default no CORS headers are sent.", "URL");
+    opts.optopt("n", "notify", "Specify an email address to be notified of new comments.", "EMAIL-ADDRESS");
+    opts.optopt("", "digest", "Instead of one email per comment, send a digest of all new comments every hour or every day.", "hourly|daily");
+    opts.optopt("", "smtp", &format!("Send notification mails via this SMTP server instead of the local sendmail binary. By default port {} is used.", DEFAULT_SMTP_PORT), "HOST[:PORT]");
+    opts.optflag("", "smtp-starttls", "Upgrade the connection to the SMTP server to TLS with STARTTLS.");
+    opts.optopt("", "smtp-user", "Specify the user name for authenticating with the SMTP server. Requires --smtp-starttls.", "USER");
+    opts.optopt("", "smtp-password", "Specify the password for authenticating with the SMTP server.", "PASSWORD");
+    opts.optopt("", "smtp-password-file", "Read the password for authenticating with the SMTP server from a file.", "PATH");
+    opts.optopt("", "mail-from", &format!("Specify the sender address of notification mails. By default this is {}.", DEFAULT_MAIL_FROM), "EMAIL-ADDRESS");
+    opts.optmulti("w", "webhook", "Post a JSON document to this URL whenever a comment is posted, approved or deleted. Can be given more than once.", "URL");
+    opts.optopt("", "webhook-secret", "Specify a secret used to sign the requests to webhooks.", "SECRET");
+    opts.optopt("", "webhook-secret-file", "Read the secret used to sign the requests to webhooks from a file.", "PATH");
+    opts.optflag("", "notify-replies", "Allow commenters to ask for an email when a new comment is posted on the same page. Requires --public-url.");
+    opts.optopt("", "public-url", "Specify the URL at which Quvyn can be reached from the internet. It is used for links in emails.", "URL");
+    opts.optflag("m", "moderation", "Hold new comments for moderation. They are only displayed after they have been approved.");
+    opts.optopt("t", "admin-token", "Specify a token that must be sent as bearer token to access the admin API. Without a token the admin API is disabled.", "TOKEN");
+    opts.optopt("", "admin-token-file", "Read the token for the admin API from a file. This avoids exposing the token in the process list.", "PATH");
+    opts.optopt("", "secret", "Specify a secret used to sign tokens, e.g. the tokens that allow authors to edit their comments. By default a random secret is used, which means tokens become invalid when the server is restarted.", "SECRET");
+    opts.optopt("", "secret-file", "Read the secret used to sign tokens from a file.", "PATH");
+    opts.optopt("", "edit-window", &format!("Specify for how many minutes after posting authors can edit or delete their comment. By default this is {} minutes.", DEFAULT_EDIT_WINDOW), "MINUTES");
+    opts.optopt("", "md-extensions", &format!("Specify a comma-separated list of Markdown extensions: tables, footnotes, strikethrough, tasklists, smart-punctuation, heading-attributes. By default only {} are enabled.", DEFAULT_MD_EXTENSIONS), "LIST");
+    opts.optopt("", "md-heading-offset", &format!("Specify by how many levels headings in comments are moved down. By default this is {}, which turns a top-level heading into <h{}>.", DEFAULT_MD_HEADING_OFFSET, DEFAULT_MD_HEADING_OFFSET + 1), "LEVELS");
+    opts.optflag("", "highlight", "Highlight the syntax of fenced code blocks that name a known language.");
+    opts.optflag("", "links-new-window", "Make links in comments open in a new window or tab.");
+    opts.optopt("", "url-schemes", &format!("Specify a comma-separated list of URL schemes allowed in links in comments. By default these are {}.", DEFAULT_URL_SCHEMES), "LIST");
+    opts.optopt("", "first-time-links", "Specify what happens to links in comments by authors who have no approved comments yet: keep them, unlink them but show the URL, or strip them. By default links are kept.", "keep|unlink|strip");
+    opts.optopt("", "max-text-length", &format!("Specify the maximum length of the text of a comment in characters. By default this is {}.", CommentLimits::default().text_length), "CHARS");
+    opts.optopt("", "max-name-length", &format!("Specify the maximum length of the name of an author in characters. By default this is {}.", CommentLimits::default().name_length), "CHARS");
+    opts.optopt("", "max-email-length", &format!("Specify the maximum length of the email address of an author in characters. By default this is {}.", CommentLimits::default().email_length), "CHARS");
+    opts.optopt("", "max-path-length", &format!("Specify the maximum length of the path of the page a comment is posted on. By default this is {} characters.", CommentLimits::default().path_length), "CHARS");
+    opts.optopt("", "max-body-size", &format!("Specify the maximum size in bytes of the JSON documents posted to the server. Larger requests are rejected without reading them completely. By default this is {} bytes.", DEFAULT_MAX_BODY_SIZE), "BYTES");
+    opts.optopt("", "import", "Imports comments from a CSV file.", "PATH");
+    opts.optflag("", "rerender", "Renders the text of all stored comments again with the current Markdown and sanitizer settings, and rewrites only the comments whose HTML changed.");
+    opts.optflag("", "restamp", "With --rerender, also rewrites the comments whose HTML stayed the same to mark them as rendered with the current settings.");
+    opts.optflag("", "migrate", "Rewrites comments stored by earlier versions to use the current public ids. The previous ids still resolve.");
     opts.optflag("h", "help", "Display this help message");
 
     let matches = match opts.parse(&args[1..]) {
@@ -31,23 +84,259 @@ fn main() {
         }
     };
     if matches.opt_present("help") {
-        print!("{}", opts.usage(&"Usage: quvyn [OPTIONS]"));
+        print!("{}", opts.usage("Usage: quvyn [OPTIONS]"));
         return;
     }
 
-    let repo_path = matches.opt_get_default("repo", String::from(DEFAULT_REPO_PATH)).unwrap();
-    let repo_reset = matches.opt_present("reset");
-    let app_path = matches.opt_get_default("app", String::from(DEFAULT_APP_PATH)).unwrap();
-    let bind_addr = matches.opt_get_default("bind", String::from(DEFAULT_BIND_ADDR)).unwrap();
-    let cors_origin = matches.opt_str("origin");
-    let notify_addr = matches.opt_str("notify");
-    let csv_file = matches.opt_str("import");
+    let storage: StorageType = match matches.opt_get_default("storage", DEFAULT_STORAGE.parse().unwrap()) {
+        Ok(s) => s,
+        Err(message) => {
+            print!("{}", opts.usage(&message));
+            exit(1);
+        }
+    };
+    let edit_window: i64 = match matches.opt_get_default("edit-window", DEFAULT_EDIT_WINDOW) {
+        Ok(m) if m < 0 => {
+            print!("{}", opts.usage("Invalid edit window: must not be negative"));
+            exit(1);
+        }
+        Ok(m) => m,
+        Err(e) => {
+            print!("{}", opts.usage(&format!("Invalid edit window: {}", e)));
+            exit(1);
+        }
+    };
+    let digest: Option<DigestInterval> = match matches.opt_get("digest") {
+        Ok(d) => d,
+        Err(message) => {
+            print!("{}", opts.usage(&message));
+            exit(1);
+        }
+    };
+    let md_extensions = match markdown::parse_extensions(&matches.opt_get_default("md-extensions", String::from(DEFAULT_MD_EXTENSIONS)).unwrap()) {
+        Ok(e) => e,
+        Err(message) => {
+            print!("{}", opts.usage(&message));
+            exit(1);
+        }
+    };
+    let md_heading_offset: usize = match matches.opt_get_default("md-heading-offset", DEFAULT_MD_HEADING_OFFSET) {
+        Ok(o) => o,
+        Err(e) => {
+            print!("{}", opts.usage(&format!("Invalid heading offset: {}", e)));
+            exit(1);
+        }
+    };
+    let limits = match comment_limits(&matches) {
+        Ok(l) => l,
+        Err(message) => {
+            print!("{}", opts.usage(&message));
+            exit(1);
+        }
+    };
+    let max_body_size: usize = match matches.opt_get_default("max-body-size", DEFAULT_MAX_BODY_SIZE) {
+        Ok(0) => {
+            print!("{}", opts.usage("Invalid --max-body-size: must be greater than 0"));
+            exit(1);
+        }
+        Ok(s) => s,
+        Err(e) => {
+            print!("{}", opts.usage(&format!("Invalid --max-body-size: {}", e)));
+            exit(1);
+        }
+    };
+    let config_file = match matches.opt_str("config") {
+        Some(path) => match ConfigFile::read(&path) {
+            Ok(c) => c,
+            Err(message) => {
+                println!("{}", message);
+                exit(1);
+            }
+        },
+        None => ConfigFile::default(),
+    };
+    if config_file.sanitizer.url_schemes.is_some() && matches.opt_present("url-schemes") {
+        print!("{}", opts.usage("URL schemes are set in the configuration file as well as with --url-schemes"));
+        exit(1);
+    }
+    let first_time_links: FirstTimeLinks = match matches.opt_get_default("first-time-links", FirstTimeLinks::Keep) {
+        Ok(l) => l,
+        Err(message) => {
+            print!("{}", opts.usage(&message));
+            exit(1);
+        }
+    };
+    let url_schemes = matches.opt_get_default("url-schemes", String::from(DEFAULT_URL_SCHEMES)).unwrap()
+        .split(',').map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty()).collect();
+    let mail_transport = match mail_transport(&matches) {
+        Ok(t) => t,
+        Err(message) => {
+            print!("{}", opts.usage(&message));
+            exit(1);
+        }
+    };
+    if matches.opt_present("notify-replies") && !matches.opt_present("public-url") {
+        print!("{}", opts.usage("Reply notifications require a public URL"));
+        exit(1);
+    }
+    if matches.opt_present("restamp") && !matches.opt_present("rerender") {
+        print!("{}", opts.usage("--restamp can only be used together with --rerender"));
+        exit(1);
+    }
+    // links in emails must keep working after a restart
+    let links_in_emails = matches.opt_present("notify-replies") || (matches.opt_present("moderation") && matches.opt_present("public-url"));
+    if links_in_emails && !matches.opt_present("secret") && !matches.opt_present("secret-file") {
+        print!("{}", opts.usage("Reply notifications and moderation links require --secret or --secret-file"));
+        exit(1);
+    }
+    let config = Config {
+        repo_path: matches.opt_get_default("repo", String::from(DEFAULT_REPO_PATH)).unwrap(),
+        repo_reset: matches.opt_present("reset"),
+        storage,
+        app_path: matches.opt_get_default("app", String::from(DEFAULT_APP_PATH)).unwrap(),
+        site_url: matches.opt_str("site"),
+        bind_addr: matches.opt_get_default("bind", String::from(DEFAULT_BIND_ADDR)).unwrap(),
+        cors_origin: matches.opt_str("origin"),
+        notify_addr: matches.opt_str("notify"),
+        mail_transport,
+        digest,
+        mail_from: matches.opt_get_default("mail-from", String::from(DEFAULT_MAIL_FROM)).unwrap(),
+        webhooks: matches.opt_strs("webhook"),
+        webhook_secret: match matches.opt_str("webhook-secret-file") {
+            Some(path) => Some(read_secret_file(&path)),
+            None => matches.opt_str("webhook-secret"),
+        },
+        notify_replies: matches.opt_present("notify-replies"),
+        public_url: matches.opt_str("public-url"),
+        moderation: matches.opt_present("moderation"),
+        admin_token: match matches.opt_str("admin-token-file") {
+            Some(path) => Some(read_secret_file(&path)),
+            None => matches.opt_str("admin-token"),
+        },
+        secret: match matches.opt_str("secret-file") {
+            Some(path) => Some(read_secret_file(&path)),
+            None => matches.opt_str("secret"),
+        },
+        edit_window: Duration::minutes(edit_window),
+        markdown: MarkdownOptions {
+            extensions: md_extensions,
+            heading_offset: md_heading_offset,
+            links_in_new_window: matches.opt_present("links-new-window"),
+            url_schemes,
+            first_time_links,
+            sanitizer: config_file.sanitizer,
+            highlight: matches.opt_present("highlight"),
+        },
+        limits,
+        max_body_size,
+    };
 
-    if let Some(filename) = csv_file {
-        quvyn::import(repo_path, repo_reset, filename);
+    if let Some(filename) = matches.opt_str("import") {
+        quvyn::import(config, filename);
+    } else if matches.opt_present("migrate") {
+        quvyn::migrate(config);
+    } else if matches.opt_present("rerender") {
+        quvyn::rerender(config, matches.opt_present("restamp"));
     } else {
-        quvyn::run(repo_path, repo_reset, app_path, bind_addr, cors_origin, notify_addr);
+        quvyn::run(config);
+    }
+
+}
+
+fn comment_limits(matches: &getopts::Matches) -> Result<CommentLimits, String> {
+    let defaults = CommentLimits::default();
+    let limit = |name: &str, default: usize| match matches.opt_get_default(name, default) {
+        Ok(0) => Err(format!("Invalid --{}: must be greater than 0", name)),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("Invalid --{}: {}", name, e)),
+    };
+    Ok(CommentLimits {
+        text_length: limit("max-text-length", defaults.text_length)?,
+        name_length: limit("max-name-length", defaults.name_length)?,
+        email_length: limit("max-email-length", defaults.email_length)?,
+        path_length: limit("max-path-length", defaults.path_length)?,
+    })
+}
+
+fn mail_transport(matches: &getopts::Matches) -> Result<MailTransport, String> {
+    let server = match matches.opt_str("smtp") {
+        Some(s) => s,
+        None => return Ok(MailTransport::Sendmail),
+    };
+    let (host, port) = smtp_host_and_port(&server)?;
+    let password = match matches.opt_str("smtp-password-file") {
+        Some(path) => Some(read_secret_file(&path)),
+        None => matches.opt_str("smtp-password"),
+    };
+    let credentials = match (matches.opt_str("smtp-user"), password) {
+        (Some(user), Some(password)) => Some((user, password)),
+        (None, None) => None,
+        _ => return Err("The SMTP user and password must be specified together".to_owned()),
+    };
+    let security = if matches.opt_present("smtp-starttls") { SmtpSecurity::StartTls } else { SmtpSecurity::Plain };
+    if credentials.is_some() && security == SmtpSecurity::Plain {
+        return Err("The SMTP user and password are only sent over an encrypted connection; add --smtp-starttls".to_owned());
     }
+    Ok(MailTransport::Smtp(SmtpSettings { host, port, security, credentials }))
+}
+
+/// Splits `host`, `host:port`, `[v6]` or `[v6]:port` into host and port. An IPv6 address
+/// without brackets is taken as a host without a port.
+fn smtp_host_and_port(server: &str) -> Result<(String, u16), String> {
+    let (host, port) = match server.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(format!("Invalid SMTP server: {}", server)),
+            },
+            None => return Err(format!("Invalid SMTP server: {}", server)),
+        },
+        None => match server.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host, Some(port)),
+            _ => (server, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(format!("Invalid SMTP server: {}", server));
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| format!("Invalid SMTP port: {}", port))?,
+        None => DEFAULT_SMTP_PORT,
+    };
+    Ok((host.to_owned(), port))
+}
 
+fn read_secret_file(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(contents) if !contents.trim().is_empty() => contents.trim().to_owned(),
+        Ok(_) => {
+            println!("File {} is empty", path);
+            exit(1);
+        }
+        Err(e) => {
+            println!("Failed to read file {}: {}", path, e);
+            exit(1);
+        }
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_smtp_server_into_host_and_port() {
+        let split = smtp_host_and_port;
+
+        assert_eq!(Ok(("mail.example.org".to_owned(), 587)), split("mail.example.org:587"));
+        assert_eq!(Ok(("mail.example.org".to_owned(), DEFAULT_SMTP_PORT)), split("mail.example.org"));
+        assert_eq!(Ok(("::1".to_owned(), DEFAULT_SMTP_PORT)), split("::1"));
+        assert_eq!(Ok(("::1".to_owned(), DEFAULT_SMTP_PORT)), split("[::1]"));
+        assert_eq!(Ok(("::1".to_owned(), 2525)), split("[::1]:2525"));
+        assert!(split("[::1").is_err());
+        assert!(split("[::1]2525").is_err());
+        assert!(split("mail.example.org:smtp").is_err());
+    }
+}
@@ -1,15 +1,207 @@
-use ammonia::clean;
-use pulldown_cmark::{html::push_html, Options, Parser};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::str::FromStr;
 
-pub fn md_to_html(md: &str) -> String {
+use ammonia::Builder;
+use sha2::{Digest, Sha256};
+use pulldown_cmark::{html::push_html, CodeBlockKind, CowStr, Event, HeadingLevel, LinkType, Options, Parser, Tag};
+
+use crate::highlight;
+use crate::sanitizer::SanitizerConfig;
+
+/// The Markdown extensions that can be enabled, by the names used in the configuration.
+const EXTENSIONS: [(&str, Options); 6] = [
+    ("tables", Options::ENABLE_TABLES),
+    ("footnotes", Options::ENABLE_FOOTNOTES),
+    ("strikethrough", Options::ENABLE_STRIKETHROUGH),
+    ("tasklists", Options::ENABLE_TASKLISTS),
+    ("smart-punctuation", Options::ENABLE_SMART_PUNCTUATION),
+    ("heading-attributes", Options::ENABLE_HEADING_ATTRIBUTES),
+];
+
+/// The value of the `rel` attribute of all links in comments. It tells search engines that
+/// the links were added by users and should not be followed.
+const LINK_REL: &str = "nofollow ugc noopener";
+
+/// What happens to the links in comments by authors who have not commented before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirstTimeLinks
+{
+    /// The links are kept.
+    Keep,
+    /// The links are replaced by their text, followed by the URL in parentheses.
+    Unlink,
+    /// The links are replaced by their text.
+    Strip,
+}
+
+impl FromStr for FirstTimeLinks
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(FirstTimeLinks::Keep),
+            "unlink" => Ok(FirstTimeLinks::Unlink),
+            "strip" => Ok(FirstTimeLinks::Strip),
+            _ => Err(format!("Unknown handling for links: {}", s)),
+        }
+    }
+}
+
+/// Determines how the Markdown in comments is turned into HTML.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MarkdownOptions
+{
+    pub extensions: Options,
+    /// How many levels headings are moved down, so that a heading in a comment does not
+    /// compete with the headings of the page. Headings never go below `<h6>`.
+    pub heading_offset: usize,
+    /// Whether links open in a new window or tab.
+    pub links_in_new_window: bool,
+    /// The URL schemes allowed in links and images. Other URLs are removed.
+    pub url_schemes: Vec<String>,
+    pub first_time_links: FirstTimeLinks,
+    pub sanitizer: SanitizerConfig,
+    /// Whether fenced code blocks with a known language are highlighted.
+    pub highlight: bool,
+}
+
+impl Default for MarkdownOptions
+{
+    fn default() -> Self {
+        MarkdownOptions {
+            extensions: Options::ENABLE_TABLES,
+            heading_offset: 1,
+            links_in_new_window: false,
+            url_schemes: vec!["http".to_owned(), "https".to_owned(), "mailto".to_owned()],
+            first_time_links: FirstTimeLinks::Keep,
+            sanitizer: SanitizerConfig::default(),
+            highlight: false,
+        }
+    }
+}
+
+impl MarkdownOptions
+{
+    /// Identifies the rendering of comments with these options and this version of Quvyn.
+    /// Comments store it, so that comments rendered differently can be found.
+    pub fn fingerprint(&self) -> String {
+        let hash = Sha256::digest(format!("{}\n{:?}", env!("CARGO_PKG_VERSION"), self).as_bytes());
+        hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Parses a comma-separated list of extension names, eg. `tables,strikethrough`.
+pub fn parse_extensions(list: &str) -> Result<Options, String> {
     let mut options = Options::empty();
-    options.insert(Options::ENABLE_TABLES);
+    for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match EXTENSIONS.iter().find(|(n, _)| *n == name) {
+            Some((_, option)) => options.insert(*option),
+            None => return Err(format!("Unknown Markdown extension: {}", name)),
+        }
+    }
+    Ok(options)
+}
+
+pub fn md_to_html(md: &str, options: &MarkdownOptions) -> String {
+    render(md, options, FirstTimeLinks::Keep, "fn-")
+}
+
+/// Converts the Markdown of a comment like `md_to_html`. The ids of footnotes start with the
+/// idh of the comment, so that they do not clash between the comments on a page. For authors
+/// who have not commented before, links are handled as set in the options.
+pub fn comment_to_html(md: &str, options: &MarkdownOptions, idh: &str, first_time_author: bool) -> String {
+    let links = if first_time_author { options.first_time_links } else { FirstTimeLinks::Keep };
+    render(md, options, links, &format!("fn-{}-", idh))
+}
 
-    let md_parse = Parser::new_ext(md, options);
+fn render(md: &str, options: &MarkdownOptions, links: FirstTimeLinks, id_prefix: &str) -> String {
+    let prefixed = |label: CowStr| CowStr::from(format!("{}{}", id_prefix, label));
+    let md_parse = Parser::new_ext(md, options.extensions).map(|event| match event {
+        Event::FootnoteReference(label) => Event::FootnoteReference(prefixed(label)),
+        Event::Start(Tag::FootnoteDefinition(label)) => Event::Start(Tag::FootnoteDefinition(prefixed(label))),
+        Event::End(Tag::FootnoteDefinition(label)) => Event::End(Tag::FootnoteDefinition(prefixed(label))),
+        Event::Start(Tag::Heading(level, id, classes)) => Event::Start(Tag::Heading(demote(level, options.heading_offset), id, classes)),
+        Event::End(Tag::Heading(level, id, classes)) => Event::End(Tag::Heading(demote(level, options.heading_offset), id, classes)),
+        Event::End(Tag::Link(link_type, url, _)) if links == FirstTimeLinks::Unlink && shows_url_separately(link_type) => {
+            Event::Text(CowStr::from(format!(" ({})", url)))
+        }
+        _ => event
+    });
     let mut unsafe_html = String::new();
-    push_html(&mut unsafe_html, md_parse);
+    if options.highlight {
+        push_html(&mut unsafe_html, highlight_code_blocks(md_parse).into_iter());
+    } else {
+        push_html(&mut unsafe_html, md_parse);
+    }
+
+    let mut sanitizer = Builder::default();
+    sanitizer.link_rel(Some(LINK_REL))
+        .url_schemes(options.url_schemes.iter().map(String::as_str).collect::<HashSet<&str>>());
+    options.sanitizer.apply(&mut sanitizer);
+    if options.links_in_new_window {
+        sanitizer.set_tag_attribute_value("a", "target", "_blank");
+    }
+    if links != FirstTimeLinks::Keep {
+        // the sanitizer keeps the text of tags that are not allowed
+        sanitizer.rm_tags(&["a"]);
+    }
+    if options.highlight && !options.sanitizer.allows_attribute("span", "class") {
+        sanitizer.add_allowed_classes("span", &highlight::CLASSES);
+    }
+    if options.extensions.contains(Options::ENABLE_FOOTNOTES) {
+        // footnote references link to the definitions by their id; any other id gets the
+        // prefix as well, so that comments cannot use the ids of the page
+        sanitizer.add_tags(&["div", "sup"])
+            .add_tag_attributes("div", &["id"])
+            .id_prefix(Some(id_prefix));
+    }
+    if options.extensions.contains(Options::ENABLE_TASKLISTS) {
+        // task list items are rendered as checkboxes, which must not become form fields
+        sanitizer.add_tags(&["input"])
+            .add_tag_attributes("input", &["checked"])
+            .set_tag_attribute_value("input", "type", "checkbox")
+            .set_tag_attribute_value("input", "disabled", "");
+    }
+    sanitizer.clean(&unsafe_html).to_string()
+}
 
-    clean(&*unsafe_html)
+/// Replaces fenced code blocks in a known language with highlighted HTML. The HTML still
+/// goes through the sanitizer.
+fn highlight_code_blocks<'a>(events: impl Iterator<Item=Event<'a>>) -> Vec<Event<'a>> {
+    let mut result = Vec::new();
+    let mut block: Option<(CowStr, Vec<Event>)> = None;
+    for event in events {
+        match (event, &mut block) {
+            (Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))), None) => {
+                block = Some((info.clone(), vec![Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))]));
+            }
+            (Event::End(Tag::CodeBlock(kind)), Some((info, events))) => {
+                let code: String = events.iter().filter_map(|e| match e { Event::Text(t) => Some(t.as_ref()), _ => None }).collect();
+                let language = info.split_whitespace().next().unwrap_or("");
+                match highlight::highlight(&code, language) {
+                    Some(html) => result.push(Event::Html(CowStr::from(format!("<pre><code>{}</code></pre>\n", html)))),
+                    None => {
+                        result.append(events);
+                        result.push(Event::End(Tag::CodeBlock(kind)));
+                    }
+                }
+                block = None;
+            }
+            (event, Some((_, events))) => events.push(event),
+            (event, None) => result.push(event),
+        }
+    }
+    result
+}
+
+fn shows_url_separately(link_type: LinkType) -> bool {
+    !matches!(link_type, LinkType::Autolink | LinkType::Email)
+}
+
+fn demote(level: HeadingLevel, offset: usize) -> HeadingLevel {
+    HeadingLevel::try_from((level as usize + offset).min(6)).unwrap_or(HeadingLevel::H6)
 }
 
 
@@ -28,7 +220,7 @@ mod tests {
     fn converts_simple_markdown() {
         let md = r#" * list _i_"#;
 
-        let html = md_to_html(md);
+        let html = md_to_html(md, &MarkdownOptions::default());
 
         assert_eq!(normalize_ws(&html), "<ul> <li>list <em>i</em></li> </ul> ");
     }
@@ -39,7 +231,7 @@ mod tests {
                 int foo() { return 3; }
                 ```"#;
 
-        let html = md_to_html(md);
+        let html = md_to_html(md, &MarkdownOptions::default());
 
         assert_eq!(normalize_ws(&html), r#"<pre><code> int foo() { return 3; } ```</code></pre> "#);
     }
@@ -48,8 +240,130 @@ mod tests {
     fn removes_harmful_html() {
         let md = r#"x <iframe src="foo"></iframe><script>alert(\"foo\")</script> y"#;
 
-        let html = md_to_html(md);
+        let html = md_to_html(md, &MarkdownOptions::default());
 
         assert_eq!(normalize_ws(&html), r#"<p>x y</p> "#);
     }
+
+    #[test]
+    fn demotes_headings() {
+        let md = "# Title\n\n##### Small";
+
+        let html = md_to_html(md, &MarkdownOptions::default());
+
+        assert_eq!(normalize_ws(&html), "<h2>Title</h2> <h6>Small</h6> ");
+    }
+
+    #[test]
+    fn enables_configured_extensions_only() {
+        let md = "~~gone~~ \"quoted\"";
+        let options = MarkdownOptions { extensions: parse_extensions("strikethrough, smart-punctuation").unwrap(), heading_offset: 0, ..MarkdownOptions::default() };
+
+        assert_eq!(normalize_ws(&md_to_html(md, &options)), "<p><del>gone</del> \u{201c}quoted\u{201d}</p> ");
+        assert_eq!(normalize_ws(&md_to_html(md, &MarkdownOptions::default())), "<p>~~gone~~ \"quoted\"</p> ");
+    }
+
+    #[test]
+    fn renders_task_lists_as_disabled_checkboxes() {
+        let md = "- [x] done\n- [ ] open";
+        let options = MarkdownOptions { extensions: parse_extensions("tasklists").unwrap(), heading_offset: 0, ..MarkdownOptions::default() };
+
+        let html = md_to_html(md, &options);
+
+        assert_eq!(2, html.matches(r#"type="checkbox""#).count());
+        assert_eq!(2, html.matches("disabled").count());
+        assert_eq!(1, html.matches("checked").count());
+    }
+
+    #[test]
+    fn rejects_unknown_extension() {
+        assert_eq!(Err("Unknown Markdown extension: emoji".to_owned()), parse_extensions("tables,emoji"));
+        assert_eq!(Ok(Options::empty()), parse_extensions(""));
+    }
+
+    #[test]
+    fn marks_links_as_user_generated() {
+        let html = md_to_html("[site](https://example.org/)", &MarkdownOptions::default());
+
+        assert_eq!(normalize_ws(&html), r#"<p><a href="https://example.org/" rel="nofollow ugc noopener">site</a></p> "#);
+    }
+
+    #[test]
+    fn opens_links_in_new_window_when_configured() {
+        let options = MarkdownOptions { links_in_new_window: true, ..MarkdownOptions::default() };
+
+        let html = md_to_html("[site](https://example.org/)", &options);
+
+        assert!(html.contains(r#"target="_blank""#));
+    }
+
+    #[test]
+    fn removes_urls_with_schemes_not_allowed() {
+        let options = MarkdownOptions { url_schemes: vec!["https".to_owned()], ..MarkdownOptions::default() };
+
+        let html = md_to_html("[a](https://example.org/) [b](http://example.org/) [c](javascript:alert(1))", &options);
+
+        assert_eq!(1, html.matches("href").count());
+        assert!(html.contains(r#"href="https://example.org/""#));
+    }
+
+    #[test]
+    fn unlinks_or_strips_links_by_first_time_authors() {
+        let md = r#"[site](https://example.org/) <https://example.com/> <a href="https://example.net/">raw</a>"#;
+        let unlink = MarkdownOptions { first_time_links: FirstTimeLinks::Unlink, ..MarkdownOptions::default() };
+        let strip = MarkdownOptions { first_time_links: FirstTimeLinks::Strip, ..MarkdownOptions::default() };
+
+        assert_eq!(normalize_ws(&comment_to_html(md, &unlink, "c1", true)),
+                   "<p>site (https://example.org/) https://example.com/ raw</p> ");
+        assert_eq!(normalize_ws(&comment_to_html(md, &strip, "c1", true)),
+                   "<p>site https://example.com/ raw</p> ");
+        assert_eq!(3, comment_to_html(md, &strip, "c1", false).matches("<a ").count());
+    }
+
+    #[test]
+    fn prefixes_footnote_ids_with_comment_idh() {
+        let md = "Text[^note]\n\n[^note]: The note <span id=\"comments\">x</span>";
+        let options = MarkdownOptions { extensions: parse_extensions("footnotes").unwrap(), ..MarkdownOptions::default() };
+
+        let html = comment_to_html(md, &options, "c1", false);
+
+        assert!(html.contains(r##"<sup><a href="#fn-c1-note" rel="nofollow ugc noopener">1</a></sup>"##), "{}", html);
+        assert!(html.contains(r#"<div id="fn-c1-note"><sup>1</sup>"#), "{}", html);
+        assert!(!html.contains(r#"id="comments""#));
+        assert!(comment_to_html(md, &options, "c2", false).contains(r#"id="fn-c2-note""#));
+        assert!(!md_to_html(md, &MarkdownOptions::default()).contains("id="));
+    }
+
+    #[test]
+    fn applies_sanitizer_configuration() {
+        let sanitizer: SanitizerConfig = serde_json::from_str(r#"{ "tags": ["p", "em", "a"], "urlSchemes": ["https"] }"#).unwrap();
+        let options = MarkdownOptions { sanitizer, ..MarkdownOptions::default() };
+
+        let html = md_to_html("_x_ [a](http://example.org/)\n\n|a|\n|-|\n|b|", &options);
+
+        assert_eq!(normalize_ws(&html), r#"<p><em>x</em> <a rel="nofollow ugc noopener">a</a></p> a b "#);
+    }
+
+    #[test]
+    fn highlights_fenced_code_blocks_when_enabled() {
+        let md = "```rust\nlet x = 1;\n```\n\n```\nlet y = 2;\n```";
+        let options = MarkdownOptions { highlight: true, ..MarkdownOptions::default() };
+
+        let html = md_to_html(md, &options);
+
+        assert_eq!(normalize_ws(&html), concat!(
+            r#"<pre><code><span class="qv-hl-keyword">let</span> x = <span class="qv-hl-number">1</span>; </code></pre> "#,
+            r#"<pre><code>let y = 2; </code></pre> "#));
+        assert!(!md_to_html(md, &MarkdownOptions::default()).contains("span"));
+    }
+
+    #[test]
+    fn sanitizer_configuration_applies_to_highlighted_code() {
+        let sanitizer: SanitizerConfig = serde_json::from_str(r#"{ "tags": ["p", "pre", "code"] }"#).unwrap();
+        let options = MarkdownOptions { highlight: true, sanitizer, ..MarkdownOptions::default() };
+
+        let html = md_to_html("```js\nvar x = <b>1</b>;\n```", &options);
+
+        assert_eq!(normalize_ws(&html), "<pre><code>var x = &lt;b&gt;1&lt;/b&gt;; </code></pre> ");
+    }
 }
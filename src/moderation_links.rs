@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use crate::comment::{Comment, ModerationStatus};
+use crate::signing::Signer;
+use crate::utils::escape_markup;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModerationAction
+{
+    Approve,
+    Delete,
+}
+
+impl ModerationAction
+{
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Approve => "approve",
+            ModerationAction::Delete => "delete",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ModerationAction::Approve => "Approve",
+            ModerationAction::Delete => "Delete",
+        }
+    }
+}
+
+impl FromStr for ModerationAction
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "approve" => Ok(ModerationAction::Approve),
+            "delete" => Ok(ModerationAction::Delete),
+            _ => Err(format!("Unknown moderation action: {}", s)),
+        }
+    }
+}
+
+
+/// Creates links that let the owner moderate a comment straight from a notification email,
+/// without logging in. The links contain a signed token that names the action, the comment
+/// and the time when the link expires.
+#[derive(Clone)]
+pub struct ModerationLinks
+{
+    signer: Signer,
+    public_url: String,
+    validity: Duration,
+}
+
+impl ModerationLinks
+{
+    pub fn new(secret: &str, public_url: &str, validity: Duration) -> Self {
+        ModerationLinks {
+            signer: Signer::new(secret),
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            validity,
+        }
+    }
+
+    pub fn link(&self, action: ModerationAction, comment: &Comment) -> String {
+        format!("{}/moderate?token={}", self.public_url, token(&self.signer, action, comment.id, Utc::now() + self.validity))
+    }
+
+    /// Appends links for the actions that make sense for the comment to the text and HTML
+    /// of a mail: pending comments can be approved, and any comment can be deleted.
+    pub fn append_to(&self, comment: &Comment, text: &mut String, html: &mut String) {
+        let mut actions = vec![ModerationAction::Delete];
+        if comment.status == ModerationStatus::Pending {
+            actions.insert(0, ModerationAction::Approve);
+        }
+        let links: Vec<(ModerationAction, String)> = actions.into_iter().map(|a| (a, self.link(a, comment))).collect();
+        for (action, link) in &links {
+            text.push_str(&format!("{}: {}\n", action.label(), link));
+        }
+        let html_links: Vec<String> = links.iter()
+            .map(|(action, link)| format!("<a href=\"{}\">{}</a>", escape_markup(link), action.label()))
+            .collect();
+        html.push_str(&format!("<p>{}</p>\n", html_links.join(" | ")));
+    }
+}
+
+
+pub fn token(signer: &Signer, action: ModerationAction, id: Uuid, expires: DateTime<Utc>) -> String {
+    signer.token_for(&format!("moderate\n{}\n{}\n{}", action.as_str(), id.as_simple(), expires.timestamp()))
+}
+
+/// Returns the action and the comment id in a token, provided the token was created with the
+/// same secret and has not expired.
+pub fn action_in(signer: &Signer, token: &str, now: DateTime<Utc>) -> Option<(ModerationAction, Uuid)> {
+    let message = signer.message_in(token)?;
+    let parts: Vec<&str> = message.split('\n').collect();
+    match parts[..] {
+        ["moderate", action, id, expires] => {
+            if now.timestamp() > expires.parse::<i64>().ok()? {
+                return None;
+            }
+            Some((action.parse().ok()?, Uuid::parse_str(id).ok()?))
+        }
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_action_and_id_from_token() {
+        let signer = Signer::new("s3cret");
+        let id = Uuid::new_v4();
+        let token = token(&signer, ModerationAction::Delete, id, Utc::now() + Duration::days(1));
+
+        assert_eq!(Some((ModerationAction::Delete, id)), action_in(&signer, &token, Utc::now()));
+        assert_eq!(None, action_in(&Signer::new("other"), &token, Utc::now()));
+        assert_eq!(None, action_in(&signer, &signer.token_for(&format!("unsubscribe\n/a/\n{}", id)), Utc::now()));
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let signer = Signer::new("s3cret");
+        let token = token(&signer, ModerationAction::Approve, Uuid::new_v4(), Utc::now() + Duration::days(1));
+
+        assert_eq!(None, action_in(&signer, &token, Utc::now() + Duration::days(2)));
+    }
+
+    #[test]
+    fn creates_link_with_public_url() {
+        let links = ModerationLinks::new("s3cret", "https://comments.example.org/", Duration::days(7));
+        let comment = Comment::new("/a/", "Text", None, None);
+
+        let link = links.link(ModerationAction::Approve, &comment);
+
+        let token = link.strip_prefix("https://comments.example.org/moderate?token=").unwrap();
+        assert_eq!(Some((ModerationAction::Approve, comment.id)), action_in(&Signer::new("s3cret"), token, Utc::now()));
+    }
+
+    #[test]
+    fn offers_approval_for_pending_comments_only() {
+        let links = ModerationLinks::new("s3cret", "https://comments.example.org", Duration::days(7));
+        let mut comment = Comment::new("/a/", "Text", None, None);
+        comment.status = ModerationStatus::Pending;
+        let (mut text, mut html) = (String::new(), String::new());
+
+        links.append_to(&comment, &mut text, &mut html);
+        comment.status = ModerationStatus::Approved;
+        links.append_to(&comment, &mut text, &mut html);
+
+        let lines: Vec<&str> = text.lines().map(|l| l.split(':').next().unwrap()).collect();
+        assert_eq!(vec!["Approve", "Delete", "Delete"], lines);
+        assert_eq!(3, html.matches("<a href=\"https://comments.example.org/moderate?token=").count());
+    }
+}
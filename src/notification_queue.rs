@@ -0,0 +1,268 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use glob::glob;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::comment::Comment;
+use crate::notifier::{Event, Notifier};
+use crate::utils;
+
+
+#[derive(Clone, Debug)]
+pub struct QueueSettings
+{
+    /// How many notifications can wait for delivery before further notifications are only
+    /// spooled, to be picked up from the spool directory once the queue has room again.
+    pub capacity: usize,
+    /// How often delivery of a notification is attempted before it is given up.
+    pub max_attempts: u32,
+    /// The wait after the first failed attempt; it grows with every further attempt.
+    pub retry_interval: Duration,
+}
+
+impl Default for QueueSettings
+{
+    fn default() -> Self {
+        QueueSettings {
+            capacity: 100,
+            max_attempts: 5,
+            retry_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+
+/// A notification for one notifier, or for one recipient of a notifier, as it is stored in
+/// the spool directory.
+#[derive(Serialize, Deserialize)]
+struct Job
+{
+    id: Uuid,
+    notifier: String,
+    #[serde(default)]
+    recipient: Option<String>,
+    event: Event,
+    comment: Comment,
+    attempts: u32,
+}
+
+
+/// Delivers notifications in a background thread, so that slow notifiers do not hold up
+/// requests. Every notification is written to the spool directory before it is queued and
+/// removed once it is delivered, which means notifications that were not delivered when the
+/// server stopped are delivered after the next start.
+pub struct NotificationQueue
+{
+    spool_path: PathBuf,
+    notifiers: Vec<Arc<dyn Notifier>>,
+    sender: Mutex<SyncSender<Job>>,
+    overflowed: Arc<Mutex<bool>>,
+}
+
+impl NotificationQueue
+{
+    pub fn start(spool_path: &str, notifiers: Vec<Arc<dyn Notifier>>, settings: QueueSettings) -> Self {
+        fs::create_dir_all(spool_path).unwrap_or_else(|_| panic!("Failed to create spool directory {}", spool_path));
+        let queued_notifiers = notifiers.clone();
+        let (sender, receiver) = mpsc::sync_channel(settings.capacity);
+        let overflowed = Arc::new(Mutex::new(false));
+        let mut worker = Worker {
+            spool_path: PathBuf::from(spool_path),
+            notifiers: notifiers.into_iter().map(|n| (n.name(), n)).collect(),
+            settings,
+            retries: Vec::new(),
+            overflowed: Arc::clone(&overflowed),
+        };
+        worker.load_spooled_jobs();
+        thread::spawn(move || worker.run(receiver));
+        NotificationQueue {
+            spool_path: PathBuf::from(spool_path),
+            notifiers: queued_notifiers,
+            sender: Mutex::new(sender),
+            overflowed,
+        }
+    }
+}
+
+impl Notifier for NotificationQueue
+{
+    fn name(&self) -> String {
+        format!("queue:{}", self.spool_path.display())
+    }
+
+    fn notify(&self, event: Event, comment: &Comment) -> Result<(), String> {
+        let jobs = self.notifiers.iter().flat_map(|notifier| {
+            let recipients = match notifier.recipients(event, comment) {
+                Some(recipients) => recipients.into_iter().map(Some).collect(),
+                None => vec![None],
+            };
+            recipients.into_iter().map(move |recipient| Job {
+                id: Uuid::new_v4(),
+                notifier: notifier.name(),
+                recipient,
+                event,
+                comment: comment.clone(),
+                attempts: 0,
+            })
+        });
+        for job in jobs {
+            // the worker holds this lock while it looks for overflowed jobs, so it never sees
+            // a job that is spooled but not yet queued
+            let mut overflowed = self.overflowed.lock().unwrap();
+            write_job(&self.spool_path, &job)?;
+            match self.sender.lock().unwrap().try_send(job) {
+                Ok(()) => {}
+                Err(TrySendError::Full(job)) => {
+                    println!("Notification queue is full; notification {} stays in the spool until there is room", job.id);
+                    *overflowed = true;
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err("Notification queue has stopped".to_owned());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+struct Worker
+{
+    spool_path: PathBuf,
+    notifiers: HashMap<String, Arc<dyn Notifier>>,
+    settings: QueueSettings,
+    retries: Vec<(Instant, Job)>,
+    overflowed: Arc<Mutex<bool>>,
+}
+
+impl Worker
+{
+    fn load_spooled_jobs(&mut self) {
+        let now = Instant::now();
+        for job in self.read_spool(&HashSet::new()) {
+            self.retries.push((now, job));
+        }
+    }
+
+    /// Reads the jobs in the spool directory except the known ones. Files that cannot be read
+    /// are renamed, so that they are kept for inspection but not read again.
+    fn read_spool(&self, known: &HashSet<Uuid>) -> Vec<Job> {
+        let mut jobs = Vec::new();
+        for path in glob(&format!("{}/*.json", self.spool_path.display())).unwrap().flatten() {
+            let known_job = path.file_stem().and_then(|s| s.to_str()).and_then(|s| Uuid::parse_str(s).ok())
+                .is_some_and(|id| known.contains(&id));
+            if known_job {
+                continue;
+            }
+            println!("Loading notification from file: {}", path.display());
+            let job = fs::read_to_string(&path).map_err(|e| e.to_string())
+                .and_then(|contents| serde_json::from_str::<Job>(&contents).map_err(|e| e.to_string()));
+            match job {
+                Ok(job) => jobs.push(job),
+                Err(message) => {
+                    let mut corrupt_path = path.clone().into_os_string();
+                    corrupt_path.push(".corrupt");
+                    println!("Failed to load notification from file {}, renaming it to {}: {}",
+                             path.display(), PathBuf::from(&corrupt_path).display(), message);
+                    if let Err(e) = fs::rename(&path, &corrupt_path) {
+                        println!("Failed to rename file {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+        jobs
+    }
+
+    /// Picks up the jobs that were only spooled because the queue was full. These are the
+    /// spooled jobs that are neither waiting for a retry nor in the queue.
+    fn take_overflowed_jobs(&self, receiver: &Receiver<Job>) -> Vec<Job> {
+        let mut overflowed = self.overflowed.lock().unwrap();
+        if !*overflowed {
+            return Vec::new();
+        }
+        *overflowed = false;
+        let mut jobs: Vec<Job> = receiver.try_iter().collect();
+        let known = self.retries.iter().map(|(_, job)| job.id).chain(jobs.iter().map(|job| job.id)).collect();
+        jobs.extend(self.read_spool(&known));
+        jobs
+    }
+
+    fn run(mut self, receiver: Receiver<Job>) {
+        loop {
+            let now = Instant::now();
+            let (due, waiting) = self.retries.drain(..).partition(|(at, _)| *at <= now);
+            self.retries = waiting;
+            for (_, job) in due {
+                self.deliver(job);
+            }
+            let timeout = self.retries.iter().map(|(at, _)| at.saturating_duration_since(now)).min()
+                .unwrap_or(self.settings.retry_interval);
+            match receiver.recv_timeout(timeout) {
+                Ok(job) => self.deliver(job),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            for job in self.take_overflowed_jobs(&receiver) {
+                self.deliver(job);
+            }
+        }
+    }
+
+    fn deliver(&mut self, mut job: Job) {
+        let notifier = match self.notifiers.get(&job.notifier) {
+            Some(n) => n,
+            None => {
+                println!("Dropping notification {} for {}, which is not configured", job.id, job.notifier);
+                remove_job(&self.spool_path, &job);
+                return;
+            }
+        };
+        let result = match &job.recipient {
+            Some(recipient) => notifier.notify_recipient(job.event, &job.comment, recipient),
+            None => notifier.notify(job.event, &job.comment),
+        };
+        match result {
+            Ok(()) => remove_job(&self.spool_path, &job),
+            Err(message) => {
+                job.attempts += 1;
+                println!("Failed to deliver notification {} to {} (attempt {} of {}): {}",
+                         job.id, job.notifier, job.attempts, self.settings.max_attempts, message);
+                if job.attempts >= self.settings.max_attempts {
+                    println!("Giving up on notification {}", job.id);
+                    remove_job(&self.spool_path, &job);
+                } else {
+                    if let Err(message) = write_job(&self.spool_path, &job) {
+                        println!("{}", message);
+                    }
+                    let at = Instant::now() + self.settings.retry_interval * job.attempts;
+                    self.retries.push((at, job));
+                }
+            }
+        }
+    }
+}
+
+
+fn job_filename(spool_path: &Path, job: &Job) -> PathBuf {
+    spool_path.join(format!("{}.json", job.id.as_simple()))
+}
+
+fn write_job(spool_path: &Path, job: &Job) -> Result<(), String> {
+    let filename = job_filename(spool_path, job);
+    utils::write_file_atomically(&filename, &utils::to_json(job))
+        .map_err(|e| format!("Failed to write file {}: {}", filename.display(), e))
+}
+
+fn remove_job(spool_path: &Path, job: &Job) {
+    let filename = job_filename(spool_path, job);
+    if let Err(e) = fs::remove_file(&filename) {
+        println!("Failed to remove file {}: {}", filename.display(), e);
+    }
+}
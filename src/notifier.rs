@@ -1,26 +1,323 @@
+use std::panic::RefUnwindSafe;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::comment::{Comment, ModerationStatus};
+use crate::mail::Message;
+use crate::moderation_links::ModerationLinks;
 use crate::sendmail;
-use crate::comment::Comment;
+use crate::signing::Signer;
+use crate::smtp::{self, SmtpSettings};
+use crate::subscriptions::{Subscription, Subscriptions};
+use crate::utils::{escape_markup, page_url};
+
+/// The changes to comments that notifiers are told about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event
+{
+    Posted,
+    Approved,
+    Deleted,
+    /// The author of the comment asked to be notified of new comments on the page, which
+    /// they have to confirm first.
+    Subscribed,
+}
+
+/// A sink for notifications. The repository passes every event to all of its notifiers, and
+/// each notifier decides which events it is interested in.
+pub trait Notifier: Send + Sync + RefUnwindSafe
+{
+    /// Identifies the notifier in log messages and in the notification spool. It must be the
+    /// same after a restart with the same configuration.
+    fn name(&self) -> String;
+
+    fn notify(&self, event: Event, comment: &Comment) -> Result<(), String>;
+
+    /// The recipients of a notification, if it is sent to each of them separately. The
+    /// notification queue then delivers, and retries, the notification for each recipient on
+    /// its own with `notify_recipient`, so that a failure for one recipient does not send the
+    /// notification to the others again.
+    fn recipients(&self, _event: Event, _comment: &Comment) -> Option<Vec<String>> {
+        None
+    }
+
+    fn notify_recipient(&self, event: Event, comment: &Comment, _recipient: &str) -> Result<(), String> {
+        self.notify(event, comment)
+    }
+}
+
+
+/// How notification mails are sent.
+#[derive(Clone, Debug)]
+pub enum MailTransport
+{
+    /// Pipes the mail into the local `sendmail` binary.
+    Sendmail,
+    /// Delivers the mail to an SMTP server.
+    Smtp(SmtpSettings),
+}
+
+impl MailTransport
+{
+    pub fn send(&self, message: &Message) -> Result<(), String> {
+        let formatted = message.format()?;
+        let result = match self {
+            MailTransport::Sendmail => sendmail::send(&formatted),
+            MailTransport::Smtp(settings) => smtp::send(settings, &message.from, &message.to, &formatted),
+        };
+        result.map_err(|e| format!("Error when sending mail to {}: {}", message.to, e))
+    }
+}
 
+/// Sends a mail to an address whenever a comment is posted.
 #[derive(Clone)]
-pub struct Notifier
+pub struct MailNotifier
 {
-    recipient: String
+    recipient: String,
+    sender: String,
+    site_url: Option<String>,
+    transport: MailTransport,
+    moderation_links: Option<ModerationLinks>,
 }
 
-impl Notifier
+impl MailNotifier
 {
-    pub fn new(recipient: &str) -> Self {
-        Notifier {
-            recipient: recipient.to_string()
+    pub fn new(recipient: &str, sender: &str, site_url: &Option<String>, transport: MailTransport) -> Self {
+        MailNotifier {
+            recipient: recipient.to_string(),
+            sender: sender.to_string(),
+            site_url: site_url.clone(),
+            transport,
+            moderation_links: None,
+        }
+    }
+
+    /// Adds links to approve and delete the comment to every mail.
+    pub fn with_moderation_links(mut self, links: ModerationLinks) -> Self {
+        self.moderation_links = Some(links);
+        self
+    }
+
+    fn message_for(&self, comment: &Comment) -> Message {
+        let author = comment.author_name.as_deref().unwrap_or("Anonymous");
+        let link = format!("{}#comment-{}", page_url(&self.site_url, &comment.path), comment.idh);
+        let mut text = format!("{} commented on {}\n\n{}\n", author, link, comment.text);
+        let mut html = format!("<p>{} commented on <a href=\"{}\">{}</a></p>\n{}",
+                               escape_markup(author), escape_markup(&link), escape_markup(&link), comment.text_html);
+        if comment.status == ModerationStatus::Pending {
+            text.push_str("\nThe comment is awaiting moderation.\n");
+            html.push_str("<p><em>The comment is awaiting moderation.</em></p>\n");
+        }
+        if let Some(links) = &self.moderation_links {
+            text.push('\n');
+            links.append_to(comment, &mut text, &mut html);
+        }
+        Message {
+            from: self.sender.clone(),
+            to: self.recipient.clone(),
+            subject: format!("New comment by {} on {}", author, comment.path),
+            text,
+            html: Some(html),
         }
     }
+}
+
+impl Notifier for MailNotifier
+{
+    fn name(&self) -> String {
+        format!("mail:{}", self.recipient)
+    }
 
-    pub fn notify(&self, comment: &Comment)
+    fn notify(&self, event: Event, comment: &Comment) -> Result<(), String>
     {
-        let result = sendmail::send(&self.recipient, &"New comment posted", &format!("{:?}", comment));
-        if let Err(message) = result {
-            println!("Error when sending mail: {}", message);
+        if event != Event::Posted {
+            return Ok(());
         }
+        self.transport.send(&self.message_for(comment))
     }
+}
+
+
+/// Sends a mail to the commenters who subscribed to a page whenever a comment on that page
+/// becomes visible. Each mail contains a link to unsubscribe. Commenters who ask to subscribe
+/// are first sent a mail with a link to confirm the subscription.
+pub struct ReplyNotifier
+{
+    subscriptions: Subscriptions,
+    signer: Signer,
+    public_url: String,
+    sender: String,
+    site_url: Option<String>,
+    transport: MailTransport,
+}
 
+impl ReplyNotifier
+{
+    pub fn new(subscriptions: Subscriptions, secret: &str, public_url: &str, sender: &str, site_url: &Option<String>,
+               transport: MailTransport) -> Self {
+        ReplyNotifier {
+            subscriptions,
+            signer: Signer::new(secret),
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            sender: sender.to_string(),
+            site_url: site_url.clone(),
+            transport,
+        }
+    }
+
+    fn message_for(&self, subscription: &Subscription, comment: &Comment) -> Message {
+        let author = comment.author_name.as_deref().unwrap_or("Anonymous");
+        let link = format!("{}#comment-{}", page_url(&self.site_url, &comment.path), comment.idh);
+        let unsubscribe_link = format!("{}/unsubscribe?token={}", self.public_url, subscription.unsubscribe_token(&self.signer));
+        let notice = "You receive this email because you asked to be notified of new comments on this page.";
+        let text = format!("{} commented on {}\n\n{}\n\n-- \n{} To stop these emails, open {}\n",
+                           author, link, comment.text, notice, unsubscribe_link);
+        let html = format!("<p>{} commented on <a href=\"{}\">{}</a></p>\n{}<hr>\n<p><small>{} <a href=\"{}\">Unsubscribe</a></small></p>\n",
+                           escape_markup(author), escape_markup(&link), escape_markup(&link), comment.text_html,
+                           notice, escape_markup(&unsubscribe_link));
+        Message {
+            from: self.sender.clone(),
+            to: subscription.email.clone(),
+            subject: format!("New comment by {} on {}", author, comment.path),
+            text,
+            html: Some(html),
+        }
+    }
+
+    fn confirmation_message_for(&self, subscription: &Subscription) -> Message {
+        let page = page_url(&self.site_url, &subscription.path);
+        let confirm_link = format!("{}/confirm?token={}", self.public_url, subscription.confirm_token(&self.signer));
+        let notice = "If you did not ask for this, ignore this email and you will not receive any more of them.";
+        let text = format!("Someone, probably you, asked to be notified by email of new comments on {}.\n\n\
+                            To confirm, open {}\n\n{}\n", page, confirm_link, notice);
+        let html = format!("<p>Someone, probably you, asked to be notified by email of new comments on <a href=\"{}\">{}</a>.</p>\n\
+                            <p><a href=\"{}\">Confirm</a></p>\n<p><small>{}</small></p>\n",
+                           escape_markup(&page), escape_markup(&page), escape_markup(&confirm_link), notice);
+        Message {
+            from: self.sender.clone(),
+            to: subscription.email.clone(),
+            subject: format!("Please confirm notifications of new comments on {}", subscription.path),
+            text,
+            html: Some(html),
+        }
+    }
+}
+
+impl Notifier for ReplyNotifier
+{
+    fn name(&self) -> String {
+        "replies".to_owned()
+    }
+
+    fn notify(&self, event: Event, comment: &Comment) -> Result<(), String>
+    {
+        let errors: Vec<String> = self.recipients(event, comment).unwrap_or_default().iter()
+            .filter_map(|email| self.notify_recipient(event, comment, email).err())
+            .collect();
+        if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) }
+    }
+
+    /// The subscribers to the page other than the author, once the comment becomes visible,
+    /// or the author, when they ask to subscribe.
+    fn recipients(&self, event: Event, comment: &Comment) -> Option<Vec<String>> {
+        let became_visible = match event {
+            Event::Posted => comment.status == ModerationStatus::Approved,
+            Event::Approved => true,
+            Event::Deleted => false,
+            Event::Subscribed => return Some(comment.author_email.iter().cloned().collect()),
+        };
+        if !became_visible {
+            return Some(Vec::new());
+        }
+        let author_email = comment.author_email.as_deref().map(|e| e.trim().to_lowercase());
+        Some(self.subscriptions.subscribers(&comment.path).into_iter()
+            .map(|s| s.email)
+            .filter(|email| Some(email) != author_email.as_ref())
+            .collect())
+    }
+
+    /// Sends the mail unless the recipient unsubscribed, or confirmed the subscription, while
+    /// it was waiting for delivery.
+    fn notify_recipient(&self, event: Event, comment: &Comment, recipient: &str) -> Result<(), String> {
+        let subscription = Subscription::new(&comment.path, recipient);
+        if event == Event::Subscribed {
+            if !self.subscriptions.is_unconfirmed(&subscription) {
+                return Ok(());
+            }
+            return self.transport.send(&self.confirmation_message_for(&subscription));
+        }
+        if !self.subscriptions.subscribers(&comment.path).contains(&subscription) {
+            return Ok(());
+        }
+        self.transport.send(&self.message_for(&subscription, comment))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_links_to_comment_and_contains_text_and_html() {
+        let site_url = Some("https://example.org/".to_owned());
+        let notifier = MailNotifier::new("joe@example.org", "quvyn@example.org", &site_url, MailTransport::Sendmail);
+        let comment = Comment::new("/a/", "_Nice_ work", Some("<Jane>"), None);
+
+        let message = notifier.message_for(&comment);
+
+        let link = format!("https://example.org/a/#comment-{}", comment.idh);
+        assert_eq!("New comment by <Jane> on /a/", message.subject);
+        assert_eq!(format!("<Jane> commented on {}\n\n_Nice_ work\n", link), message.text);
+        let html = message.html.unwrap();
+        assert!(html.starts_with(&format!("<p>&lt;Jane&gt; commented on <a href=\"{}\">", link)));
+        assert!(html.contains("<em>Nice</em> work"));
+    }
+
+    #[test]
+    fn message_contains_moderation_links_when_configured() {
+        let links = ModerationLinks::new("s3cret", "https://comments.example.org", chrono::Duration::days(7));
+        let notifier = MailNotifier::new("joe@example.org", "quvyn@example.org", &None, MailTransport::Sendmail)
+            .with_moderation_links(links);
+        let mut comment = Comment::new("/a/", "Text", None, None);
+        comment.status = ModerationStatus::Pending;
+
+        let message = notifier.message_for(&comment);
+
+        assert!(message.text.contains("\nApprove: https://comments.example.org/moderate?token="));
+        assert!(message.text.contains("\nDelete: https://comments.example.org/moderate?token="));
+    }
+
+    #[test]
+    fn reply_message_contains_unsubscribe_link() {
+        let notifier = ReplyNotifier::new(Subscriptions::default(), "s3cret", "https://comments.example.org/", "quvyn@example.org",
+                                          &None, MailTransport::Sendmail);
+        let subscription = Subscription::new("/a/", "jane@example.org");
+        let comment = Comment::new("/a/", "A reply", None, None);
+
+        let message = notifier.message_for(&subscription, &comment);
+
+        let token = subscription.unsubscribe_token(&Signer::new("s3cret"));
+        assert_eq!("jane@example.org", message.to);
+        assert!(message.text.contains(&format!("https://comments.example.org/unsubscribe?token={}\n", token)));
+        assert!(message.html.unwrap().contains(&format!("<a href=\"https://comments.example.org/unsubscribe?token={}\">", token)));
+    }
+
+    #[test]
+    fn confirmation_is_sent_to_author_asking_to_subscribe() {
+        let notifier = ReplyNotifier::new(Subscriptions::default(), "s3cret", "https://comments.example.org/", "quvyn@example.org",
+                                          &None, MailTransport::Sendmail);
+        let subscription = Subscription::new("/a/", "jane@example.org");
+        let comment = Comment::new("/a/", "A comment", None, Some("jane@example.org"));
+
+        let recipients = notifier.recipients(Event::Subscribed, &comment);
+        let message = notifier.confirmation_message_for(&subscription);
+
+        let token = subscription.confirm_token(&Signer::new("s3cret"));
+        assert_eq!(Some(vec!["jane@example.org".to_owned()]), recipients);
+        assert_eq!("jane@example.org", message.to);
+        assert!(message.text.contains(&format!("https://comments.example.org/confirm?token={}\n", token)));
+        assert!(message.html.unwrap().contains(&format!("<a href=\"https://comments.example.org/confirm?token={}\">", token)));
+    }
 }
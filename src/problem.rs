@@ -0,0 +1,114 @@
+use gotham::helpers::http::header::X_REQUEST_ID;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::{Body, Response, StatusCode};
+use gotham::hyper::header::CONTENT_TYPE;
+use gotham::state::{request_id, State};
+use serde_derive::Serialize;
+
+use crate::validation::{FieldError, ValidationErrors};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+
+/// An error response in the format of RFC 7807. The type is always `about:blank`, so the
+/// title is the reason phrase of the status; clients should look at the `code`, which names
+/// the problem and does not change between versions.
+#[derive(Debug, Serialize)]
+pub struct Problem
+{
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: String,
+    status: u16,
+    code: &'static str,
+    detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+impl Problem
+{
+    pub fn new(status: StatusCode, code: &'static str, detail: &str) -> Self {
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_owned(),
+            status: status.as_u16(),
+            code,
+            detail: detail.to_owned(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn bad_request(code: &'static str, detail: &str) -> Self {
+        Problem::new(StatusCode::BAD_REQUEST, code, detail)
+    }
+
+    pub fn comment_not_found() -> Self {
+        Problem::new(StatusCode::NOT_FOUND, "comment-not-found", "There is no comment with this id")
+    }
+
+    pub fn not_found() -> Self {
+        Problem::new(StatusCode::NOT_FOUND, "not-found", "There is nothing at this path")
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Problem::new(StatusCode::METHOD_NOT_ALLOWED, "method-not-allowed", "This method is not supported for this path")
+    }
+
+    /// A problem that lists each field that failed validation.
+    pub fn invalid_fields(errors: ValidationErrors) -> Self {
+        Problem { errors: errors.errors, ..Problem::bad_request("invalid-fields", "Some fields are missing or invalid") }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap()
+    }
+
+    pub fn to_response(&self, state: &State) -> Response<Body> {
+        create_response(state, self.status(), mime(), serde_json::to_vec(self).unwrap())
+    }
+
+    /// Turns a response created by Gotham, eg. when a query string cannot be parsed, into
+    /// the response for this problem.
+    pub fn extend(&self, state: &State, response: &mut Response<Body>) {
+        *response.status_mut() = self.status();
+        response.headers_mut().insert(X_REQUEST_ID, request_id(state).parse().unwrap());
+        response.headers_mut().insert(CONTENT_TYPE, PROBLEM_JSON.parse().unwrap());
+        *response.body_mut() = Body::from(serde_json::to_vec(self).unwrap());
+    }
+}
+
+fn mime() -> mime::Mime {
+    PROBLEM_JSON.parse().unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_as_problem_details() {
+        let mut errors = ValidationErrors::default();
+        errors.add("text", "No visible text");
+
+        let json = serde_json::to_value(Problem::invalid_fields(errors)).unwrap();
+
+        assert_eq!(serde_json::json!({
+            "type": "about:blank",
+            "title": "Bad Request",
+            "status": 400,
+            "code": "invalid-fields",
+            "detail": "Some fields are missing or invalid",
+            "errors": [{ "field": "text", "message": "No visible text" }]
+        }), json);
+    }
+
+    #[test]
+    fn omits_empty_field_list() {
+        let json = serde_json::to_value(Problem::comment_not_found()).unwrap();
+
+        assert_eq!(None, json.get("errors"));
+        assert_eq!(Some(&serde_json::json!(404)), json.get("status"));
+    }
+}
@@ -1,100 +1,439 @@
-use std::fs;
 use std::borrow::BorrowMut;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use glob::glob;
+use chrono::{DateTime, TimeZone, Utc};
 use gotham_derive::*;
+use serde_derive::Deserialize;
 use uuid::Uuid;
 
-use crate::comment::Comment;
-use crate::notifier::Notifier;
-use crate::utils;
+use crate::comment::{Comment, ModerationStatus};
+use crate::json_storage::JsonDirectoryStore;
+use crate::markdown::MarkdownOptions;
+use crate::notifier::{Event, Notifier};
+use crate::storage::CommentStore;
+use crate::subscriptions::{Subscription, Subscriptions};
 
 #[derive(Clone, StateData)]
 pub struct CommentRepository {
-    path: String,
+    store: Arc<dyn CommentStore>,
     comments: Arc<Mutex<Vec<Comment>>>,
-    notifier: Option<Notifier>,
+    path_counts: Arc<Mutex<HashMap<String, usize>>>,
+    idh_index: Arc<Mutex<IdhIndex>>,
+    notifiers: Vec<Arc<dyn Notifier>>,
+    subscriptions: Option<Subscriptions>,
+    moderated: bool,
+    modified: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
     should_reload: Arc<AtomicBool>,
 }
 
 
+/// Maps public ids, and the numeric ids used by earlier versions, to comment ids.
+#[derive(Default)]
+struct IdhIndex {
+    idhs: HashMap<String, Uuid>,
+    legacy_idhs: HashMap<u64, Uuid>,
+}
+
+impl IdhIndex {
+    fn insert(&mut self, comment: &Comment) {
+        self.idhs.insert(comment.idh.clone(), comment.id);
+        if let Some(legacy_idh) = comment.legacy_idh {
+            self.legacy_idhs.insert(legacy_idh, comment.id);
+        }
+    }
+
+    fn remove(&mut self, comment: &Comment) {
+        if self.idhs.get(&comment.idh) == Some(&comment.id) {
+            self.idhs.remove(&comment.idh);
+        }
+        if let Some(legacy_idh) = comment.legacy_idh {
+            if self.legacy_idhs.get(&legacy_idh) == Some(&comment.id) {
+                self.legacy_idhs.remove(&legacy_idh);
+            }
+        }
+    }
+
+    fn get(&self, idh: &str) -> Option<Uuid> {
+        self.idhs.get(idh).copied()
+            .or_else(|| idh.parse().ok().and_then(|legacy_idh: u64| self.legacy_idhs.get(&legacy_idh).copied()))
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Selects a page of threads. A thread is a top-level comment together with all its replies.
+/// Threads are ordered by the timestamp of their top-level comment, replies always follow
+/// the comment they reply to.
+#[derive(Default)]
+pub struct CommentQuery<'a> {
+    pub path: Option<&'a str>,
+    pub since: Option<DateTime<Utc>>,
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub cursor: Option<ThreadCursor>,
+}
+
+/// Marks the position after a thread. Unlike an offset it stays valid when comments are
+/// added or deleted.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadCursor {
+    timestamp: DateTime<Utc>,
+    idh: String,
+}
+
+impl ThreadCursor {
+    fn for_comment(comment: &Comment) -> Self {
+        ThreadCursor { timestamp: comment.timestamp, idh: comment.idh.clone() }
+    }
+}
+
+impl fmt::Display for ThreadCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.timestamp.timestamp_nanos(), self.idh)
+    }
+}
+
+impl FromStr for ThreadCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cursor: {}", s);
+        // the idh is base32 and contains no '-', but the nanoseconds may be negative
+        let (nanos, idh) = s.rsplit_once('-').ok_or_else(invalid)?;
+        let nanos: i64 = nanos.parse().map_err(|_| invalid())?;
+        if idh.is_empty() || !idh.chars().all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c)) {
+            return Err(invalid());
+        }
+        let timestamp = Utc.timestamp_nanos(nanos);
+        Ok(ThreadCursor { timestamp, idh: idh.to_owned() })
+    }
+}
+
+pub struct ThreadedComment {
+    pub comment: Comment,
+    pub parent_idh: Option<String>,
+    pub depth: usize,
+}
+
+pub struct CommentPage {
+    pub comments: Vec<ThreadedComment>,
+    pub next: Option<ThreadCursor>,
+}
+
+/// A comment whose HTML changed when it was rendered again.
+#[derive(Debug)]
+pub struct RerenderedComment {
+    /// The comment as it is stored now.
+    pub comment: Comment,
+    pub previous_html: String,
+}
+
+/// What happened to the stored comments when they were rendered again.
+#[derive(Debug, Default)]
+pub struct RerenderSummary {
+    pub changed: Vec<RerenderedComment>,
+    /// The number of comments whose HTML stayed the same but whose renderer fingerprint
+    /// was updated.
+    pub stamped: usize,
+    /// The number of comments whose HTML stayed the same but whose renderer fingerprint
+    /// is outdated and was left as it is.
+    pub outdated: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+}
+
+
 impl CommentRepository {
     pub fn new(path: &str, reset: bool) -> Self {
-        let repo = Self {
-            path: path.to_owned(),
+        Self::with_store(Arc::new(JsonDirectoryStore::new(path, reset)))
+    }
+
+    pub fn with_store(store: Arc<dyn CommentStore>) -> Self {
+        Self {
+            store,
             comments: Arc::new(Mutex::new(Vec::new())),
-            notifier: None,
+            path_counts: Arc::new(Mutex::new(HashMap::new())),
+            idh_index: Arc::new(Mutex::new(IdhIndex::default())),
+            notifiers: Vec::new(),
+            subscriptions: None,
+            moderated: false,
+            modified: Arc::new(Mutex::new(HashMap::new())),
             should_reload: Arc::new(AtomicBool::new(false)),
-        };
-        if reset {
-            repo.remove_storage_directory();
         }
-        repo.create_storage_directory();
-        repo
     }
 
     pub fn set_reload_flag(&mut self, flag: &Arc<AtomicBool>) {
-        self.should_reload = Arc::clone(&flag);
+        self.should_reload = Arc::clone(flag);
+    }
+
+    pub fn add_notifier(&mut self, notifier: Arc<dyn Notifier>) {
+        self.notifiers.push(notifier)
+    }
+
+    /// Enables subscriptions to new comments on a page. Without subscriptions, requests to
+    /// subscribe are ignored.
+    pub fn set_subscriptions(&mut self, subscriptions: Subscriptions) {
+        self.subscriptions = Some(subscriptions)
+    }
+
+    /// Records that the author of the comment wants to be notified of new comments on its
+    /// page. The subscription becomes active once the author confirms it with the link that
+    /// is mailed to them.
+    pub fn subscribe(&self, comment: &Comment) {
+        if let (Some(subscriptions), Some(email)) = (&self.subscriptions, &comment.author_email) {
+            if subscriptions.request(Subscription::new(&comment.path, email)) {
+                self.notify(Event::Subscribed, comment);
+            }
+        }
+    }
+
+    pub fn confirm_subscription(&self, subscription: &Subscription) -> bool {
+        self.subscriptions.as_ref().is_some_and(|s| s.confirm(subscription))
     }
 
-    pub fn set_notifier(&mut self, notifier: Notifier) {
-        self.notifier = Some(notifier)
+    pub fn unsubscribe(&self, subscription: &Subscription) -> bool {
+        self.subscriptions.as_ref().is_some_and(|s| s.unsubscribe(subscription))
+    }
+
+    fn notify(&self, event: Event, comment: &Comment) {
+        for notifier in &self.notifiers {
+            if let Err(message) = notifier.notify(event, comment) {
+                println!("{}", message);
+            }
+        }
+    }
+
+    /// When moderation is enabled, new comments are stored as pending and are hidden until
+    /// they are approved.
+    pub fn set_moderated(&mut self, moderated: bool) {
+        self.moderated = moderated
+    }
+
+    pub fn is_moderated(&self) -> bool {
+        self.moderated
+    }
+
+    /// Returns when the visible comments on the path, or on the whole site, last changed: the
+    /// newest timestamp or edit time of these comments, or the last time one of the comments
+    /// on the path was moderated or deleted since the server started, whichever is later.
+    pub fn last_modified(&self, path: Option<&str>) -> Option<DateTime<Utc>> {
+        self.reload_all_comments();
+        let newest = self.comments.lock().unwrap().iter()
+            .filter(|c| is_counted(c) && path.is_none_or(|p| c.path == p))
+            .map(|c| c.edited.unwrap_or(c.timestamp))
+            .max();
+        let modified = self.modified.lock().unwrap();
+        let modified = match path {
+            Some(p) => modified.get(p).copied(),
+            None => modified.values().max().copied(),
+        };
+        newest.max(modified)
+    }
+
+    /// Records a change to the comments on the path that their timestamps do not show.
+    fn touch(&self, path: &str) {
+        self.modified.lock().unwrap().insert(path.to_owned(), Utc::now());
     }
 
     pub fn all_comments(&self) -> Vec<Comment> {
         self.reload_all_comments();
         let mut guard = self.comments.lock().unwrap();
-        guard.borrow_mut().clone()
+        let list = guard.borrow_mut();
+        list.iter().filter(|c| c.status == ModerationStatus::Approved).cloned().collect()
+    }
+
+    pub fn pending_comments(&self) -> Vec<Comment> {
+        self.reload_all_comments();
+        let mut guard = self.comments.lock().unwrap();
+        let list = guard.borrow_mut();
+        let mut list: Vec<Comment> = list.iter().filter(|c| c.status == ModerationStatus::Pending).cloned().collect();
+        list.sort_unstable_by_key(|c| c.timestamp);
+        list
     }
 
     pub fn comment_with_id(&self, id: Uuid) -> Option<Comment> {
         self.reload_all_comments();
         let mut guard = self.comments.lock().unwrap();
         let list = guard.borrow_mut();
-        list.iter().filter(|c| c.id == id).map(|c| c.clone()).last() // TODO: improve
+        list.iter().filter(|c| c.id == id).cloned().next_back() // TODO: improve
     }
 
     pub fn comments_for_path(&self, path: &str) -> Vec<Comment> {
         self.reload_all_comments();
         let mut guard = self.comments.lock().unwrap();
         let list = guard.borrow_mut();
-        let mut list: Vec<Comment> = list.iter().filter(|c| c.path == path).map(|c| c.clone()).collect();
-        list.sort_unstable_by_key(|c| c.timestamp);
-        list
+        let list: Vec<&Comment> = list.iter()
+            .filter(|c| c.path == path && c.status == ModerationStatus::Approved)
+            .collect();
+        sort_into_threads(list).into_iter().map(|(c, _)| c.clone()).collect()
+    }
+
+    /// Returns a page of approved comments in thread order. Only the comments on the page
+    /// are copied. Replies whose parent is not on the page still carry the parent's idh.
+    pub fn query_comments(&self, query: &CommentQuery) -> CommentPage {
+        self.reload_all_comments();
+        let guard = self.comments.lock().unwrap();
+        let visible: Vec<&Comment> = guard.iter()
+            .filter(|c| c.status == ModerationStatus::Approved && query.path.is_none_or(|p| c.path == p))
+            .collect();
+        let idhs: HashMap<Uuid, &str> = visible.iter().map(|c| (c.id, c.idh.as_str())).collect();
+        let entries: Vec<(&Comment, usize)> = sort_into_threads(visible).into_iter()
+            .filter(|(c, _)| query.since.is_none_or(|since| c.timestamp > since))
+            .collect();
+
+        // comments whose parent was filtered out start a thread of their own, and the depth
+        // of their replies is counted from them
+        let mut threads: Vec<Vec<(&Comment, usize)>> = Vec::new();
+        let mut root_depths: Vec<usize> = Vec::new();
+        let mut thread_of: HashMap<Uuid, usize> = HashMap::new();
+        for (comment, depth) in entries {
+            let index = match comment.parent_id.and_then(|id| thread_of.get(&id)) {
+                Some(index) => *index,
+                None => {
+                    threads.push(Vec::new());
+                    root_depths.push(depth);
+                    threads.len() - 1
+                }
+            };
+            thread_of.insert(comment.id, index);
+            threads[index].push((comment, depth - root_depths[index]));
+        }
+        threads.sort_unstable_by_key(|t| ThreadCursor::for_comment(t[0].0));
+        if query.order == SortOrder::Desc {
+            threads.reverse();
+        }
+
+        let mut remaining = threads.into_iter()
+            .filter(|t| match (&query.cursor, query.order) {
+                (Some(cursor), SortOrder::Asc) => ThreadCursor::for_comment(t[0].0) > *cursor,
+                (Some(cursor), SortOrder::Desc) => ThreadCursor::for_comment(t[0].0) < *cursor,
+                (None, _) => true,
+            })
+            .peekable();
+        let mut comments = Vec::new();
+        let mut next = None;
+        let mut count = 0;
+        while let Some(thread) = remaining.next() {
+            for (comment, depth) in &thread {
+                let parent_idh = comment.parent_id.and_then(|id| idhs.get(&id).map(|idh| idh.to_string()));
+                comments.push(ThreadedComment { comment: (*comment).clone(), parent_idh, depth: *depth });
+            }
+            count += 1;
+            if query.limit == Some(count) {
+                if remaining.peek().is_some() {
+                    next = Some(ThreadCursor::for_comment(thread[0].0));
+                }
+                break;
+            }
+        }
+        CommentPage { comments, next }
+    }
+
+    pub fn depth_of(&self, comment: &Comment) -> usize {
+        let mut depth = 0;
+        let mut parent_id = comment.parent_id;
+        while let Some(parent) = parent_id.and_then(|id| self.comment_with_id(id)) {
+            depth += 1;
+            parent_id = parent.parent_id;
+        }
+        depth
+    }
+
+    pub fn comment_on_path_with_idh(&self, path: &str, idh: &str) -> Option<Comment> {
+        self.comments_for_path(path).into_iter().find(|c| c.idh == idh)
+    }
+
+    /// Finds a visible comment by its public id, or by the numeric idh that earlier versions
+    /// used in permalinks.
+    pub fn comment_with_idh(&self, idh: &str) -> Option<Comment> {
+        self.reload_all_comments();
+        let id = self.idh_index.lock().unwrap().get(idh)?;
+        self.comment_with_id(id).filter(|c| c.status == ModerationStatus::Approved)
+    }
+
+    /// Whether the comment's author had no approved comment before this one. Authors are
+    /// recognised by their email address; comments without an email address are always by
+    /// first-time authors.
+    pub fn is_first_time_author(&self, comment: &Comment) -> bool {
+        self.reload_all_comments();
+        let guard = self.comments.lock().unwrap();
+        is_first_time_author(guard.iter(), comment.author_email.as_deref(), comment.timestamp)
+    }
+
+    /// Whether a comment posted now with this email address is by a first-time author.
+    pub fn is_first_time_email(&self, email: Option<&str>) -> bool {
+        self.reload_all_comments();
+        let guard = self.comments.lock().unwrap();
+        is_first_time_author(guard.iter(), email, Utc::now())
+    }
+
+    /// Returns the number of comments that were rendered with other Markdown options or by
+    /// another version of Quvyn.
+    pub fn count_stale_renders(&self, options: &MarkdownOptions) -> usize {
+        self.reload_all_comments();
+        let fingerprint = options.fingerprint();
+        let guard = self.comments.lock().unwrap();
+        guard.iter().filter(|c| !c.deleted && c.renderer.as_deref() != Some(fingerprint.as_str())).count()
+    }
+
+    pub fn has_replies(&self, comment: &Comment) -> bool {
+        self.reload_all_comments();
+        let mut guard = self.comments.lock().unwrap();
+        let list = guard.borrow_mut();
+        list.iter().any(|c| c.parent_id == Some(comment.id))
+    }
+
+    /// Returns the number of visible comments for each of the paths.
+    pub fn count_comments(&self, paths: &[String]) -> HashMap<String, usize> {
+        self.reload_all_comments();
+        let counts = self.path_counts.lock().unwrap();
+        paths.iter().map(|p| (p.clone(), counts.get(p).copied().unwrap_or(0))).collect()
     }
 
     pub fn add_comment(&self, comment: &Comment) {
         let mut guard = self.comments.lock().unwrap();
         let list = guard.borrow_mut();
         list.push(comment.clone());
+        self.idh_index.lock().unwrap().insert(comment);
+        if is_counted(comment) {
+            *self.path_counts.lock().unwrap().entry(comment.path.clone()).or_insert(0) += 1;
+        }
     }
 
     pub fn remove_comment(&self, comment: &Comment) -> bool {
         let mut guard = self.comments.lock().unwrap();
         let list = guard.borrow_mut();
-        list.iter().position(|c| c.id == comment.id).map(|c| list.remove(c)).is_some()
+        match list.iter().position(|c| c.id == comment.id).map(|c| list.remove(c)) {
+            Some(removed) => {
+                self.idh_index.lock().unwrap().remove(&removed);
+                if is_counted(&removed) {
+                    if let Some(count) = self.path_counts.lock().unwrap().get_mut(&removed.path) {
+                        *count -= 1;
+                    }
+                }
+                true
+            }
+            None => false
+        }
     }
 
     fn remove_all_comments(&self) {
         let mut guard = self.comments.lock().unwrap();
         let list = guard.borrow_mut();
         list.clear();
-    }
-
-    fn create_storage_directory(&self) {
-        fs::create_dir_all(&self.path).expect(&format!("Failed to create directory at {}", &self.path));
-    }
-
-    fn remove_storage_directory(&self) {
-        if !fs::metadata(&self.path).is_ok() {
-            return;
-        }
-        fs::remove_dir_all(&self.path).expect(&format!("Failed to remove directory at {}", &self.path));
+        self.path_counts.lock().unwrap().clear();
+        *self.idh_index.lock().unwrap() = IdhIndex::default();
     }
 
     fn reload_all_comments(&self) {
@@ -106,39 +445,144 @@ impl CommentRepository {
     }
 
     pub fn load_all_comments(&self) {
-        for entry in glob(&format!("{}/*.json", self.path)).unwrap() {
-            match entry {
-                Ok(path) => self.load_comment(&path),
-                Err(_) => {} // TODO
+        for mut comment in self.store.load_all() {
+            comment.migrate_idh();
+            self.add_comment(&comment);
+        }
+    }
+
+    /// Rewrites stored comments that still have an idh created by earlier versions. Returns
+    /// the number of comments that were migrated.
+    pub fn migrate_idhs(&self) -> usize {
+        let mut count = 0;
+        for mut comment in self.store.load_all() {
+            if comment.migrate_idh() {
+                self.store.save(&comment);
+                count += 1;
             }
         }
+        count
     }
 
-    fn load_comment(&self, path: &Path) {
-        println!("Loading comment from file: {}", path.display());
-        let mut file = File::open(path).expect(&format!("Failed to open file {}", path.display()));
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).expect(&format!("Failed to read file {}", path.display()));
-        let comment = utils::from_json(&contents);
-        self.add_comment(&comment);
+    /// Renders the text of all stored comments again with the options, and rewrites the
+    /// comments whose HTML changed. With `restamp` the comments whose HTML stayed the same
+    /// but whose renderer fingerprint is outdated are rewritten as well.
+    pub fn rerender(&self, options: &MarkdownOptions, restamp: bool) -> RerenderSummary {
+        let all = self.store.load_all();
+        let mut summary = RerenderSummary::default();
+        for comment in &all {
+            if comment.deleted {
+                summary.deleted += 1;
+                continue;
+            }
+            let mut rendered = comment.clone();
+            rendered.render(options, is_first_time_author(all.iter(), comment.author_email.as_deref(), comment.timestamp));
+            if rendered.text_html != comment.text_html {
+                summary.changed.push(RerenderedComment { comment: rendered.clone(), previous_html: comment.text_html.clone() });
+            } else if rendered.renderer != comment.renderer && restamp {
+                summary.stamped += 1;
+            } else if rendered.renderer != comment.renderer {
+                summary.outdated += 1;
+                continue;
+            } else {
+                summary.unchanged += 1;
+                continue;
+            }
+            self.store.save(&rendered);
+        }
+        summary
     }
 
     pub fn save_comment(&self, comment: &Comment) {
-        let filename = format!("{}/{}.json", self.path, comment.id.as_simple());
-        println!("Saving comment to file: {}", filename);
-        let mut file = File::create(&filename).expect(&format!("Failed to create file {}", &filename));
-        let _result = file.write_all(utils::to_json(comment).as_ref());
+        self.store.save(comment);
         self.add_comment(comment); // TODO: there is no test to check that this happens after saving
-        if let Some(notifier) = &self.notifier {
-            notifier.notify(comment)
+        self.notify(Event::Posted, comment);
+    }
+
+    pub fn set_status(&self, comment: &Comment, status: ModerationStatus) -> Comment {
+        let mut updated = comment.clone();
+        updated.status = status;
+        self.update_comment(&updated);
+        if status == ModerationStatus::Approved && comment.status != ModerationStatus::Approved {
+            self.notify(Event::Approved, &updated);
         }
+        updated
     }
 
+    /// Deletes a comment. If the comment has replies it is turned into a tombstone instead, so
+    /// that the thread stays readable. Tombstones are removed once their last reply is deleted.
     pub fn delete_comment(&self, comment: &Comment) {
-        let filename = format!("{}/{}.json", self.path, comment.id.as_simple());
-        println!("Deleting comment in file: {}", filename);
-        std::fs::remove_file(&filename).expect(&format!("Failed to delete comment in file {}", &filename));
+        if !comment.deleted {
+            self.notify(Event::Deleted, comment);
+        }
+        if self.has_replies(comment) {
+            let mut tombstone = comment.clone();
+            tombstone.mark_deleted();
+            self.update_comment(&tombstone);
+            return;
+        }
+        self.store.delete(comment);
         self.remove_comment(comment);
+        self.touch(&comment.path);
+        if let Some(parent) = comment.parent_id.and_then(|id| self.comment_with_id(id)) {
+            if parent.deleted {
+                self.delete_comment(&parent);
+            }
+        }
+    }
+
+    pub fn update_comment(&self, comment: &Comment) {
+        self.store.save(comment);
+        self.remove_comment(comment);
+        self.add_comment(comment);
+        self.touch(&comment.path);
+    }
+}
+
+
+fn is_first_time_author<'a>(comments: impl Iterator<Item=&'a Comment>, email: Option<&str>, before: DateTime<Utc>) -> bool {
+    let email = match email {
+        Some(e) if !e.trim().is_empty() => e.trim().to_lowercase(),
+        _ => return true,
+    };
+    let mut comments = comments;
+    !comments.any(|c| c.timestamp < before && c.status == ModerationStatus::Approved
+        && !c.deleted && c.author_email.as_deref().is_some_and(|e| e.trim().to_lowercase() == email))
+}
+
+fn is_counted(comment: &Comment) -> bool {
+    comment.status == ModerationStatus::Approved && !comment.deleted
+}
+
+
+/// Orders comments so that each comment is followed by its replies and adds the depth of
+/// each comment in its thread. Top-level comments and the replies to any one comment are
+/// ordered by timestamp. Comments whose parent is not in the list are treated as top-level
+/// comments.
+fn sort_into_threads(mut list: Vec<&Comment>) -> Vec<(&Comment, usize)> {
+    list.sort_unstable_by(|a, b| (a.timestamp, &a.idh).cmp(&(b.timestamp, &b.idh)));
+    let ids: HashSet<Uuid> = list.iter().map(|c| c.id).collect();
+    let mut roots = Vec::new();
+    let mut replies: HashMap<Uuid, Vec<&Comment>> = HashMap::new();
+    for comment in list {
+        match comment.parent_id.filter(|id| ids.contains(id)) {
+            Some(parent_id) => replies.entry(parent_id).or_default().push(comment),
+            None => roots.push(comment),
+        }
+    }
+    let mut result = Vec::with_capacity(ids.len());
+    for comment in roots {
+        append_with_replies(comment, 0, &mut replies, &mut result);
+    }
+    result
+}
+
+fn append_with_replies<'a>(comment: &'a Comment, depth: usize, replies: &mut HashMap<Uuid, Vec<&'a Comment>>,
+                           result: &mut Vec<(&'a Comment, usize)>) {
+    let children = replies.remove(&comment.id).unwrap_or_default();
+    result.push((comment, depth));
+    for child in children {
+        append_with_replies(child, depth + 1, replies, result);
     }
 }
 
@@ -149,14 +593,33 @@ mod tests {
 
     use super::*;
 
+    struct NullStore;
+
+    impl CommentStore for NullStore {
+        fn load_all(&self) -> Vec<Comment> { Vec::new() }
+        fn save(&self, _comment: &Comment) {}
+        fn delete(&self, _comment: &Comment) {}
+    }
+
     impl CommentRepository {
         fn for_testing() -> CommentRepository {
-            CommentRepository {
-                path: "/r".to_owned(),
-                comments: Arc::new(Mutex::new(Vec::new())),
-                notifier: None,
-                should_reload: Arc::new(AtomicBool::new(false)),
-            }
+            CommentRepository::with_store(Arc::new(NullStore))
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingNotifier {
+        events: Mutex<Vec<(Event, String)>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn name(&self) -> String {
+            "recording".to_owned()
+        }
+
+        fn notify(&self, event: Event, comment: &Comment) -> Result<(), String> {
+            self.events.lock().unwrap().push((event, comment.text.clone()));
+            Ok(())
         }
     }
 
@@ -182,7 +645,59 @@ mod tests {
 
         assert_eq!(1, repository.all_comments().len());
         let found = repository.comment_with_id(comment1.id).is_some();
-        assert_eq!(false, found);
+        assert!(!found);
+    }
+
+    #[test]
+    fn last_modified_is_newest_comment_on_path() {
+        let repository = CommentRepository::for_testing();
+        let mut c1 = Comment::new("/a/", "first", None, None);
+        c1.timestamp = c1.timestamp - Duration::hours(2);
+        c1.edited = Some(c1.timestamp + Duration::hours(1));
+        let mut c2 = Comment::new("/a/", "second", None, None);
+        c2.timestamp = c2.timestamp - Duration::hours(3);
+        let c3 = Comment::new("/b/", "third", None, None);
+        for c in [&c1, &c2, &c3] {
+            repository.add_comment(c);
+        }
+
+        assert_eq!(c1.edited, repository.last_modified(Some("/a/")));
+        assert_eq!(Some(c3.timestamp), repository.last_modified(None));
+        assert_eq!(None, repository.last_modified(Some("/c/")));
+    }
+
+    #[test]
+    fn last_modified_advances_on_moderation_and_delete() {
+        let repository = CommentRepository::for_testing();
+        let comment = Comment::new("/test-topic/", "Test", None, None);
+        repository.add_comment(&comment);
+        let added = repository.last_modified(Some("/test-topic/"));
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let rejected = repository.set_status(&comment, ModerationStatus::Rejected);
+        let moderated = repository.last_modified(Some("/test-topic/"));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        repository.delete_comment(&rejected);
+
+        assert!(moderated > added);
+        assert!(repository.last_modified(Some("/test-topic/")) > moderated);
+        assert_eq!(None, repository.last_modified(Some("/other-topic/")));
+    }
+
+    #[test]
+    fn comment_can_be_retrieved_by_idh_until_removed() {
+        let repository = CommentRepository::for_testing();
+        let mut comment = Comment::new("/test-topic/", "Test", None, None);
+        comment.legacy_idh = Some(42);
+        repository.add_comment(&comment);
+
+        assert_eq!(Some(comment.id), repository.comment_with_idh(&comment.idh).map(|c| c.id));
+        assert_eq!(Some(comment.id), repository.comment_with_idh("42").map(|c| c.id));
+
+        repository.remove_comment(&comment);
+
+        assert!(repository.comment_with_idh(&comment.idh).is_none());
+        assert!(repository.comment_with_idh("42").is_none());
     }
 
     #[test]
@@ -229,4 +744,277 @@ mod tests {
         assert_eq!(list[1].text, "Second comment");
         assert_eq!(list[2].text, "Third comment");
     }
+
+    #[test]
+    fn replies_follow_their_parent_in_timestamp_order() {
+        let repository = CommentRepository::for_testing();
+        let mut c1 = Comment::new("/test-topic/", "First comment", None, None);
+        c1.timestamp = c1.timestamp - Duration::hours(2);
+        repository.add_comment(&c1);
+        let mut c2 = Comment::new("/test-topic/", "Second comment", None, None);
+        c2.timestamp = c2.timestamp - Duration::hours(1);
+        repository.add_comment(&c2);
+        let mut r2 = Comment::new("/test-topic/", "Second reply to first", None, None);
+        r2.parent_id = Some(c1.id);
+        repository.add_comment(&r2);
+        let mut r1 = Comment::new("/test-topic/", "First reply to first", None, None);
+        r1.parent_id = Some(c1.id);
+        r1.timestamp = r1.timestamp - Duration::minutes(30);
+        repository.add_comment(&r1);
+        let mut r11 = Comment::new("/test-topic/", "Reply to first reply", None, None);
+        r11.parent_id = Some(r1.id);
+        repository.add_comment(&r11);
+
+        let list = repository.comments_for_path("/test-topic/");
+
+        let texts: Vec<&str> = list.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(vec!["First comment", "First reply to first", "Reply to first reply",
+                        "Second reply to first", "Second comment"], texts);
+    }
+
+    fn texts(page: &CommentPage) -> Vec<&str> {
+        page.comments.iter().map(|t| t.comment.text.as_str()).collect()
+    }
+
+    fn add_thread(repository: &CommentRepository, text: &str, minutes_ago: i64) -> Comment {
+        let mut comment = Comment::new("/test-topic/", text, None, None);
+        comment.timestamp = comment.timestamp - Duration::minutes(minutes_ago);
+        repository.add_comment(&comment);
+        let mut reply = Comment::new("/test-topic/", &format!("Reply to {}", text), None, None);
+        reply.parent_id = Some(comment.id);
+        reply.timestamp = comment.timestamp + Duration::seconds(1);
+        repository.add_comment(&reply);
+        comment
+    }
+
+    #[test]
+    fn query_adds_parent_and_depth() {
+        let repository = CommentRepository::for_testing();
+        let c1 = add_thread(&repository, "First", 10);
+
+        let page = repository.query_comments(&CommentQuery::default());
+
+        assert_eq!((None, 0), (page.comments[0].parent_idh.clone(), page.comments[0].depth));
+        assert_eq!((Some(c1.idh.clone()), 1), (page.comments[1].parent_idh.clone(), page.comments[1].depth));
+        assert_eq!(None, page.next);
+    }
+
+    #[test]
+    fn query_pages_through_threads() {
+        let repository = CommentRepository::for_testing();
+        add_thread(&repository, "First", 30);
+        add_thread(&repository, "Second", 20);
+        add_thread(&repository, "Third", 10);
+
+        let page1 = repository.query_comments(&CommentQuery { limit: Some(2), ..Default::default() });
+        assert_eq!(vec!["First", "Reply to First", "Second", "Reply to Second"], texts(&page1));
+
+        let page2 = repository.query_comments(&CommentQuery { limit: Some(2), cursor: page1.next, ..Default::default() });
+        assert_eq!(vec!["Third", "Reply to Third"], texts(&page2));
+        assert_eq!(None, page2.next);
+    }
+
+    #[test]
+    fn query_pages_through_threads_in_descending_order() {
+        let repository = CommentRepository::for_testing();
+        add_thread(&repository, "First", 30);
+        add_thread(&repository, "Second", 20);
+        add_thread(&repository, "Third", 10);
+
+        let query = CommentQuery { order: SortOrder::Desc, limit: Some(2), ..Default::default() };
+        let page1 = repository.query_comments(&query);
+        assert_eq!(vec!["Third", "Reply to Third", "Second", "Reply to Second"], texts(&page1));
+
+        let query = CommentQuery { order: SortOrder::Desc, limit: Some(2), cursor: page1.next, ..Default::default() };
+        let page2 = repository.query_comments(&query);
+        assert_eq!(vec!["First", "Reply to First"], texts(&page2));
+    }
+
+    #[test]
+    fn query_returns_comments_since_timestamp() {
+        let repository = CommentRepository::for_testing();
+        let c1 = add_thread(&repository, "First", 30);
+        add_thread(&repository, "Second", 10);
+
+        let query = CommentQuery { since: Some(c1.timestamp), ..Default::default() };
+        let page = repository.query_comments(&query);
+
+        assert_eq!(vec!["Reply to First", "Second", "Reply to Second"], texts(&page));
+        assert_eq!(Some(c1.idh.clone()), page.comments[0].parent_idh);
+        assert_eq!(vec![0, 0, 1], page.comments.iter().map(|c| c.depth).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn query_filters_by_path() {
+        let repository = CommentRepository::for_testing();
+        add_thread(&repository, "First", 30);
+        repository.add_comment(&Comment::new("/something-else/", "Other", None, None));
+
+        let page = repository.query_comments(&CommentQuery { path: Some("/something-else/"), ..Default::default() });
+
+        assert_eq!(vec!["Other"], texts(&page));
+    }
+
+    #[test]
+    fn counts_visible_comments_per_path() {
+        let repository = CommentRepository::for_testing();
+        let c1 = Comment::new("/a/", "First", None, None);
+        repository.add_comment(&c1);
+        repository.add_comment(&Comment::new("/a/", "Second", None, None));
+        let mut pending = Comment::new("/a/", "Pending", None, None);
+        pending.status = ModerationStatus::Pending;
+        repository.add_comment(&pending);
+        let mut tombstone = Comment::new("/b/", "Deleted", None, None);
+        tombstone.mark_deleted();
+        repository.add_comment(&tombstone);
+
+        let paths = vec!["/a/".to_owned(), "/b/".to_owned(), "/c/".to_owned()];
+        let counts = repository.count_comments(&paths);
+        assert_eq!((2, 0, 0), (counts["/a/"], counts["/b/"], counts["/c/"]));
+
+        repository.remove_comment(&c1);
+        repository.set_status(&pending, ModerationStatus::Approved);
+        assert_eq!(2, repository.count_comments(&paths)["/a/"]);
+    }
+
+    #[test]
+    fn recognises_first_time_authors_by_approved_comments() {
+        let repository = CommentRepository::for_testing();
+        let mut pending = Comment::new("/a/", "Pending", None, Some("jane@example.org"));
+        pending.status = ModerationStatus::Pending;
+        repository.add_comment(&pending);
+        let anonymous = Comment::new("/a/", "Anonymous", None, None);
+        repository.add_comment(&anonymous);
+
+        let new = Comment::new("/b/", "New", None, Some("Jane@Example.org"));
+        assert!(repository.is_first_time_author(&new));
+        assert!(repository.is_first_time_author(&anonymous));
+
+        let mut approved = Comment::new("/a/", "Approved", None, Some("jane@example.org"));
+        approved.timestamp = new.timestamp - chrono::Duration::minutes(1);
+        repository.add_comment(&approved);
+        assert!(!repository.is_first_time_author(&new));
+
+        let mut earlier = new.clone();
+        earlier.timestamp = approved.timestamp - chrono::Duration::minutes(1);
+        assert!(repository.is_first_time_author(&earlier));
+        assert!(!repository.is_first_time_email(Some(" jane@example.org")));
+        assert!(repository.is_first_time_email(Some("joe@example.org")));
+        assert!(repository.is_first_time_email(None));
+    }
+
+    #[test]
+    fn notifies_all_notifiers_of_changes() {
+        let mut repository = CommentRepository::for_testing();
+        let (n1, n2) = (Arc::new(RecordingNotifier::default()), Arc::new(RecordingNotifier::default()));
+        repository.add_notifier(n1.clone());
+        repository.add_notifier(n2.clone());
+        let mut parent = Comment::new("/a/", "Parent", None, None);
+        parent.status = ModerationStatus::Pending;
+        let mut reply = Comment::new("/a/", "Reply", None, None);
+        reply.parent_id = Some(parent.id);
+
+        repository.save_comment(&parent);
+        let parent = repository.set_status(&parent, ModerationStatus::Approved);
+        repository.save_comment(&reply);
+        repository.delete_comment(&parent);
+        repository.delete_comment(&reply);
+
+        let expected = vec![
+            (Event::Posted, "Parent".to_owned()),
+            (Event::Approved, "Parent".to_owned()),
+            (Event::Posted, "Reply".to_owned()),
+            (Event::Deleted, "Parent".to_owned()),
+            (Event::Deleted, "Reply".to_owned()),
+        ];
+        assert_eq!(expected, *n1.events.lock().unwrap());
+        assert_eq!(expected, *n2.events.lock().unwrap());
+    }
+
+    #[test]
+    fn cursor_can_be_parsed_from_string() {
+        let comment = Comment::new("/test-topic/", "Test", None, None);
+        let cursor = ThreadCursor::for_comment(&comment);
+
+        assert_eq!(Ok(cursor.clone()), cursor.to_string().parse());
+        assert!("foo".parse::<ThreadCursor>().is_err());
+        assert!("1-".parse::<ThreadCursor>().is_err());
+        assert!("1-FOO".parse::<ThreadCursor>().is_err());
+    }
+
+    #[test]
+    fn cursor_before_1970_can_be_parsed_from_string() {
+        let mut comment = Comment::new("/test-topic/", "Test", None, None);
+        comment.timestamp = Utc.timestamp(-14182940, 0);
+        let cursor = ThreadCursor::for_comment(&comment);
+
+        assert!(cursor.to_string().starts_with('-'));
+        assert_eq!(Ok(cursor.clone()), cursor.to_string().parse());
+        assert!("-1".parse::<ThreadCursor>().is_err());
+        assert!("1-".parse::<ThreadCursor>().is_err());
+        assert!("1-FOO".parse::<ThreadCursor>().is_err());
+    }
+
+    #[test]
+    fn depth_counts_ancestors() {
+        let repository = CommentRepository::for_testing();
+        let c1 = add_thread(&repository, "First", 30);
+        let mut reply = Comment::new("/test-topic/", "Reply", None, None);
+        reply.parent_id = Some(c1.id);
+
+        assert_eq!(0, repository.depth_of(&c1));
+        assert_eq!(1, repository.depth_of(&reply));
+    }
+
+    #[test]
+    fn replies_with_unknown_parent_are_treated_as_top_level() {
+        let repository = CommentRepository::for_testing();
+        let mut reply = Comment::new("/test-topic/", "Reply", None, None);
+        reply.parent_id = Some(Uuid::new_v4());
+        repository.add_comment(&reply);
+
+        let list = repository.comments_for_path("/test-topic/");
+
+        assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn only_approved_comments_are_listed() {
+        let repository = CommentRepository::for_testing();
+        let mut pending = Comment::new("/test-topic/", "Pending", None, None);
+        pending.status = ModerationStatus::Pending;
+        repository.add_comment(&pending);
+        let mut rejected = Comment::new("/test-topic/", "Rejected", None, None);
+        rejected.status = ModerationStatus::Rejected;
+        repository.add_comment(&rejected);
+        repository.add_comment(&Comment::new("/test-topic/", "Approved", None, None));
+
+        assert_eq!(1, repository.all_comments().len());
+        assert_eq!(1, repository.comments_for_path("/test-topic/").len());
+        assert_eq!("Pending", repository.pending_comments()[0].text);
+        assert!(repository.comment_with_id(pending.id).is_some());
+    }
+
+    #[test]
+    fn approving_comment_makes_it_available_in_list() {
+        let repository = CommentRepository::for_testing();
+        let mut comment = Comment::new("/test-topic/", "Pending", None, None);
+        comment.status = ModerationStatus::Pending;
+        repository.add_comment(&comment);
+
+        repository.set_status(&comment, ModerationStatus::Approved);
+
+        assert_eq!(1, repository.comments_for_path("/test-topic/").len());
+        assert_eq!(0, repository.pending_comments().len());
+    }
+
+    #[test]
+    fn finds_parent_only_on_same_path() {
+        let repository = CommentRepository::for_testing();
+        let comment = Comment::new("/test-topic/", "Comment", None, None);
+        repository.add_comment(&comment);
+
+        assert!(repository.comment_on_path_with_idh("/test-topic/", &comment.idh).is_some());
+        assert!(repository.comment_on_path_with_idh("/something-else/", &comment.idh).is_none());
+    }
 }
@@ -0,0 +1,204 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use ammonia::Builder;
+use regex::Regex;
+use serde_derive::Deserialize;
+use url::Url;
+
+/// Tags that can run scripts, load other documents or collect input. They cannot be allowed,
+/// whatever the configuration says.
+const FORBIDDEN_TAGS: [&str; 20] = [
+    "script", "style", "iframe", "frame", "frameset", "object", "embed", "applet", "form", "input", "button",
+    "textarea", "select", "option", "link", "meta", "base", "svg", "math", "template",
+];
+
+/// URL schemes that can run scripts or embed arbitrary content.
+const FORBIDDEN_URL_SCHEMES: [&str; 3] = ["javascript", "vbscript", "data"];
+
+
+/// Changes to the list of HTML tags and attributes that are allowed in comments. Every field
+/// that is set replaces the corresponding default of the sanitizer.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SanitizerConfig
+{
+    pub tags: Option<Vec<String>>,
+    /// The allowed attributes per tag. Attributes listed for `*` are allowed on all tags.
+    pub attributes: Option<BTreeMap<String, Vec<String>>>,
+    pub url_schemes: Option<Vec<String>>,
+    /// If set, images are only shown if they are loaded from one of these hosts.
+    pub image_hosts: Option<Vec<String>>,
+}
+
+impl SanitizerConfig
+{
+    /// Checks that the configuration can be used by the sanitizer and does not allow markup
+    /// that is unsafe in comments.
+    pub fn validate(&self) -> Result<(), String> {
+        let name_regex = Regex::new(r"^[a-z][a-z0-9-]*$").unwrap();
+        if let Some(tags) = &self.tags {
+            for tag in tags {
+                if !name_regex.is_match(tag) {
+                    return Err(format!("Invalid tag name \"{}\"; tag names must be lowercase", tag));
+                }
+                if FORBIDDEN_TAGS.contains(&tag.as_str()) {
+                    return Err(format!("The tag \"{}\" cannot be allowed in comments", tag));
+                }
+            }
+        }
+        if let Some(attributes) = &self.attributes {
+            for (tag, names) in attributes {
+                if tag != "*" && !self.allows_tag(tag) {
+                    return Err(format!("Attributes are given for the tag \"{}\", which is not allowed", tag));
+                }
+                for name in names {
+                    if !name_regex.is_match(name) {
+                        return Err(format!("Invalid attribute name \"{}\"; attribute names must be lowercase", name));
+                    }
+                    if name.starts_with("on") || name == "style" {
+                        return Err(format!("The attribute \"{}\" cannot be allowed in comments", name));
+                    }
+                    if name == "rel" && (tag == "*" || tag == "a") {
+                        return Err("The attribute \"rel\" cannot be allowed on links; Quvyn sets it".to_owned());
+                    }
+                    if name == "target" && (tag == "*" || tag == "a") {
+                        return Err("The attribute \"target\" cannot be allowed on links; use --links-new-window instead".to_owned());
+                    }
+                }
+            }
+        }
+        if let Some(schemes) = &self.url_schemes {
+            let scheme_regex = Regex::new(r"^[a-z][a-z0-9+.-]*$").unwrap();
+            for scheme in schemes {
+                if !scheme_regex.is_match(scheme) {
+                    return Err(format!("Invalid URL scheme \"{}\"; schemes must be lowercase and without \":\"", scheme));
+                }
+                if FORBIDDEN_URL_SCHEMES.contains(&scheme.as_str()) {
+                    return Err(format!("The URL scheme \"{}\" cannot be allowed in comments", scheme));
+                }
+            }
+        }
+        if let Some(hosts) = &self.image_hosts {
+            if !self.allows_tag("img") {
+                return Err("Image hosts are given but the tag \"img\" is not allowed".to_owned());
+            }
+            if let Some(host) = hosts.iter().find(|h| h.is_empty() || h.contains(['/', ':', '@'])) {
+                return Err(format!("Invalid image host \"{}\"; give host names only, eg. images.example.org", host));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the attribute is allowed on the tag, either for the tag or for all tags.
+    pub fn allows_attribute(&self, tag: &str, attribute: &str) -> bool {
+        match &self.attributes {
+            Some(attributes) => [tag, "*"].iter()
+                .any(|t| attributes.get(*t).is_some_and(|names| names.iter().any(|n| n == attribute))),
+            None => {
+                let defaults = Builder::default();
+                defaults.clone_generic_attributes().contains(attribute)
+                    || defaults.clone_tag_attributes().get(tag).is_some_and(|names| names.contains(attribute))
+            }
+        }
+    }
+
+    fn allows_tag(&self, tag: &str) -> bool {
+        match &self.tags {
+            Some(tags) => tags.iter().any(|t| t == tag),
+            None => Builder::default().clone_tags().contains(tag),
+        }
+    }
+
+    /// Applies the configuration to the sanitizer. The configuration must be valid.
+    pub fn apply<'a>(&'a self, builder: &mut Builder<'a>) {
+        if let Some(tags) = &self.tags {
+            builder.tags(tags.iter().map(String::as_str).collect());
+        }
+        if let Some(attributes) = &self.attributes {
+            let mut tag_attributes: HashMap<&str, HashSet<&str>> = HashMap::new();
+            for (tag, names) in attributes {
+                let names = names.iter().map(String::as_str).collect();
+                if tag == "*" {
+                    builder.generic_attributes(names);
+                } else {
+                    tag_attributes.insert(tag, names);
+                }
+            }
+            builder.tag_attributes(tag_attributes);
+        }
+        if let Some(schemes) = &self.url_schemes {
+            builder.url_schemes(schemes.iter().map(String::as_str).collect());
+        }
+        if let Some(hosts) = &self.image_hosts {
+            let hosts = hosts.clone();
+            builder.attribute_filter(move |tag, attribute, value| {
+                if tag == "img" && attribute == "src" && !is_on_host(value, &hosts) {
+                    None
+                } else {
+                    Some(Cow::Borrowed(value))
+                }
+            });
+        }
+    }
+}
+
+fn is_on_host(url: &str, hosts: &[String]) -> bool {
+    match Url::parse(url) {
+        Ok(url) => url.host_str().is_some_and(|h| hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(h))),
+        Err(_) => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> SanitizerConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn clean(config: &SanitizerConfig, html: &str) -> String {
+        let mut builder = Builder::default();
+        config.apply(&mut builder);
+        builder.clean(html).to_string()
+    }
+
+    #[test]
+    fn replaces_allowed_tags_and_attributes() {
+        let config = config(r#"{ "tags": ["p", "a"], "attributes": { "a": ["href"], "*": ["title"] } }"#);
+        assert_eq!(Ok(()), config.validate());
+
+        let html = clean(&config, r#"<p title="t"><a href="/x" hreflang="en">x</a></p><table><tr><td>y</td></tr></table>"#);
+
+        assert_eq!(r#"<p title="t"><a href="/x" rel="noopener noreferrer">x</a></p>y"#, html);
+    }
+
+    #[test]
+    fn shows_images_from_allowed_hosts_only() {
+        let config = config(r#"{ "imageHosts": ["images.example.org"] }"#);
+        assert_eq!(Ok(()), config.validate());
+
+        let html = clean(&config, r#"<img src="https://images.example.org/a.png"><img src="https://example.com/b.png"><img src="/c.png">"#);
+
+        assert_eq!(r#"<img src="https://images.example.org/a.png"><img><img>"#, html);
+    }
+
+    #[test]
+    fn rejects_unsafe_configuration() {
+        let errors = [
+            (r#"{ "tags": ["p", "script"] }"#, "The tag \"script\" cannot be allowed in comments"),
+            (r#"{ "tags": ["P"] }"#, "Invalid tag name \"P\"; tag names must be lowercase"),
+            (r#"{ "attributes": { "p": ["onclick"] } }"#, "The attribute \"onclick\" cannot be allowed in comments"),
+            (r#"{ "attributes": { "a": ["rel"] } }"#, "The attribute \"rel\" cannot be allowed on links; Quvyn sets it"),
+            (r#"{ "tags": ["p"], "attributes": { "a": ["href"] } }"#, "Attributes are given for the tag \"a\", which is not allowed"),
+            (r#"{ "urlSchemes": ["https", "javascript"] }"#, "The URL scheme \"javascript\" cannot be allowed in comments"),
+            (r#"{ "tags": ["p"], "imageHosts": ["example.org"] }"#, "Image hosts are given but the tag \"img\" is not allowed"),
+            (r#"{ "imageHosts": ["https://example.org"] }"#, "Invalid image host \"https://example.org\"; give host names only, eg. images.example.org"),
+        ];
+        for (json, message) in errors {
+            assert_eq!(Err(message.to_owned()), config(json).validate());
+        }
+    }
+}
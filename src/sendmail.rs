@@ -6,22 +6,20 @@ use std::io::BufReader;
 // Part of this file copyright (c) 2015 Alexander
 // https://github.com/vokeio/rust-sendmail
 
-pub fn send(to_address: &str, subject_text: &str, body_text: &str) -> Result<(),Error>
+/// Sends a formatted message. Sendmail takes the recipients from the message's headers.
+pub fn send(message: &str) -> Result<(),Error>
 {
     let mut cmd = Command::new("sendmail");
-    cmd.args(&[&"-t"])
+    cmd.args(["-t", "-i"])
         .stdin(Stdio::piped())
         .stdout(Stdio::null())
         .stderr(Stdio::piped());
     let mut process = cmd.spawn()?;
 
     { // required because of process.wait()
-        let mut stdin = process.stdin.as_mut().ok_or(Error::new(ErrorKind::BrokenPipe, "no stdin"))?;
+        let stdin = process.stdin.as_mut().ok_or(Error::new(ErrorKind::BrokenPipe, "no stdin"))?;
 
-        writeln!(&mut stdin,"To: {}", to_address)?;
-        writeln!(&mut stdin,"Subject: {}",subject_text)?;
-        writeln!(&mut stdin,"" )?;
-        stdin.write_all(body_text.as_bytes())?;
+        stdin.write_all(message.as_bytes())?;
         stdin.flush()?;
     }
 
@@ -34,6 +32,6 @@ pub fn send(to_address: &str, subject_text: &str, body_text: &str) -> Result<(),
     if stderr.is_empty() {
         Ok(())
     } else {
-        Err(Error::new(ErrorKind::Other, format!("sendmail returned: {}",stderr)))
+        Err(Error::other(format!("sendmail returned: {}",stderr)))
     }
 }
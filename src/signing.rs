@@ -0,0 +1,81 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::utils::constant_time_eq;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Creates and verifies HMAC-SHA256 signatures with a server secret. Signatures are hex
+/// encoded so that they can be used in URLs and headers without further encoding.
+#[derive(Clone)]
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(secret: &str) -> Self {
+        Signer {
+            key: secret.as_bytes().to_vec()
+        }
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn verify(&self, message: &str, signature: &str) -> bool {
+        constant_time_eq(self.sign(message).as_bytes(), signature.as_bytes())
+    }
+
+    /// Creates a token that contains the message as well as its signature. Tokens only use
+    /// characters that are safe in URLs.
+    pub fn token_for(&self, message: &str) -> String {
+        format!("{}.{}", BASE64_URL.encode(message), self.sign(message))
+    }
+
+    /// Returns the message in a token, provided the token was created with the same secret.
+    pub fn message_in(&self, token: &str) -> Option<String> {
+        let (encoded, signature) = token.split_once('.')?;
+        let message = String::from_utf8(BASE64_URL.decode(encoded).ok()?).ok()?;
+        if self.verify(&message, signature) { Some(message) } else { None }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_hex_encoded_hmac_sha256() {
+        // test vector from RFC 4231, test case 2
+        let signer = Signer::new("Jefe");
+        assert_eq!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+                   signer.sign("what do ya want for nothing?"));
+    }
+
+    #[test]
+    fn verifies_own_signature_only() {
+        let signer = Signer::new("s3cret");
+        let signature = signer.sign("message");
+
+        assert!(signer.verify("message", &signature));
+        assert!(!signer.verify("other message", &signature));
+        assert!(!Signer::new("other").verify("message", &signature));
+    }
+
+    #[test]
+    fn recovers_message_from_own_token_only() {
+        let signer = Signer::new("s3cret");
+        let token = signer.token_for("unsubscribe\n/a/\njoe@example.org");
+
+        assert_eq!(Some("unsubscribe\n/a/\njoe@example.org".to_owned()), signer.message_in(&token));
+        assert_eq!(None, Signer::new("other").message_in(&token));
+        assert_eq!(None, signer.message_in(&token.replacen('.', "x.", 1)));
+        assert_eq!(None, signer.message_in("garbage"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use native_tls::TlsConnector;
+
+use crate::mail::is_plain_address;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const CLIENT_NAME: &str = "localhost";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity
+{
+    /// The connection is not encrypted. Only use this with a server on the same host or network.
+    Plain,
+    /// The connection is upgraded to TLS with the STARTTLS command before anything else is sent.
+    StartTls,
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpSettings
+{
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub credentials: Option<(String, String)>,
+}
+
+
+/// Sends a formatted message via an SMTP server. Only the PLAIN mechanism is used for
+/// authentication, which is supported by practically all servers.
+pub fn send(settings: &SmtpSettings, from_address: &str, to_address: &str, message: &str) -> Result<(), Error>
+{
+    for address in [from_address, to_address] {
+        if !is_plain_address(address) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Not a plain email address: {:?}", address)));
+        }
+    }
+    let stream = connect(&settings.host, settings.port)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut client = Client::new(stream);
+    client.expect(220)?;
+    client.command(&format!("EHLO {}", CLIENT_NAME), 250)?;
+    match settings.security {
+        SmtpSecurity::Plain => {
+            client.deliver(settings, from_address, to_address, message)
+        }
+        SmtpSecurity::StartTls => {
+            client.command("STARTTLS", 220)?;
+            let connector = TlsConnector::new().map_err(Error::other)?;
+            let stream = connector.connect(&settings.host, client.into_inner()).map_err(Error::other)?;
+            let mut client = Client::new(stream);
+            client.command(&format!("EHLO {}", CLIENT_NAME), 250)?;
+            client.deliver(settings, from_address, to_address, message)
+        }
+    }
+}
+
+/// Connects to the first address of the host that accepts the connection within the timeout.
+fn connect(host: &str, port: u16) -> Result<TcpStream, Error> {
+    let mut last_error = Error::new(ErrorKind::NotFound, format!("SMTP server {} has no address", host));
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+
+struct Client<S: Read + Write>
+{
+    reader: BufReader<S>,
+}
+
+impl<S: Read + Write> Client<S>
+{
+    fn new(stream: S) -> Self {
+        Client { reader: BufReader::new(stream) }
+    }
+
+    fn into_inner(self) -> S {
+        self.reader.into_inner()
+    }
+
+    fn deliver(&mut self, settings: &SmtpSettings, from_address: &str, to_address: &str, message: &str) -> Result<(), Error> {
+        if let Some((user, password)) = &settings.credentials {
+            let token = BASE64.encode(format!("\0{}\0{}", user, password));
+            self.command(&format!("AUTH PLAIN {}", token), 235)?;
+        }
+        self.command(&format!("MAIL FROM:<{}>", from_address), 250)?;
+        self.command(&format!("RCPT TO:<{}>", to_address), 250)?;
+        self.command("DATA", 354)?;
+        self.write(&dot_stuff(message))?;
+        self.command(".", 250)?;
+        self.command("QUIT", 221)
+    }
+
+    fn command(&mut self, line: &str, code: u16) -> Result<(), Error> {
+        self.write(&format!("{}\r\n", line))?;
+        self.expect(code)
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Error> {
+        let stream = self.reader.get_mut();
+        stream.write_all(text.as_bytes())?;
+        stream.flush()
+    }
+
+    fn expect(&mut self, code: u16) -> Result<(), Error> {
+        let (actual, text) = self.read_reply()?;
+        if actual == code {
+            Ok(())
+        } else {
+            Err(Error::other(format!("SMTP server returned: {} {}", actual, text)))
+        }
+    }
+
+    /// Reads a reply, which can span multiple lines. All but the last line have a dash
+    /// after the code.
+    fn read_reply(&mut self) -> Result<(u16, String), Error> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "SMTP server closed the connection"));
+            }
+            let line = line.trim_end();
+            let code = line.get(..3).and_then(|c| c.parse().ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid SMTP reply: {}", line)))?;
+            text.push_str(line.get(4..).unwrap_or(""));
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok((code, text));
+            }
+            text.push(' ');
+        }
+    }
+}
+
+
+/// Converts line breaks to CRLF and doubles dots at the start of lines, so that the message
+/// cannot end the DATA command prematurely. The result ends with a line break.
+fn dot_stuff(message: &str) -> String {
+    let mut result = String::with_capacity(message.len() + 2);
+    for line in message.lines() {
+        if line.starts_with('.') {
+            result.push('.');
+        }
+        result.push_str(line);
+        result.push_str("\r\n");
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_leading_dots_and_normalises_line_breaks() {
+        assert_eq!("a\r\n..\r\n..b\r\nc.\r\n", dot_stuff("a\n.\r\n.b\nc."));
+    }
+
+    #[test]
+    fn reads_multiline_replies() {
+        let stream = std::io::Cursor::new(b"250-example.org\r\n250-PIPELINING\r\n250 STARTTLS\r\n".to_vec());
+        let mut client = Client::new(stream);
+
+        assert_eq!((250, "example.org PIPELINING STARTTLS".to_owned()), client.read_reply().unwrap());
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::Mutex;
+
+use rusqlite::{Connection, OptionalExtension, Params, params};
+use uuid::Uuid;
+
+use crate::comment::Comment;
+use crate::storage::{CommentStore, prepare_directory};
+use crate::utils;
+
+/// Stores comments in an SQLite database file inside the repository directory. Each row holds
+/// the comment in the same JSON format used by the directory store, plus the columns needed
+/// to look up comments.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+const DATABASE_FILENAME: &str = "comments.db";
+
+impl SqliteStore {
+    pub fn new(path: &str, reset: bool) -> Self {
+        prepare_directory(path, reset);
+        let filename = format!("{}/{}", path, DATABASE_FILENAME);
+        let connection = Connection::open(&filename)
+            .unwrap_or_else(|e| panic!("Failed to open database {}: {}", filename, e));
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS comments (
+                 id        TEXT PRIMARY KEY,
+                 path      TEXT NOT NULL,
+                 timestamp TEXT NOT NULL,
+                 data      TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS comments_path ON comments (path);")
+            .unwrap_or_else(|e| panic!("Failed to create tables in database {}: {}", filename, e));
+        Self {
+            connection: Mutex::new(connection),
+        }
+    }
+
+    fn query<P: Params>(&self, sql: &str, params: P) -> Vec<Comment> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(sql).expect("Failed to prepare query");
+        let rows = statement.query_map(params, |row| row.get::<_, String>(0))
+            .expect("Failed to query comments");
+        rows.map(|data| utils::from_json(&data.expect("Failed to read comment"))).collect()
+    }
+}
+
+impl CommentStore for SqliteStore {
+    fn load_all(&self) -> Vec<Comment> {
+        println!("Loading comments from database");
+        self.query("SELECT data FROM comments", [])
+    }
+
+    fn save(&self, comment: &Comment) {
+        println!("Saving comment to database: {}", comment.id.as_simple());
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO comments (id, path, timestamp, data) VALUES (?1, ?2, ?3, ?4)",
+            params![comment.id.as_simple().to_string(), comment.path, comment.timestamp.to_rfc3339(), utils::to_json(comment)])
+            .unwrap_or_else(|e| panic!("Failed to save comment {}: {}", comment.id.as_simple(), e));
+    }
+
+    fn delete(&self, comment: &Comment) {
+        println!("Deleting comment in database: {}", comment.id.as_simple());
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM comments WHERE id = ?1", params![comment.id.as_simple().to_string()])
+            .unwrap_or_else(|e| panic!("Failed to delete comment {}: {}", comment.id.as_simple(), e));
+    }
+
+    fn find_by_id(&self, id: Uuid) -> Option<Comment> {
+        let connection = self.connection.lock().unwrap();
+        connection.query_row("SELECT data FROM comments WHERE id = ?1", params![id.as_simple().to_string()],
+                             |row| row.get::<_, String>(0))
+            .optional()
+            .expect("Failed to query comment")
+            .map(|data| utils::from_json(&data))
+    }
+
+    fn find_by_path(&self, path: &str) -> Vec<Comment> {
+        self.query("SELECT data FROM comments WHERE path = ?1 ORDER BY timestamp", params![path])
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::panic::RefUnwindSafe;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::comment::Comment;
+use crate::json_storage::JsonDirectoryStore;
+use crate::sqlite_storage::SqliteStore;
+
+/// Persistence for comments. The repository keeps all comments in memory; it uses a store to
+/// load them when it (re)loads and to persist every change.
+pub trait CommentStore: Send + Sync + RefUnwindSafe {
+    fn load_all(&self) -> Vec<Comment>;
+
+    fn save(&self, comment: &Comment);
+
+    fn delete(&self, comment: &Comment);
+
+    fn find_by_id(&self, id: Uuid) -> Option<Comment> {
+        self.load_all().into_iter().find(|c| c.id == id)
+    }
+
+    fn find_by_path(&self, path: &str) -> Vec<Comment> {
+        self.load_all().into_iter().filter(|c| c.path == path).collect()
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageType {
+    Json,
+    Sqlite,
+}
+
+impl StorageType {
+    pub fn open(&self, path: &str, reset: bool) -> Arc<dyn CommentStore> {
+        match self {
+            StorageType::Json => Arc::new(JsonDirectoryStore::new(path, reset)),
+            StorageType::Sqlite => Arc::new(SqliteStore::new(path, reset)),
+        }
+    }
+}
+
+impl FromStr for StorageType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StorageType::Json),
+            "sqlite" => Ok(StorageType::Sqlite),
+            _ => Err(format!("Unknown storage type: {}", s)),
+        }
+    }
+}
+
+
+pub(crate) fn prepare_directory(path: &str, reset: bool) {
+    if reset && fs::metadata(path).is_ok() {
+        fs::remove_dir_all(path).unwrap_or_else(|_| panic!("Failed to remove directory at {}", path));
+    }
+    fs::create_dir_all(path).unwrap_or_else(|_| panic!("Failed to create directory at {}", path));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_storage_type() {
+        assert_eq!(Ok(StorageType::Json), "json".parse());
+        assert_eq!(Ok(StorageType::Sqlite), "sqlite".parse());
+        assert!("xml".parse::<StorageType>().is_err());
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::signing::Signer;
+use crate::utils;
+
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Subscription
+{
+    pub path: String,
+    pub email: String,
+}
+
+impl Subscription
+{
+    pub fn new(path: &str, email: &str) -> Self {
+        Subscription { path: path.to_owned(), email: email.trim().to_lowercase() }
+    }
+
+    /// Creates a token that allows unsubscribing without further authentication.
+    pub fn unsubscribe_token(&self, signer: &Signer) -> String {
+        self.token(signer, "unsubscribe")
+    }
+
+    pub fn from_unsubscribe_token(signer: &Signer, token: &str) -> Option<Self> {
+        Self::from_token(signer, token, "unsubscribe")
+    }
+
+    /// Creates a token that proves that the owner of the email address received the mail
+    /// asking them to confirm the subscription.
+    pub fn confirm_token(&self, signer: &Signer) -> String {
+        self.token(signer, "confirm")
+    }
+
+    pub fn from_confirm_token(signer: &Signer, token: &str) -> Option<Self> {
+        Self::from_token(signer, token, "confirm")
+    }
+
+    fn token(&self, signer: &Signer, purpose: &str) -> String {
+        signer.token_for(&format!("{}\n{}\n{}", purpose, self.path, self.email))
+    }
+
+    fn from_token(signer: &Signer, token: &str, purpose: &str) -> Option<Self> {
+        let message = signer.message_in(token)?;
+        let mut parts = message.splitn(3, '\n');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(p), Some(path), Some(email)) if p == purpose => Some(Subscription::new(path, email)),
+            _ => None
+        }
+    }
+}
+
+
+/// How a subscription is stored in the file. Files written by earlier versions only contain
+/// confirmed subscriptions, without the flag.
+#[derive(Serialize, Deserialize)]
+struct StoredSubscription
+{
+    #[serde(flatten)]
+    subscription: Subscription,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    unconfirmed: bool,
+}
+
+#[derive(Default)]
+struct Entries
+{
+    confirmed: BTreeSet<Subscription>,
+    unconfirmed: BTreeSet<Subscription>,
+}
+
+
+/// The commenters who want to be notified of new comments on a page. A subscription stays
+/// unconfirmed, and no notifications are sent for it, until the owner of the email address
+/// confirms it. If a file is given, the subscriptions are kept in that file as JSON.
+#[derive(Clone, Default)]
+pub struct Subscriptions
+{
+    filename: Option<String>,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl Subscriptions
+{
+    /// Reads the subscriptions from the file, if it exists.
+    pub fn open(filename: &str) -> Result<Self, String> {
+        let stored: Vec<StoredSubscription> = match fs::read_to_string(filename) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Failed to read subscriptions from file {}: {}", filename, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read file {}: {}", filename, e)),
+        };
+        let mut entries = Entries::default();
+        for s in stored {
+            if s.unconfirmed {
+                entries.unconfirmed.insert(s.subscription);
+            } else {
+                entries.confirmed.insert(s.subscription);
+            }
+        }
+        Ok(Subscriptions {
+            filename: Some(filename.to_owned()),
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    /// Adds an unconfirmed subscription. Returns whether it is new, ie. whether the owner of
+    /// the email address should be asked to confirm it; they are asked only once.
+    pub fn request(&self, subscription: Subscription) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.confirmed.contains(&subscription) || !entries.unconfirmed.insert(subscription) {
+            return false;
+        }
+        self.save(&entries);
+        true
+    }
+
+    /// Activates a subscription that was requested before. Returns whether the subscription
+    /// is active.
+    pub fn confirm(&self, subscription: &Subscription) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.unconfirmed.remove(subscription) {
+            entries.confirmed.insert(subscription.clone());
+            self.save(&entries);
+        }
+        entries.confirmed.contains(subscription)
+    }
+
+    pub fn is_unconfirmed(&self, subscription: &Subscription) -> bool {
+        self.entries.lock().unwrap().unconfirmed.contains(subscription)
+    }
+
+    pub fn unsubscribe(&self, subscription: &Subscription) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let removed = entries.confirmed.remove(subscription) | entries.unconfirmed.remove(subscription);
+        if removed {
+            self.save(&entries);
+        }
+        removed
+    }
+
+    /// The confirmed subscriptions to the page.
+    pub fn subscribers(&self, path: &str) -> Vec<Subscription> {
+        self.entries.lock().unwrap().confirmed.iter().filter(|s| s.path == path).cloned().collect()
+    }
+
+    fn save(&self, entries: &Entries) {
+        if let Some(filename) = &self.filename {
+            let confirmed = entries.confirmed.iter().map(|s| (s, false));
+            let unconfirmed = entries.unconfirmed.iter().map(|s| (s, true));
+            let stored: Vec<StoredSubscription> = confirmed.chain(unconfirmed)
+                .map(|(s, unconfirmed)| StoredSubscription { subscription: s.clone(), unconfirmed })
+                .collect();
+            utils::write_file_atomically(Path::new(filename), &utils::to_json(&stored))
+                .unwrap_or_else(|e| panic!("Failed to write file {}: {}", filename, e));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribe(subscriptions: &Subscriptions, subscription: Subscription) {
+        subscriptions.request(subscription.clone());
+        subscriptions.confirm(&subscription);
+    }
+
+    #[test]
+    fn lists_subscribers_for_path() {
+        let subscriptions = Subscriptions::default();
+        subscribe(&subscriptions, Subscription::new("/a/", "joe@example.org"));
+        subscribe(&subscriptions, Subscription::new("/a/", "Jane@Example.org "));
+        subscribe(&subscriptions, Subscription::new("/a/", "joe@example.org"));
+        subscribe(&subscriptions, Subscription::new("/b/", "joe@example.org"));
+
+        let emails: Vec<String> = subscriptions.subscribers("/a/").into_iter().map(|s| s.email).collect();
+
+        assert_eq!(vec!["jane@example.org", "joe@example.org"], emails);
+    }
+
+    #[test]
+    fn lists_subscribers_only_once_confirmed() {
+        let subscriptions = Subscriptions::default();
+        let subscription = Subscription::new("/a/", "joe@example.org");
+
+        assert!(subscriptions.request(subscription.clone()));
+        assert!(!subscriptions.request(subscription.clone()));
+        assert!(subscriptions.is_unconfirmed(&subscription));
+        assert!(subscriptions.subscribers("/a/").is_empty());
+
+        assert!(subscriptions.confirm(&subscription));
+        assert!(subscriptions.confirm(&subscription));
+        assert!(!subscriptions.request(subscription.clone()));
+        assert_eq!(vec![subscription.clone()], subscriptions.subscribers("/a/"));
+
+        assert!(subscriptions.unsubscribe(&subscription));
+        assert!(!subscriptions.confirm(&subscription));
+    }
+
+    #[test]
+    fn confirm_and_unsubscribe_tokens_are_not_interchangeable() {
+        let signer = Signer::new("s3cret");
+        let subscription = Subscription::new("/a/", "joe@example.org");
+
+        let token = subscription.confirm_token(&signer);
+
+        assert_eq!(Some(subscription.clone()), Subscription::from_confirm_token(&signer, &token));
+        assert_eq!(None, Subscription::from_unsubscribe_token(&signer, &token));
+        assert_eq!(None, Subscription::from_confirm_token(&signer, &subscription.unsubscribe_token(&signer)));
+    }
+
+    #[test]
+    fn unsubscribes_with_token() {
+        let signer = Signer::new("s3cret");
+        let subscriptions = Subscriptions::default();
+        let subscription = Subscription::new("/a/", "joe@example.org");
+        subscribe(&subscriptions, subscription.clone());
+
+        let token = subscription.unsubscribe_token(&signer);
+        let parsed = Subscription::from_unsubscribe_token(&signer, &token).unwrap();
+
+        assert!(subscriptions.unsubscribe(&parsed));
+        assert!(subscriptions.subscribers("/a/").is_empty());
+        assert!(Subscription::from_unsubscribe_token(&signer, &signer.token_for("other")).is_none());
+    }
+}
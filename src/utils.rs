@@ -1,5 +1,6 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::fs;
+use std::io;
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -10,11 +11,116 @@ pub fn from_json<'a, T>(s: &'a str) -> T where T: Deserialize<'a> {
 
 pub fn to_json<T>(val: T) -> String where T: Serialize {
     let result = serde_json::to_string_pretty(&val);
-    result.ok().expect("Failed to produce JSON")
+    result.expect("Failed to produce JSON")
 }
 
-pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+/// Writes the contents to a new file next to the file and renames it, so that the file is
+/// never left half written.
+pub fn write_file_atomically(filename: &Path, contents: &str) -> io::Result<()> {
+    let mut temp_filename = filename.as_os_str().to_owned();
+    temp_filename.push(".tmp");
+    fs::write(&temp_filename, contents)?;
+    fs::rename(&temp_filename, filename)
+}
+
+/// Escapes the characters that have a special meaning in HTML and XML.
+pub fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Returns the URL of the page with the path, which is absolute if the site URL is known.
+pub fn page_url(site_url: &Option<String>, path: &str) -> String {
+    match site_url {
+        Some(site) => format!("{}{}", site.trim_end_matches('/'), path),
+        None => path.to_owned()
+    }
+}
+
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encodes bytes with the RFC 4648 base 32 alphabet, in lower case and without padding.
+pub fn base32(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+/// Compares two texts line by line. Lines that were removed start with `-`, lines that were
+/// added with `+` and lines that stayed the same with a space.
+pub fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] { common[i + 1][j + 1] + 1 } else { common[i + 1][j].max(common[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push(format!(" {}", old[i]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            diff.push(format!("-{}", old[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+{}", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!("&lt;p&gt;Tom &amp; &quot;Jerry&quot;&lt;/p&gt;", escape_markup("<p>Tom & \"Jerry\"</p>"));
+    }
+
+    #[test]
+    fn encodes_rfc_4648_test_vectors() {
+        assert_eq!("", base32(b""));
+        assert_eq!("my", base32(b"f"));
+        assert_eq!("mzxw6", base32(b"foo"));
+        assert_eq!("mzxw6yq", base32(b"foob"));
+        assert_eq!("mzxw6ytboi", base32(b"foobar"));
+    }
+
+    #[test]
+    fn diffs_lines() {
+        let diff = line_diff("<h3>Title</h3>\n<p>Text</p>\n", "<h1>Title</h1>\n<p>Text</p>\n<p>More</p>\n");
+
+        assert_eq!(vec!["-<h3>Title</h3>", "+<h1>Title</h1>", " <p>Text</p>", "+<p>More</p>"], diff);
+        assert!(line_diff("a\nb", "a\nb").iter().all(|l| l.starts_with(' ')));
+    }
 }
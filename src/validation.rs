@@ -0,0 +1,178 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde_derive::Serialize;
+
+
+/// The maximum lengths of the fields of a comment, counted in characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommentLimits
+{
+    pub text_length: usize,
+    pub name_length: usize,
+    pub email_length: usize,
+    pub path_length: usize,
+}
+
+impl Default for CommentLimits
+{
+    fn default() -> Self {
+        CommentLimits {
+            text_length: 10_000,
+            name_length: 100,
+            email_length: 254,
+            path_length: 500,
+        }
+    }
+}
+
+impl CommentLimits
+{
+    pub fn check_text(&self, text: &str, errors: &mut ValidationErrors) {
+        check_length("text", text, self.text_length, errors);
+    }
+
+    pub fn check_path(&self, path: &str, errors: &mut ValidationErrors) {
+        if check_length("path", path, self.path_length, errors) && !is_valid_path(path) {
+            errors.add("path", "Path must start with / and must not contain spaces, control characters, ? or #");
+        }
+    }
+
+    /// Checks the name and email address of the author. Empty values count as not given.
+    pub fn check_author(&self, name: Option<&str>, email: Option<&str>, errors: &mut ValidationErrors) {
+        if let Some(name) = name {
+            check_length("authorName", name, self.name_length, errors);
+        }
+        if let Some(email) = email.filter(|e| !e.is_empty()) {
+            if check_length("authorEmail", email, self.email_length, errors) && !is_valid_email(email) {
+                errors.add("authorEmail", "Not a valid email address");
+            }
+        }
+    }
+}
+
+fn check_length(field: &'static str, value: &str, max: usize, errors: &mut ValidationErrors) -> bool {
+    let length = value.chars().count();
+    if length > max {
+        errors.add(field, &format!("Must not be longer than {} characters, but is {}", max, length));
+        false
+    } else {
+        true
+    }
+}
+
+/// Whether the path is an absolute URL path without query or fragment.
+pub fn is_valid_path(path: &str) -> bool {
+    path.starts_with('/') && !path.chars().any(|c| c.is_whitespace() || c.is_control() || c == '?' || c == '#')
+}
+
+/// Whether the address is a plain email address: a local part made of the ASCII characters
+/// that RFC 5322 allows without quoting, an @ and a domain with at least two labels. Addresses
+/// are used as mail recipients, so anything that could be read as a header or a list of
+/// addresses is rejected.
+pub fn is_valid_email(email: &str) -> bool {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"^[A-Za-z0-9.!#$%&'*+/=?^_`{|}~-]+@[A-Za-z0-9](?:[A-Za-z0-9-]*[A-Za-z0-9])?(?:\.[A-Za-z0-9](?:[A-Za-z0-9-]*[A-Za-z0-9])?)+$").unwrap())
+        .is_match(email)
+}
+
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FieldError
+{
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// The problems found with a request, one entry per field and problem. They are listed in
+/// the problem that is sent when the request is rejected.
+#[derive(Debug, Default)]
+pub struct ValidationErrors
+{
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors
+{
+    pub fn add(&mut self, field: &'static str, message: &str) {
+        self.errors.push(FieldError { field, message: message.to_owned() });
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.errors.iter().any(|e| e.field == field)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(errors: &ValidationErrors) -> Vec<&str> {
+        errors.errors.iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let limits = CommentLimits { text_length: 3, ..CommentLimits::default() };
+        let mut errors = ValidationErrors::default();
+
+        limits.check_text("äöü", &mut errors);
+        assert!(errors.is_empty());
+        limits.check_text("äöüß", &mut errors);
+        assert_eq!(vec![FieldError { field: "text", message: "Must not be longer than 3 characters, but is 4".to_owned() }], errors.errors);
+    }
+
+    #[test]
+    fn reports_each_failing_field() {
+        let limits = CommentLimits { name_length: 5, ..CommentLimits::default() };
+        let mut errors = ValidationErrors::default();
+
+        limits.check_path("blog/post", &mut errors);
+        limits.check_author(Some("Joe Bloggs"), Some("joe@"), &mut errors);
+
+        assert_eq!(vec!["path", "authorName", "authorEmail"], fields(&errors));
+    }
+
+    #[test]
+    fn treats_empty_email_as_missing() {
+        let mut errors = ValidationErrors::default();
+
+        CommentLimits::default().check_author(Some(""), Some(""), &mut errors);
+
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn accepts_absolute_paths_only() {
+        assert!(is_valid_path("/"));
+        assert!(is_valid_path("/blog/2020/hello-world.html"));
+        assert!(!is_valid_path(""));
+        assert!(!is_valid_path("https://example.org/"));
+        assert!(!is_valid_path("/a b/"));
+        assert!(!is_valid_path("/a/?x=1"));
+        assert!(!is_valid_path("/a/#top"));
+        assert!(!is_valid_path("/a/\n"));
+    }
+
+    #[test]
+    fn checks_syntax_of_email_addresses() {
+        assert!(is_valid_email("joe@example.org"));
+        assert!(is_valid_email("joe.bloggs+blog@mail.example-domain.co.uk"));
+        assert!(!is_valid_email("joe"));
+        assert!(!is_valid_email("joe@localhost"));
+        assert!(!is_valid_email("joe@example..org"));
+        assert!(!is_valid_email("joe@-example.org"));
+        assert!(!is_valid_email("joe bloggs@example.org"));
+        assert!(!is_valid_email("Joe <joe@example.org>"));
+        assert!(!is_valid_email("joe@exa@mple.org"));
+        assert!(!is_valid_email("joe@example.org,ann@example.org"));
+        assert!(!is_valid_email("joe\r\nBcc:ann@example.org"));
+        assert!(!is_valid_email("\"joe\"@example.org"));
+        assert!(!is_valid_email("jo\u{0}e@example.org"));
+    }
+}
@@ -1,46 +1,107 @@
+use std::collections::HashMap;
 use std::pin::Pin;
-use chrono::{DateTime, Utc};
-use futures_util::{future, FutureExt, TryFutureExt};
+use chrono::{DateTime, Duration, Utc};
 use gotham::handler::HandlerFuture;
 use gotham::handler::FileOptions;
-use gotham::helpers::http::response::create_response;
+use gotham::helpers::http::response::{create_empty_response, create_response};
 use gotham::middleware::state::StateMiddleware;
-use gotham::pipeline::{new_pipeline, single_pipeline};
+use gotham::pipeline::{finalize_pipeline_set, new_pipeline, new_pipeline_set};
 use gotham::router::builder::{build_router, DrawRoutes};
 use gotham::router::builder::DefineSingleRoute;
 use gotham::router::Router;
+use gotham::router::response::StaticResponseExtender;
 use gotham::state::{FromState, State};
 use gotham::prelude::*;
-use gotham::hyper::{Body, Response, StatusCode, Uri};
+use gotham::hyper::{Body, HeaderMap, Response, StatusCode, Uri};
+use gotham::hyper::header::{CONTENT_TYPE, IF_MODIFIED_SINCE, LAST_MODIFIED};
 use serde_derive::*;
 use uuid::Uuid;
 
-use crate::comment::Comment;
-use crate::gotham_json::{create_json_response, create_json_response_with_headers, take_json_body};
-use crate::markdown::md_to_html;
-use crate::repository::CommentRepository;
+use crate::comment::{Comment, Editor, ModerationStatus};
+use crate::feed::{self, FeedInfo};
+use crate::gotham_json::{create_json_response, create_json_response_with_headers, with_json_body};
+use crate::markdown::{md_to_html, MarkdownOptions};
+use crate::moderation_links::{self, ModerationAction};
+use crate::problem::Problem;
+use crate::repository::{CommentQuery, CommentRepository, SortOrder, ThreadCursor, ThreadedComment};
 use crate::gotham_cors::CorsMiddleware;
+use crate::gotham_auth::{BearerAuthMiddleware, Credentials, RequireAdminMiddleware, unauthorized_response};
+use crate::signing::Signer;
+use crate::subscriptions::Subscription;
+use crate::utils::escape_markup;
+use crate::validation::{CommentLimits, ValidationErrors};
+
+/// Path parameters and query strings that cannot be parsed are answered with a problem
+/// instead of an empty response.
+macro_rules! bad_request_extender {
+    ($extractor:ty, $code:expr, $detail:expr) => {
+        impl StaticResponseExtender for $extractor {
+            type ResBody = Body;
+
+            fn extend(state: &mut State, response: &mut Response<Body>) {
+                Problem::bad_request($code, $detail).extend(state, response);
+            }
+        }
+    };
+}
+
+/// Gotham answers unknown paths and unsupported methods with empty responses; those are
+/// replaced by a problem. Responses that already have a body, like a missing comment, are
+/// left as they are.
+fn extend_empty_response(problem: Problem) -> impl Fn(&mut State, &mut Response<Body>) + Send + Sync {
+    move |state, response| {
+        if !response.headers().contains_key(CONTENT_TYPE) {
+            problem.extend(state, response);
+        }
+    }
+}
+
+#[derive(Clone, StateData)]
+pub struct ApiSettings {
+    pub app_path: String,
+    pub site_url: Option<String>,
+    pub cors_origin: Option<String>,
+    pub admin_token: Option<String>,
+    pub secret: String,
+    pub edit_window: Duration,
+    pub markdown: MarkdownOptions,
+    pub limits: CommentLimits,
+    /// The maximum size in bytes of the JSON documents that are posted.
+    pub max_body_size: usize,
+}
 
-pub fn run(repo: CommentRepository, app_path: &str, addr: &str, origin: &Option<String>) {
+pub fn run(repo: CommentRepository, addr: &str, settings: &ApiSettings) {
     println!("Listening for requests at http://{}", addr);
-    let _ = gotham::start(addr.to_string(), router(app_path, origin, repo));
+    let _ = gotham::start(addr.to_string(), router(settings, repo));
 }
 
-pub fn router(app_path: &str, origin: &Option<String>, repo: CommentRepository) -> Router {
-    let pipeline1 = new_pipeline()
+pub fn router(settings: &ApiSettings, repo: CommentRepository) -> Router {
+    let app_path = &settings.app_path;
+    let pipelines = new_pipeline_set();
+    let (pipelines, default) = pipelines.add(new_pipeline()
         .add(StateMiddleware::new(repo))
-        .add(CorsMiddleware::new(origin))  // TODO: should only add middleware when needed
-        .build();
-    let (chain, pipelines) = single_pipeline(pipeline1);
-    build_router(chain, pipelines, |route| {
+        .add(StateMiddleware::new(settings.clone()))
+        .add(StateMiddleware::new(EditPolicy::new(&settings.secret, settings.edit_window)))
+        .add(CorsMiddleware::new(&settings.cors_origin))  // TODO: should only add middleware when needed
+        .add(BearerAuthMiddleware::new(&settings.admin_token))
+        .build());
+    let (pipelines, admin) = pipelines.add(new_pipeline()
+        .add(RequireAdminMiddleware)
+        .build());
+    let pipelines = finalize_pipeline_set(pipelines);
+    let default_chain = (default, ());
+    let admin_chain = (admin, default_chain);
+    build_router(default_chain, pipelines, |route| {
+        route.add_response_extender(StatusCode::NOT_FOUND, extend_empty_response(Problem::not_found()));
+        route.add_response_extender(StatusCode::METHOD_NOT_ALLOWED, extend_empty_response(Problem::method_not_allowed()));
         route.get("/ping")
             .to(get_ping);
         route.get("/comments")
             .with_query_string_extractor::<CommentsQueryStringExtractor>()
             .to(get_comments);
-        route.get("/comments/:id")
+        route.patch("/comments/:id")
             .with_path_extractor::<IdParam>()
-            .to(get_comment);
+            .to(patch_comment);
         route.delete("/comments/:id")
             .with_path_extractor::<IdParam>()
             .to(delete_comment);
@@ -50,10 +111,54 @@ pub fn router(app_path: &str, origin: &Option<String>, repo: CommentRepository)
             .to(post_comment);
         route.options("/comments")
             .to(cors_preflight);
+        route.get("/permalinks/:idh")
+            .with_path_extractor::<IdhParam>()
+            .to(get_permalink);
+        route.get("/unsubscribe")
+            .with_query_string_extractor::<UnsubscribeQueryStringExtractor>()
+            .to(get_unsubscribe);
+        route.get("/confirm")
+            .with_query_string_extractor::<ConfirmQueryStringExtractor>()
+            .to(get_confirm);
+        route.post("/confirm")
+            .with_query_string_extractor::<ConfirmQueryStringExtractor>()
+            .to(post_confirm);
+        route.get("/moderate")
+            .with_query_string_extractor::<ModerateQueryStringExtractor>()
+            .to(get_moderate);
+        route.post("/moderate")
+            .with_query_string_extractor::<ModerateQueryStringExtractor>()
+            .to(post_moderate);
+        route.get("/counts")
+            .with_query_string_extractor::<CountsQueryStringExtractor>()
+            .to(get_counts);
+        route.post("/counts")
+            .to(post_counts);
+        route.options("/counts")
+            .to(cors_preflight);
+        route.get("/feed.atom")
+            .with_query_string_extractor::<FeedQueryStringExtractor>()
+            .to(get_atom_feed);
+        route.get("/feed.rss")
+            .with_query_string_extractor::<FeedQueryStringExtractor>()
+            .to(get_rss_feed);
         route.post("/preview")
             .to(post_preview);
         route.options("/preview")
             .to(cors_preflight);
+        route.with_pipeline_chain(admin_chain, |route| {
+            route.get("/comments/:id")
+                .with_path_extractor::<IdParam>()
+                .to(get_comment);
+            route.get("/moderation")
+                .to(get_pending_comments);
+            route.post("/moderation/:id/approve")
+                .with_path_extractor::<IdParam>()
+                .to(approve_comment);
+            route.post("/moderation/:id/reject")
+                .with_path_extractor::<IdParam>()
+                .to(reject_comment);
+        });
         route.get("/favicon.png")
             .to_file(&format!("{}/favicon.png", app_path));
         route.get("/app/*")
@@ -78,36 +183,140 @@ fn get_ping(state: State) -> (State, Response<Body>) {
 }
 
 
-#[derive(Deserialize, StateData, StaticResponseExtender)]
+#[derive(Deserialize, StateData)]
 struct IdParam {
     id: Uuid,
 }
 
+bad_request_extender!(IdParam, "invalid-path", "The comment id is not valid");
+
 fn get_comment(mut state: State) -> (State, Response<Body>) {
     let p = IdParam::take_from(&mut state);
     let repository = CommentRepository::borrow_from(&state);
 
     let response = match repository.comment_with_id(p.id) {
         Some(comment) => create_json_response(&state, StatusCode::OK, &comment).unwrap(),
-        None => create_response(&state, StatusCode::NOT_FOUND, mime::TEXT_PLAIN, "Comment not found")
+        None => Problem::comment_not_found().to_response(&state)
     };
     (state, response)
 }
 
+#[derive(Deserialize, StateData)]
+struct IdhParam {
+    idh: String,
+}
+
+bad_request_extender!(IdhParam, "invalid-path", "The comment id is not valid");
+
+/// Resolves the public id used in a permalink, including the ids used by earlier versions,
+/// to the comment it belongs to.
+fn get_permalink(mut state: State) -> (State, Response<Body>) {
+    let p = IdhParam::take_from(&mut state);
+    let repository = CommentRepository::borrow_from(&state);
+
+    let response = match repository.comment_with_idh(&p.idh) {
+        Some(comment) => {
+            let mut doc = CommentDisplayDoc::from_comment(&comment);
+            doc.parent_idh = comment.parent_id.and_then(|id| repository.comment_with_id(id)).map(|p| p.idh);
+            doc.depth = repository.depth_of(&comment);
+            create_json_response(&state, StatusCode::OK, &doc).unwrap()
+        }
+        None => Problem::comment_not_found().to_response(&state)
+    };
+    (state, response)
+}
+
+
+/// Authors can edit and delete their comments for a limited time after posting them. They
+/// prove authorship with a token that is returned when the comment is posted. Admins can
+/// always edit and delete comments.
+#[derive(Clone, StateData)]
+pub struct EditPolicy {
+    signer: Signer,
+    window: Duration,
+}
+
+impl EditPolicy {
+    pub fn new(secret: &str, window: Duration) -> Self {
+        EditPolicy { signer: Signer::new(secret), window }
+    }
+
+    fn token_for(&self, comment: &Comment) -> String {
+        self.signer.sign(&format!("edit:{}", comment.id.as_simple()))
+    }
+
+    fn permits(&self, credentials: &Credentials, comment: &Comment) -> bool {
+        if credentials.is_admin {
+            return true;
+        }
+        match &credentials.bearer_token {
+            Some(token) => self.signer.verify(&format!("edit:{}", comment.id.as_simple()), token)
+                && Utc::now() - comment.timestamp <= self.window,
+            None => false
+        }
+    }
+}
+
 
 fn delete_comment(mut state: State) -> (State, Response<Body>) {
     let p = IdParam::take_from(&mut state);
     let repository = CommentRepository::borrow_from(&state);
-    let response = if let Some(comment) = repository.comment_with_id(p.id) {
-        repository.delete_comment(&comment); // TODO: error handling?
-        create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Deleted comment")
-    } else {
-        create_response(&state, StatusCode::NOT_FOUND, mime::TEXT_PLAIN, "Comment not found")
+    let policy = EditPolicy::borrow_from(&state);
+    let response = match repository.comment_with_id(p.id) {
+        Some(comment) if policy.permits(Credentials::borrow_from(&state), &comment) => {
+            repository.delete_comment(&comment); // TODO: error handling?
+            create_response(&state, StatusCode::OK, mime::TEXT_PLAIN, "Deleted comment")
+        }
+        Some(_) => unauthorized_response(&state),
+        None => Problem::comment_not_found().to_response(&state)
     };
     (state, response)
 }
 
 
+#[derive(Deserialize)]
+struct CommentEditDoc {
+    text: String,
+}
+
+fn patch_comment(mut state: State) -> Pin<Box<HandlerFuture>> {
+    let p = IdParam::take_from(&mut state);
+    let max_body_size = ApiSettings::borrow_from(&state).max_body_size;
+    with_json_body(state, max_body_size, move |state, doc: CommentEditDoc| {
+        let repository = CommentRepository::borrow_from(&state);
+        let policy = EditPolicy::borrow_from(&state);
+        let credentials = Credentials::borrow_from(&state);
+        let settings = ApiSettings::borrow_from(&state);
+        let response = match repository.comment_with_id(p.id).filter(|c| !c.deleted) {
+            Some(comment) if policy.permits(credentials, &comment) => {
+                let mut comment = comment;
+                let mut errors = ValidationErrors::default();
+                settings.limits.check_text(&doc.text, &mut errors);
+                if errors.is_empty() {
+                    let first_time_author = !credentials.is_admin && repository.is_first_time_author(&comment);
+                    comment.edit(&doc.text, if credentials.is_admin { Editor::Admin } else { Editor::Author }, &settings.markdown, first_time_author);
+                    if repository.is_moderated() && !credentials.is_admin {
+                        comment.status = ModerationStatus::Pending;
+                    }
+                    if comment.text_html.is_empty() {
+                        errors.add("text", "No visible text");
+                    }
+                }
+                if !errors.is_empty() {
+                    Problem::invalid_fields(errors).to_response(&state)
+                } else {
+                    repository.update_comment(&comment);
+                    create_json_response(&state, StatusCode::OK, &CommentDisplayDoc::from_comment(&comment)).unwrap()
+                }
+            }
+            Some(_) => unauthorized_response(&state),
+            None => Problem::comment_not_found().to_response(&state)
+        };
+        (state, response)
+    })
+}
+
+
 #[derive(Deserialize)]
 struct CommentPostDoc {
     path: String,
@@ -116,49 +325,114 @@ struct CommentPostDoc {
     author_name: Option<String>,
     #[serde(rename = "authorEmail")]
     author_email: Option<String>,
+    #[serde(rename = "parentIdh")]
+    parent_idh: Option<String>,
+    #[serde(rename = "notifyReplies", default)]
+    notify_replies: bool,
 }
 
 impl CommentPostDoc {
-    fn to_comment(&self) -> Comment {
-        Comment::new(&self.path, &self.text,
-                     self.author_name.as_ref().map(String::as_str),
-                     self.author_email.as_ref().map(String::as_str)) // TODO: better way?
+    fn to_comment(&self, options: &MarkdownOptions, first_time_author: bool) -> Comment {
+        Comment::with_markdown(&self.path, &self.text,
+                               self.author_name.as_deref(),
+                               self.author_email.as_deref(),
+                               options, first_time_author) // TODO: better way?
+    }
+
+    fn validate(&self, limits: &CommentLimits) -> ValidationErrors {
+        let mut errors = ValidationErrors::default();
+        limits.check_path(&self.path, &mut errors);
+        limits.check_text(&self.text, &mut errors);
+        limits.check_author(self.author_name.as_deref(), self.author_email.as_deref(), &mut errors);
+        errors
     }
 }
 
 
 fn post_comment(state: State) -> Pin<Box<HandlerFuture>> {
-    let f = take_json_body::<CommentPostDoc>(state).and_then(|(state, doc)| {
-        let comment = doc.to_comment();
-        let response = if comment.text_html == "" {
-            create_response(&state, StatusCode::BAD_REQUEST, mime::TEXT_PLAIN, "No visible text")
+    let max_body_size = ApiSettings::borrow_from(&state).max_body_size;
+    with_json_body(state, max_body_size, |state, doc: CommentPostDoc| {
+        let repository = CommentRepository::borrow_from(&state);
+        let settings = ApiSettings::borrow_from(&state);
+        let mut errors = doc.validate(&settings.limits);
+        let parent = doc.parent_idh.as_ref().map(|idh| repository.comment_on_path_with_idh(&doc.path, idh));
+        if let Some(None) = parent {
+            errors.add("parentIdh", "Parent comment not found");
+        }
+        if doc.notify_replies && doc.author_email.as_deref().unwrap_or("").is_empty() {
+            errors.add("authorEmail", "Email address required for notifications");
+        }
+        // The text is only rendered once it is known not to be too long
+        let comment = if errors.contains("text") {
+            None
         } else {
-            CommentRepository::borrow_from(&state).save_comment(&comment);
-            let location = format!("{}/{}", Uri::borrow_from(&state), comment.id);
-            let headers = vec![("Location", location)].into_iter().collect(); // TODO: better way?
-            let resp_doc = CommentDisplayDoc::from_comment(&comment);
-            create_json_response_with_headers(&state, StatusCode::CREATED, headers, &resp_doc).unwrap()
+            let first_time_author = repository.is_first_time_email(doc.author_email.as_deref());
+            let comment = doc.to_comment(&settings.markdown, first_time_author);
+            if comment.text_html.is_empty() {
+                errors.add("text", "No visible text");
+            }
+            Some(comment)
+        };
+        let response = match comment {
+            Some(mut comment) if errors.is_empty() => {
+                if repository.is_moderated() {
+                    comment.status = ModerationStatus::Pending;
+                }
+                let parent = parent.flatten();
+                comment.parent_id = parent.as_ref().map(|p| p.id);
+                repository.save_comment(&comment);
+                if doc.notify_replies {
+                    repository.subscribe(&comment);
+                }
+                let location = format!("{}/{}", Uri::borrow_from(&state), comment.id);
+                let headers = vec![("Location", location)].into_iter().collect(); // TODO: better way?
+                let mut display_doc = CommentDisplayDoc::from_comment(&comment);
+                display_doc.parent_idh = parent.as_ref().map(|p| p.idh.clone());
+                display_doc.depth = repository.depth_of(&comment);
+                let edit_token = EditPolicy::borrow_from(&state).token_for(&comment);
+                let resp_doc = CommentCreatedDoc { comment: display_doc, edit_token };
+                create_json_response_with_headers(&state, StatusCode::CREATED, headers, &resp_doc).unwrap()
+            }
+            _ => Problem::invalid_fields(errors).to_response(&state)
         };
-        future::ok((state, response))
-    });
-    f.boxed()
+        (state, response)
+    })
 }
 
 
 
-#[derive(Deserialize, StateData, StaticResponseExtender)]
+#[derive(Serialize)]
+struct CommentCreatedDoc {
+    #[serde(flatten)]
+    comment: CommentDisplayDoc,
+    #[serde(rename = "editToken")]
+    edit_token: String,
+}
+
+
+#[derive(Deserialize, StateData)]
 struct CommentsQueryStringExtractor {
     p: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    order: Option<SortOrder>,
+    since: Option<DateTime<Utc>>,
 }
 
+bad_request_extender!(CommentsQueryStringExtractor, "invalid-query", "The query string is missing parameters or has invalid values");
+
+/// The largest number of threads returned for one page. Larger limits are reduced to it.
+const MAX_PAGE_LIMIT: usize = 100;
+
 #[derive(Serialize, Clone)]
 struct CommentListWrapper {
-    comments: Vec<CommentDisplayDoc>
+    comments: Vec<CommentDisplayDoc>,
+    next: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
 struct CommentDisplayDoc {
-    idh: u64,
+    idh: String,
     timestamp: DateTime<Utc>,
     path: String,
     #[serde(rename = "textHtml")]
@@ -167,36 +441,344 @@ struct CommentDisplayDoc {
     author_name: Option<String>,
     #[serde(rename = "authorGravatar")]
     author_gravatar: String,
-
+    #[serde(rename = "parentIdh")]
+    parent_idh: Option<String>,
+    depth: usize,
+    deleted: bool,
+    status: ModerationStatus,
+    edited: Option<DateTime<Utc>>,
 }
 
 impl CommentDisplayDoc {
     pub fn from_comment(comment: &Comment) -> CommentDisplayDoc {
         CommentDisplayDoc {
-            idh: comment.idh,
+            idh: comment.idh.clone(),
             timestamp: comment.timestamp,
             path: comment.path.clone(),
             text_html: comment.text_html.clone(),
             author_name: comment.author_name.clone(),
             author_gravatar: comment.author_gravatar.clone(),
+            parent_idh: None,
+            depth: 0,
+            deleted: comment.deleted,
+            status: comment.status,
+            edited: comment.edited,
         }
     }
+
+    pub fn from_threaded_comment(threaded: &ThreadedComment) -> CommentDisplayDoc {
+        let mut doc = CommentDisplayDoc::from_comment(&threaded.comment);
+        doc.parent_idh = threaded.parent_idh.clone();
+        doc.depth = threaded.depth;
+        doc
+    }
 }
 
 fn get_comments(mut state: State) -> (State, Response<Body>) {
     let query_param = CommentsQueryStringExtractor::take_from(&mut state);
     let repository = CommentRepository::borrow_from(&state);
 
-    let comments = match query_param.p {
-        Some(p) => repository.comments_for_path(&p),
+    let cursor = match query_param.cursor.as_deref().map(str::parse::<ThreadCursor>) {
+        Some(Err(message)) => {
+            let response = Problem::bad_request("invalid-cursor", &message).to_response(&state);
+            return (state, response);
+        }
+        Some(Ok(cursor)) => Some(cursor),
+        None => None
+    };
+    if query_param.limit == Some(0) {
+        let response = Problem::bad_request("invalid-query", "The limit must be at least 1").to_response(&state);
+        return (state, response);
+    }
+    let query = CommentQuery {
+        path: query_param.p.as_deref(),
+        since: query_param.since,
+        order: query_param.order.unwrap_or_default(),
+        limit: query_param.limit.map(|limit| limit.min(MAX_PAGE_LIMIT)),
+        cursor,
+    };
+    let page = repository.query_comments(&query);
+    let display_comments = page.comments.iter().map(CommentDisplayDoc::from_threaded_comment).collect();
+    let wrapper = CommentListWrapper { comments: display_comments, next: page.next.map(|c| c.to_string()) };
+    let response = create_json_response(&state, StatusCode::OK, &wrapper).unwrap();
+    (state, response)
+}
+
+
+#[derive(Deserialize, StateData)]
+struct UnsubscribeQueryStringExtractor {
+    token: String,
+}
+
+bad_request_extender!(UnsubscribeQueryStringExtractor, "invalid-query", "The query string is missing parameters or has invalid values");
+
+/// Removes a subscription. The link with the token is sent in every notification, so that
+/// commenters can unsubscribe with one click.
+fn get_unsubscribe(mut state: State) -> (State, Response<Body>) {
+    let query_param = UnsubscribeQueryStringExtractor::take_from(&mut state);
+    let signer = Signer::new(&ApiSettings::borrow_from(&state).secret);
+    let response = match Subscription::from_unsubscribe_token(&signer, &query_param.token) {
+        Some(subscription) => {
+            CommentRepository::borrow_from(&state).unsubscribe(&subscription);
+            let message = format!("<p>You will no longer be notified of new comments on {}.</p>", escape_markup(&subscription.path));
+            html_page_response(&state, StatusCode::OK, &message)
+        }
+        None => invalid_subscription_link_response(&state)
+    };
+    (state, response)
+}
+
+
+#[derive(Deserialize, StateData)]
+struct ConfirmQueryStringExtractor {
+    token: String,
+}
+
+bad_request_extender!(ConfirmQueryStringExtractor, "invalid-query", "The query string is missing parameters or has invalid values");
+
+/// Shows the page that the link in the mail asking to confirm a subscription leads to. Only
+/// submitting the form on it activates the subscription, so that mail scanners that follow
+/// links do not confirm subscriptions.
+fn get_confirm(mut state: State) -> (State, Response<Body>) {
+    let query_param = ConfirmQueryStringExtractor::take_from(&mut state);
+    let signer = Signer::new(&ApiSettings::borrow_from(&state).secret);
+    let response = match Subscription::from_confirm_token(&signer, &query_param.token) {
+        Some(subscription) => {
+            let body = format!("<p>Notify {} of new comments on {}?</p>\n\
+                                <form method=\"post\" action=\"?token={}\"><button type=\"submit\">Confirm</button></form>",
+                               escape_markup(&subscription.email), escape_markup(&subscription.path), escape_markup(&query_param.token));
+            html_page_response(&state, StatusCode::OK, &body)
+        }
+        None => invalid_subscription_link_response(&state)
+    };
+    (state, response)
+}
+
+fn post_confirm(mut state: State) -> (State, Response<Body>) {
+    let query_param = ConfirmQueryStringExtractor::take_from(&mut state);
+    let signer = Signer::new(&ApiSettings::borrow_from(&state).secret);
+    let subscription = Subscription::from_confirm_token(&signer, &query_param.token)
+        .filter(|s| CommentRepository::borrow_from(&state).confirm_subscription(s));
+    let response = match subscription {
+        Some(subscription) => {
+            let message = format!("<p>You will be notified of new comments on {}.</p>", escape_markup(&subscription.path));
+            html_page_response(&state, StatusCode::OK, &message)
+        }
+        None => invalid_subscription_link_response(&state)
+    };
+    (state, response)
+}
+
+fn invalid_subscription_link_response(state: &State) -> Response<Body> {
+    html_page_response(state, StatusCode::BAD_REQUEST, "<p>This link is invalid, or you have unsubscribed since.</p>")
+}
+
+
+#[derive(Deserialize, StateData)]
+struct ModerateQueryStringExtractor {
+    token: String,
+}
+
+bad_request_extender!(ModerateQueryStringExtractor, "invalid-query", "The query string is missing parameters or has invalid values");
+
+/// Shows the comment and a button to confirm the action in a moderation link. The action is
+/// only performed when the form is submitted, because mail clients and link scanners may open
+/// links in emails on their own.
+fn get_moderate(mut state: State) -> (State, Response<Body>) {
+    let query_param = ModerateQueryStringExtractor::take_from(&mut state);
+    let response = match moderation_target(&state, &query_param.token) {
+        Some((action, comment)) if is_moderated_already(action, &comment) => already_moderated_response(&state),
+        Some((action, comment)) => {
+            let body = format!("<p>{} this comment by {} on {}?</p>\n<blockquote>{}</blockquote>\n\
+                                <form method=\"post\" action=\"?token={}\"><button type=\"submit\">{}</button></form>",
+                               action.label(), escape_markup(comment.author_name.as_deref().unwrap_or("Anonymous")),
+                               escape_markup(&comment.path), comment.text_html, escape_markup(&query_param.token), action.label());
+            html_page_response(&state, StatusCode::OK, &body)
+        }
+        None => invalid_moderation_link_response(&state)
+    };
+    (state, response)
+}
+
+fn post_moderate(mut state: State) -> (State, Response<Body>) {
+    let query_param = ModerateQueryStringExtractor::take_from(&mut state);
+    let response = match moderation_target(&state, &query_param.token) {
+        Some((action, comment)) if is_moderated_already(action, &comment) => already_moderated_response(&state),
+        Some((action, comment)) => {
+            let repository = CommentRepository::borrow_from(&state);
+            let message = match action {
+                ModerationAction::Approve => {
+                    repository.set_status(&comment, ModerationStatus::Approved);
+                    "The comment has been approved."
+                }
+                ModerationAction::Delete => {
+                    repository.delete_comment(&comment);
+                    "The comment has been deleted."
+                }
+            };
+            html_page_response(&state, StatusCode::OK, &format!("<p>{}</p>", message))
+        }
+        None => invalid_moderation_link_response(&state)
+    };
+    (state, response)
+}
+
+fn moderation_target(state: &State, token: &str) -> Option<(ModerationAction, Comment)> {
+    let signer = Signer::new(&ApiSettings::borrow_from(state).secret);
+    let (action, id) = moderation_links::action_in(&signer, token, Utc::now())?;
+    let comment = CommentRepository::borrow_from(state).comment_with_id(id)?;
+    if comment.deleted {
+        return None;
+    }
+    Some((action, comment))
+}
+
+/// Only pending comments can be approved; otherwise an old link could approve a comment that
+/// was rejected in the meantime.
+fn is_moderated_already(action: ModerationAction, comment: &Comment) -> bool {
+    action == ModerationAction::Approve && comment.status != ModerationStatus::Pending
+}
+
+fn already_moderated_response(state: &State) -> Response<Body> {
+    html_page_response(state, StatusCode::CONFLICT, "<p>This comment has already been moderated.</p>")
+}
+
+fn invalid_moderation_link_response(state: &State) -> Response<Body> {
+    html_page_response(state, StatusCode::BAD_REQUEST, "<p>This link is invalid or has expired, or the comment has been deleted.</p>")
+}
+
+fn html_page_response(state: &State, status: StatusCode, body: &str) -> Response<Body> {
+    let page = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Quvyn</title></head>\n<body>\n{}\n</body></html>\n", body);
+    create_response(state, status, mime::TEXT_HTML_UTF_8, page)
+}
+
+
+#[derive(Deserialize, StateData)]
+struct CountsQueryStringExtractor {
+    #[serde(default)]
+    p: Vec<String>,
+}
+
+bad_request_extender!(CountsQueryStringExtractor, "invalid-query", "The query string is missing parameters or has invalid values");
+
+#[derive(Deserialize)]
+struct CountsPostDoc {
+    paths: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CountsWrapper {
+    counts: HashMap<String, usize>,
+}
+
+fn get_counts(mut state: State) -> (State, Response<Body>) {
+    let query_param = CountsQueryStringExtractor::take_from(&mut state);
+    let response = counts_response(&state, &query_param.p);
+    (state, response)
+}
+
+fn post_counts(state: State) -> Pin<Box<HandlerFuture>> {
+    let max_body_size = ApiSettings::borrow_from(&state).max_body_size;
+    with_json_body(state, max_body_size, |state, doc: CountsPostDoc| {
+        let response = counts_response(&state, &doc.paths);
+        (state, response)
+    })
+}
+
+fn counts_response(state: &State, paths: &[String]) -> Response<Body> {
+    let counts = CommentRepository::borrow_from(state).count_comments(paths);
+    create_json_response(state, StatusCode::OK, &CountsWrapper { counts }).unwrap()
+}
+
+
+const FEED_LENGTH: usize = 50;
+
+#[derive(Deserialize, StateData)]
+struct FeedQueryStringExtractor {
+    p: Option<String>,
+}
+
+bad_request_extender!(FeedQueryStringExtractor, "invalid-query", "The query string is missing parameters or has invalid values");
+
+fn get_atom_feed(state: State) -> (State, Response<Body>) {
+    feed_response(state, feed::atom, "application/atom+xml; charset=utf-8")
+}
+
+fn get_rss_feed(state: State) -> (State, Response<Body>) {
+    feed_response(state, feed::rss, "application/rss+xml; charset=utf-8")
+}
+
+fn feed_response(mut state: State, render: fn(&FeedInfo, &[Comment]) -> String, content_type: &str) -> (State, Response<Body>) {
+    let query_param = FeedQueryStringExtractor::take_from(&mut state);
+    let repository = CommentRepository::borrow_from(&state);
+    let settings = ApiSettings::borrow_from(&state);
+
+    let last_modified = repository.last_modified(query_param.p.as_deref());
+    let if_modified_since = HeaderMap::borrow_from(&state).get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    if let (Some(modified), Some(since)) = (last_modified, if_modified_since) {
+        if modified.timestamp() <= since.timestamp() {
+            let response = create_empty_response(&state, StatusCode::NOT_MODIFIED);
+            return (state, response);
+        }
+    }
+
+    let mut comments = match &query_param.p {
+        Some(p) => repository.comments_for_path(p),
         None => repository.all_comments()
     };
-    let display_comments = comments.iter().map(CommentDisplayDoc::from_comment).collect();
-    let wrapper = CommentListWrapper { comments: display_comments };
+    comments.retain(|c| !c.deleted);
+    comments.sort_unstable_by_key(|c| std::cmp::Reverse(c.timestamp));
+    comments.truncate(FEED_LENGTH);
+
+    let info = match &query_param.p {
+        Some(p) => FeedInfo { title: format!("Comments on {}", p), site_url: &settings.site_url, path: p, updated: last_modified },
+        None => FeedInfo { title: "Comments".to_owned(), site_url: &settings.site_url, path: "/", updated: last_modified },
+    };
+    let body = render(&info, &comments);
+    let mut response = create_response(&state, StatusCode::OK, content_type.parse::<mime::Mime>().unwrap(), body);
+    if let Some(modified) = last_modified {
+        let value = modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        response.headers_mut().insert(LAST_MODIFIED, value.parse().unwrap());
+    }
+    (state, response)
+}
+
+
+#[derive(Serialize)]
+struct ModerationListWrapper {
+    comments: Vec<Comment>
+}
+
+fn get_pending_comments(state: State) -> (State, Response<Body>) {
+    let repository = CommentRepository::borrow_from(&state);
+    let wrapper = ModerationListWrapper { comments: repository.pending_comments() };
     let response = create_json_response(&state, StatusCode::OK, &wrapper).unwrap();
     (state, response)
 }
 
+fn approve_comment(state: State) -> (State, Response<Body>) {
+    moderate_comment(state, ModerationStatus::Approved)
+}
+
+fn reject_comment(state: State) -> (State, Response<Body>) {
+    moderate_comment(state, ModerationStatus::Rejected)
+}
+
+fn moderate_comment(mut state: State, status: ModerationStatus) -> (State, Response<Body>) {
+    let p = IdParam::take_from(&mut state);
+    let repository = CommentRepository::borrow_from(&state);
+    let response = match repository.comment_with_id(p.id) {
+        Some(comment) => {
+            let comment = repository.set_status(&comment, status);
+            create_json_response(&state, StatusCode::OK, &comment).unwrap()
+        }
+        None => Problem::comment_not_found().to_response(&state)
+    };
+    (state, response)
+}
+
 
 #[derive(Deserialize)]
 struct CommentPreviewDoc {
@@ -204,12 +786,18 @@ struct CommentPreviewDoc {
 }
 
 fn post_preview(state: State) -> Pin<Box<HandlerFuture>> {
-    let f = take_json_body::<CommentPreviewDoc>(state).and_then(|(state, doc)| {
-        let body = md_to_html(&doc.text);
-        let response = create_response(&state, StatusCode::OK, mime::TEXT_HTML, body);
-        future::ok((state, response))
-    });
-    f.boxed()
+    let max_body_size = ApiSettings::borrow_from(&state).max_body_size;
+    with_json_body(state, max_body_size, |state, doc: CommentPreviewDoc| {
+        let settings = ApiSettings::borrow_from(&state);
+        let mut errors = ValidationErrors::default();
+        settings.limits.check_text(&doc.text, &mut errors);
+        let response = if errors.is_empty() {
+            create_response(&state, StatusCode::OK, mime::TEXT_HTML, md_to_html(&doc.text, &settings.markdown))
+        } else {
+            Problem::invalid_fields(errors).to_response(&state)
+        };
+        (state, response)
+    })
 }
 
 
@@ -230,10 +818,13 @@ mod tests {
             text: String::from("First comment"),
             author_name: Some(String::from("Joe Bloggs")),
             author_email: Some(String::from("joe@example.org")),
+            parent_idh: None,
+            notify_replies: false,
         };
-        let comment = dto.to_comment();
+        let comment = dto.to_comment(&MarkdownOptions::default(), false);
         assert_eq!(comment.path, "/a/");
         assert_eq!(comment.text, "First comment");
+        assert_eq!(comment.text_html, "<p>First comment</p>\n");
         assert_eq!(comment.author_name, Some(String::from("Joe Bloggs")));
         assert_eq!(comment.author_email, Some(String::from("joe@example.org")));
     }
@@ -248,5 +839,17 @@ mod tests {
         assert_eq!(dto.text_html, comment.text_html);
         assert_eq!(dto.author_name, comment.author_name);
     }
-}
 
+    #[test]
+    fn adds_parent_and_depth_to_dto_for_reply() {
+        let parent = Comment::new("/t/", "Question", None, None);
+        let mut reply = Comment::new("/t/", "Answer", None, None);
+        reply.parent_id = Some(parent.id);
+        let threaded = ThreadedComment { comment: reply, parent_idh: Some(parent.idh.clone()), depth: 1 };
+
+        let dto = CommentDisplayDoc::from_threaded_comment(&threaded);
+
+        assert_eq!(Some(parent.idh), dto.parent_idh);
+        assert_eq!(1, dto.depth);
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use native_tls::TlsConnector;
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+use crate::comment::{Comment, ModerationStatus};
+use crate::notifier::{Event, Notifier};
+use crate::signing::Signer;
+use crate::utils::{page_url, to_json};
+
+pub const SIGNATURE_HEADER: &str = "X-Quvyn-Signature";
+
+
+#[derive(Clone, Debug)]
+pub struct WebhookSettings
+{
+    pub url: String,
+    /// If set, the body of each request is signed with HMAC-SHA256 using this secret.
+    pub secret: Option<String>,
+    pub timeout: Duration,
+}
+
+impl WebhookSettings
+{
+    pub fn new(url: &str, secret: Option<String>) -> Self {
+        WebhookSettings {
+            url: url.to_owned(),
+            secret,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+
+/// The JSON document that is posted to the webhook.
+#[derive(Serialize)]
+struct WebhookPayload<'a>
+{
+    event: Event,
+    id: Uuid,
+    idh: &'a str,
+    path: &'a str,
+    url: String,
+    timestamp: DateTime<Utc>,
+    #[serde(rename = "authorName")]
+    author_name: Option<&'a str>,
+    text: &'a str,
+    #[serde(rename = "textHtml")]
+    text_html: &'a str,
+    status: ModerationStatus,
+}
+
+
+/// Posts a JSON document to a URL for every event. Requests that fail because of a network
+/// problem or a server error are reported as errors, so that the notification queue retries
+/// them; requests that the server rejects are only logged, as retrying would not help.
+pub struct WebhookNotifier
+{
+    settings: WebhookSettings,
+    site_url: Option<String>,
+    signer: Option<Signer>,
+}
+
+impl WebhookNotifier
+{
+    pub fn new(settings: WebhookSettings, site_url: &Option<String>) -> Self {
+        let signer = settings.secret.as_deref().map(Signer::new);
+        WebhookNotifier {
+            settings,
+            site_url: site_url.clone(),
+            signer,
+        }
+    }
+
+    fn payload(&self, event: Event, comment: &Comment) -> String {
+        to_json(WebhookPayload {
+            event,
+            id: comment.id,
+            idh: &comment.idh,
+            path: &comment.path,
+            url: format!("{}#comment-{}", page_url(&self.site_url, &comment.path), comment.idh),
+            timestamp: comment.timestamp,
+            author_name: comment.author_name.as_deref(),
+            text: &comment.text,
+            text_html: &comment.text_html,
+            status: comment.status,
+        })
+    }
+
+    fn post(&self, body: &str) -> Result<(), Box<ureq::Error>> {
+        let connector = TlsConnector::new().map_err(|e| ureq::Error::from(std::io::Error::other(e)))?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(self.settings.timeout)
+            .tls_connector(Arc::new(connector))
+            .build();
+        let mut request = agent.post(&self.settings.url)
+            .set("Content-Type", "application/json");
+        if let Some(signer) = &self.signer {
+            request = request.set(SIGNATURE_HEADER, &format!("sha256={}", signer.sign(body)));
+        }
+        request.send_string(body)?;
+        Ok(())
+    }
+}
+
+impl Notifier for WebhookNotifier
+{
+    fn name(&self) -> String {
+        format!("webhook:{}", self.settings.url)
+    }
+
+    fn notify(&self, event: Event, comment: &Comment) -> Result<(), String> {
+        // subscriptions are no change to the comment
+        if event == Event::Subscribed {
+            return Ok(());
+        }
+        let body = self.payload(event, comment);
+        match self.post(&body) {
+            Ok(()) => Ok(()),
+            Err(error) if is_transient(&error) => {
+                Err(format!("Error when calling webhook {}: {}", self.settings.url, error))
+            }
+            Err(error) => {
+                println!("Webhook {} rejected the notification: {}", self.settings.url, error);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn is_transient(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => *code >= 500 || *code == 429,
+        ureq::Error::Transport(_) => true,
+    }
+}
@@ -0,0 +1,84 @@
+extern crate quvyn;
+
+use std::fs;
+
+use chrono::{Duration, Utc};
+
+use quvyn::comment::Comment;
+use quvyn::digest::Digest;
+
+fn digest_file(test_name: &str) -> String {
+    let path = format!("var/it/digest/{}", test_name);
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    format!("{}/digest.json", path)
+}
+
+
+#[test]
+fn it_sends_collected_comments_once_interval_has_passed() {
+    let digest = Digest::open(&digest_file("it_sends_collected_comments_once_interval_has_passed"), Duration::hours(1)).unwrap();
+    let comment = Comment::new("/a/", "First", None, None);
+    digest.add(&comment).unwrap();
+    digest.add(&comment).unwrap();
+
+    assert_eq!(Ok(false), digest.send_if_due(Utc::now(), |_| panic!("not due yet")));
+
+    let mut sent = Vec::new();
+    let due = Utc::now() + Duration::hours(1);
+    assert_eq!(Ok(true), digest.send_if_due(due, |comments| { sent.extend_from_slice(comments); Ok(()) }));
+    assert_eq!(1, sent.len());
+    assert_eq!(Ok(false), digest.send_if_due(due + Duration::hours(1), |_| panic!("nothing to send")));
+}
+
+#[test]
+fn it_keeps_comments_when_sending_digest_fails() {
+    let digest = Digest::open(&digest_file("it_keeps_comments_when_sending_digest_fails"), Duration::hours(1)).unwrap();
+    digest.add(&Comment::new("/a/", "First", None, None)).unwrap();
+    let due = Utc::now() + Duration::hours(1);
+
+    assert!(digest.send_if_due(due, |_| Err("failed".to_owned())).is_err());
+
+    assert_eq!(Ok(true), digest.send_if_due(due, |comments| { assert_eq!(1, comments.len()); Ok(()) }));
+}
+
+#[test]
+fn it_keeps_digest_state_across_restarts() {
+    let filename = digest_file("it_keeps_digest_state_across_restarts");
+    let first = Digest::open(&filename, Duration::hours(1)).unwrap();
+    first.add(&Comment::new("/a/", "First", None, None)).unwrap();
+    let due = Utc::now() + Duration::hours(1);
+    first.send_if_due(due, |_| Ok(())).unwrap();
+    first.add(&Comment::new("/a/", "Second", None, None)).unwrap();
+
+    let second = Digest::open(&filename, Duration::hours(1)).unwrap();
+
+    assert_eq!(Ok(false), second.send_if_due(due, |_| panic!("digest was sent at this time")));
+    let mut texts = Vec::new();
+    second.send_if_due(due + Duration::hours(1), |comments| { texts.extend(comments.iter().map(|c| c.text.clone())); Ok(()) }).unwrap();
+    assert_eq!(vec!["Second"], texts);
+}
+
+#[test]
+fn it_keeps_comments_added_while_digest_is_sent() {
+    let digest = Digest::open(&digest_file("it_keeps_comments_added_while_digest_is_sent"), Duration::hours(1)).unwrap();
+    digest.add(&Comment::new("/a/", "First", None, None)).unwrap();
+    let due = Utc::now() + Duration::hours(1);
+
+    let sent = digest.send_if_due(due, |_| digest.add(&Comment::new("/a/", "Second", None, None)));
+
+    assert_eq!(Ok(true), sent);
+    let mut texts = Vec::new();
+    digest.send_if_due(due + Duration::hours(1), |comments| { texts.extend(comments.iter().map(|c| c.text.clone())); Ok(()) }).unwrap();
+    assert_eq!(vec!["Second"], texts);
+}
+
+#[test]
+fn it_reports_corrupt_digest_file() {
+    let filename = digest_file("it_reports_corrupt_digest_file");
+    fs::write(&filename, "{ \"last_sent\": ").unwrap();
+
+    let message = Digest::open(&filename, Duration::hours(1)).err().expect("expected an error");
+
+    assert!(message.starts_with("Failed to read digest from file var/it/digest/"));
+}
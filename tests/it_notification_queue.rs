@@ -0,0 +1,214 @@
+extern crate quvyn;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use quvyn::comment::Comment;
+use quvyn::notification_queue::{NotificationQueue, QueueSettings};
+use quvyn::notifier::{Event, Notifier};
+
+/// A notifier that reports the comments it is notified about on a channel. It fails a given
+/// number of times before it succeeds, and it can be made to wait for a signal before it
+/// does anything.
+struct TestNotifier {
+    delivered: Mutex<Sender<String>>,
+    failures: AtomicU32,
+    gate: Option<Mutex<Receiver<()>>>,
+}
+
+impl Notifier for TestNotifier {
+    fn name(&self) -> String {
+        "test".to_owned()
+    }
+
+    fn notify(&self, _event: Event, comment: &Comment) -> Result<(), String> {
+        if let Some(gate) = &self.gate {
+            gate.lock().unwrap().recv().map_err(|_| "gate closed".to_owned())?;
+        }
+        if self.failures.load(Ordering::SeqCst) > 0 {
+            self.failures.fetch_sub(1, Ordering::SeqCst);
+            return Err("failed on purpose".to_owned());
+        }
+        self.delivered.lock().unwrap().send(comment.text.clone()).unwrap();
+        Ok(())
+    }
+}
+
+fn notifier(failures: u32, gate: Option<Receiver<()>>) -> (Arc<TestNotifier>, Receiver<String>) {
+    let (sender, receiver) = mpsc::channel();
+    let notifier = TestNotifier { delivered: Mutex::new(sender), failures: AtomicU32::new(failures), gate: gate.map(Mutex::new) };
+    (Arc::new(notifier), receiver)
+}
+
+fn spool(test_name: &str) -> String {
+    let path = format!("var/it/notification_queue/{}", test_name);
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
+fn settings() -> QueueSettings {
+    QueueSettings { capacity: 10, max_attempts: 3, retry_interval: Duration::from_millis(10) }
+}
+
+fn spooled_files(path: &str) -> usize {
+    fs::read_dir(path).unwrap().count()
+}
+
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+
+#[test]
+fn it_delivers_notifications_in_background() {
+    let path = spool("it_delivers_notifications_in_background");
+    let (gate, gate_receiver) = mpsc::channel();
+    let (notifier, delivered) = notifier(0, Some(gate_receiver));
+    let queue = NotificationQueue::start(&path, vec![notifier], settings());
+
+    queue.notify(Event::Posted, &Comment::new("/1/", "First comment", None, None)).unwrap();
+
+    assert_eq!(1, spooled_files(&path));
+    gate.send(()).unwrap();
+    assert_eq!("First comment", delivered.recv_timeout(TIMEOUT).unwrap());
+    assert!(wait_until(|| spooled_files(&path) == 0));
+}
+
+#[test]
+fn it_retries_failed_notifications() {
+    let path = spool("it_retries_failed_notifications");
+    let (notifier, delivered) = notifier(2, None);
+    let queue = NotificationQueue::start(&path, vec![notifier.clone()], settings());
+
+    queue.notify(Event::Posted, &Comment::new("/1/", "First comment", None, None)).unwrap();
+
+    assert_eq!("First comment", delivered.recv_timeout(TIMEOUT).unwrap());
+    assert_eq!(0, notifier.failures.load(Ordering::SeqCst));
+    assert!(wait_until(|| spooled_files(&path) == 0));
+}
+
+#[test]
+fn it_gives_up_after_max_attempts() {
+    let path = spool("it_gives_up_after_max_attempts");
+    let (notifier, delivered) = notifier(5, None);
+    let queue = NotificationQueue::start(&path, vec![notifier.clone()], settings());
+
+    queue.notify(Event::Posted, &Comment::new("/1/", "First comment", None, None)).unwrap();
+
+    assert!(wait_until(|| notifier.failures.load(Ordering::SeqCst) == 2));
+    assert!(wait_until(|| spooled_files(&path) == 0));
+    assert!(delivered.try_recv().is_err());
+}
+
+#[test]
+fn it_delivers_spooled_notifications_after_restart() {
+    let path = spool("it_delivers_spooled_notifications_after_restart");
+    let (_gate, gate_receiver) = mpsc::channel();
+    let (stuck_notifier, _) = notifier(0, Some(gate_receiver));
+    let queue = NotificationQueue::start(&path, vec![stuck_notifier], settings());
+    queue.notify(Event::Posted, &Comment::new("/1/", "First comment", None, None)).unwrap();
+    queue.notify(Event::Posted, &Comment::new("/1/", "Second comment", None, None)).unwrap();
+    drop(queue);
+
+    let (notifier, delivered) = notifier(0, None);
+    let _queue = NotificationQueue::start(&path, vec![notifier], settings());
+
+    let mut texts = vec![delivered.recv_timeout(TIMEOUT).unwrap(), delivered.recv_timeout(TIMEOUT).unwrap()];
+    texts.sort();
+    assert_eq!(vec!["First comment", "Second comment"], texts);
+}
+
+#[test]
+fn it_delivers_notifications_that_did_not_fit_into_queue() {
+    let path = spool("it_delivers_notifications_that_did_not_fit_into_queue");
+    let (gate, gate_receiver) = mpsc::channel();
+    let (notifier, delivered) = notifier(0, Some(gate_receiver));
+    let settings = QueueSettings { capacity: 1, ..settings() };
+    let queue = NotificationQueue::start(&path, vec![notifier], settings);
+
+    for text in ["First comment", "Second comment", "Third comment"] {
+        queue.notify(Event::Posted, &Comment::new("/1/", text, None, None)).unwrap();
+    }
+    for _ in 0..3 {
+        gate.send(()).unwrap();
+    }
+
+    let mut texts: Vec<String> = (0..3).map(|_| delivered.recv_timeout(TIMEOUT).unwrap()).collect();
+    texts.sort();
+    assert_eq!(vec!["First comment", "Second comment", "Third comment"], texts);
+    assert!(wait_until(|| spooled_files(&path) == 0));
+    assert!(delivered.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn it_sets_aside_unreadable_spool_files() {
+    let path = spool("it_sets_aside_unreadable_spool_files");
+    fs::create_dir_all(&path).unwrap();
+    let filename = format!("{}/{}.json", path, uuid::Uuid::new_v4().as_simple());
+    fs::write(&filename, "{ not json").unwrap();
+
+    let (notifier, delivered) = notifier(0, None);
+    let queue = NotificationQueue::start(&path, vec![notifier], settings());
+    queue.notify(Event::Posted, &Comment::new("/1/", "First comment", None, None)).unwrap();
+
+    assert_eq!("First comment", delivered.recv_timeout(TIMEOUT).unwrap());
+    assert!(fs::metadata(&filename).is_err());
+    assert!(fs::metadata(format!("{}.corrupt", filename)).is_ok());
+}
+
+/// A notifier with two recipients, of which the second fails once.
+struct TwoRecipientNotifier {
+    delivered: Mutex<Sender<String>>,
+    failed: AtomicU32,
+}
+
+impl Notifier for TwoRecipientNotifier {
+    fn name(&self) -> String {
+        "two".to_owned()
+    }
+
+    fn notify(&self, _event: Event, _comment: &Comment) -> Result<(), String> {
+        Err("notifications must be sent to each recipient".to_owned())
+    }
+
+    fn recipients(&self, _event: Event, _comment: &Comment) -> Option<Vec<String>> {
+        Some(vec!["joe@example.org".to_owned(), "jane@example.org".to_owned()])
+    }
+
+    fn notify_recipient(&self, _event: Event, _comment: &Comment, recipient: &str) -> Result<(), String> {
+        if recipient == "jane@example.org" && self.failed.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err("failed on purpose".to_owned());
+        }
+        self.delivered.lock().unwrap().send(recipient.to_owned()).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn it_retries_notification_only_for_failed_recipient() {
+    let path = spool("it_retries_notification_only_for_failed_recipient");
+    let (sender, delivered) = mpsc::channel();
+    let notifier = Arc::new(TwoRecipientNotifier { delivered: Mutex::new(sender), failed: AtomicU32::new(0) });
+    let queue = NotificationQueue::start(&path, vec![notifier], settings());
+
+    queue.notify(Event::Posted, &Comment::new("/1/", "First comment", None, None)).unwrap();
+
+    let mut recipients = vec![delivered.recv_timeout(TIMEOUT).unwrap(), delivered.recv_timeout(TIMEOUT).unwrap()];
+    recipients.sort();
+    assert_eq!(vec!["jane@example.org", "joe@example.org"], recipients);
+    assert!(wait_until(|| spooled_files(&path) == 0));
+    assert!(delivered.try_recv().is_err());
+}
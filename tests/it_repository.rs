@@ -1,6 +1,11 @@
 extern crate quvyn;
 
+use std::fs;
+
+use serde_json::{json, Value};
+
 use quvyn::comment::Comment;
+use quvyn::markdown::MarkdownOptions;
 use quvyn::repository::CommentRepository;
 
 fn repo(test_name: &str, reset: bool) -> CommentRepository {
@@ -34,3 +39,97 @@ fn it_saving_a_comment_adds_it_to_the_list() {
     assert_eq!(1, comments.len());
     assert_eq!("Nice work!", comments[0].text);
 }
+
+#[test]
+fn it_deleting_comment_with_replies_leaves_tombstone() {
+    let repo1 = repo("it_deleting_comment_with_replies_leaves_tombstone", true);
+    let parent = Comment::new("/some-topic/", "Question", Some("Joe Bloggs"), None);
+    repo1.save_comment(&parent);
+    let mut reply = Comment::new("/some-topic/", "Answer", None, None);
+    reply.parent_id = Some(parent.id);
+    repo1.save_comment(&reply);
+
+    repo1.delete_comment(&parent);
+
+    let comments = repo1.comments_for_path("/some-topic/");
+    assert_eq!(2, comments.len());
+    assert!(comments[0].deleted);
+    assert_eq!("", comments[0].text);
+    assert_eq!(None, comments[0].author_name);
+    assert_eq!("Answer", comments[1].text);
+
+    let repo2 = repo("it_deleting_comment_with_replies_leaves_tombstone", false);
+    repo2.load_all_comments();
+    assert!(repo2.comment_with_id(parent.id).expect("expected tombstone").deleted);
+}
+
+#[test]
+fn it_deleting_last_reply_removes_tombstone() {
+    let repo1 = repo("it_deleting_last_reply_removes_tombstone", true);
+    let parent = Comment::new("/some-topic/", "Question", None, None);
+    repo1.save_comment(&parent);
+    let mut reply = Comment::new("/some-topic/", "Answer", None, None);
+    reply.parent_id = Some(parent.id);
+    repo1.save_comment(&reply);
+    repo1.delete_comment(&parent);
+
+    repo1.delete_comment(&reply);
+
+    assert_eq!(0, repo1.all_comments().len());
+    let repo2 = repo("it_deleting_last_reply_removes_tombstone", false);
+    repo2.load_all_comments();
+    assert_eq!(0, repo2.all_comments().len());
+}
+
+#[test]
+fn it_migrates_legacy_idh_and_keeps_it_resolvable() {
+    let repo1 = repo("it_migrates_legacy_idh_and_keeps_it_resolvable", true);
+    let comment = Comment::new("/some-topic/", "Nice work!", None, None);
+    let mut json = serde_json::to_value(&comment).unwrap();
+    json["idh"] = serde_json::json!(1234567890123456789u64);
+    json.as_object_mut().unwrap().remove("legacy_idh");
+    let filename = format!("var/it/repository/it_migrates_legacy_idh_and_keeps_it_resolvable/{}.json", comment.id.simple());
+    fs::write(&filename, json.to_string()).unwrap();
+
+    assert_eq!(1, repo1.migrate_idhs());
+    assert_eq!(0, repo1.migrate_idhs());
+
+    let stored: Value = serde_json::from_str(&fs::read_to_string(&filename).unwrap()).unwrap();
+    assert_eq!(json!(comment.idh), stored["idh"]);
+    assert_eq!(json!(1234567890123456789u64), stored["legacy_idh"]);
+
+    repo1.load_all_comments();
+    assert_eq!(Some(comment.id), repo1.comment_with_idh(&comment.idh).map(|c| c.id));
+    assert_eq!(Some(comment.id), repo1.comment_with_idh("1234567890123456789").map(|c| c.id));
+    assert!(repo1.comment_with_idh("42").is_none());
+}
+
+#[test]
+fn it_rerenders_changed_comments_only() {
+    let repo1 = repo("it_rerenders_changed_comments_only", true);
+    let heading = Comment::new("/some-topic/", "# Heading", None, None);
+    let plain = Comment::new("/some-topic/", "Nice work!", None, None);
+    let mut deleted = Comment::new("/some-topic/", "Gone", None, None);
+    deleted.mark_deleted();
+    for c in [&heading, &plain, &deleted] {
+        repo1.save_comment(c);
+    }
+    let options = MarkdownOptions { heading_offset: 0, ..MarkdownOptions::default() };
+    assert_eq!(2, repo1.count_stale_renders(&options));
+
+    let summary = repo1.rerender(&options, false);
+
+    assert_eq!(vec![heading.id], summary.changed.iter().map(|c| c.comment.id).collect::<Vec<_>>());
+    assert_eq!(heading.text_html, summary.changed[0].previous_html);
+    assert_eq!((0, 1, 0, 1), (summary.stamped, summary.outdated, summary.unchanged, summary.deleted));
+    let repo2 = repo("it_rerenders_changed_comments_only", false);
+    repo2.load_all_comments();
+    assert_eq!("<h1>Heading</h1>\n", repo2.comment_with_id(heading.id).unwrap().text_html);
+    assert_eq!(1, repo2.count_stale_renders(&options));
+
+    let summary = repo2.rerender(&options, true);
+    assert_eq!((0, 1, 1), (summary.changed.len(), summary.stamped, summary.unchanged));
+    let repo3 = repo("it_rerenders_changed_comments_only", false);
+    repo3.load_all_comments();
+    assert_eq!(0, repo3.count_stale_renders(&options));
+}
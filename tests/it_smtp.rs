@@ -0,0 +1,111 @@
+extern crate quvyn;
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+
+use quvyn::smtp::{self, SmtpSecurity, SmtpSettings};
+
+/// Starts a stand-in SMTP server on localhost that accepts a single connection. It replies to
+/// commands with the given reply for the command, or with 250. Returns the port and a handle
+/// that yields everything the client sent.
+fn smtp_server(replies: &'static [(&'static str, &'static str)]) -> (u16, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut transcript = String::new();
+        let mut in_data = false;
+        writer.write_all(b"220 localhost ESMTP stand-in\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            transcript.push_str(&line);
+            if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    writer.write_all(b"250 OK queued\r\n").unwrap();
+                }
+                continue;
+            }
+            let command = line.split([' ', ':', '\r']).next().unwrap().to_owned();
+            let reply = replies.iter().find(|(c, _)| *c == command).map(|(_, r)| *r);
+            let reply = match (reply, command.as_str()) {
+                (Some(r), _) => r,
+                (None, "EHLO") => "250-localhost\r\n250 AUTH PLAIN",
+                (None, "AUTH") => "235 Authenticated",
+                (None, "DATA") => "354 Go ahead",
+                (None, "QUIT") => "221 Bye",
+                (None, _) => "250 OK",
+            };
+            writer.write_all(format!("{}\r\n", reply).as_bytes()).unwrap();
+            in_data = command == "DATA" && reply.starts_with("354");
+            if command == "QUIT" || reply.starts_with('5') {
+                break;
+            }
+        }
+        transcript
+    });
+    (port, handle)
+}
+
+fn settings(port: u16, credentials: Option<(&str, &str)>) -> SmtpSettings {
+    SmtpSettings {
+        host: "localhost".to_owned(),
+        port,
+        security: SmtpSecurity::Plain,
+        credentials: credentials.map(|(u, p)| (u.to_owned(), p.to_owned())),
+    }
+}
+
+
+#[test]
+fn it_sends_mail_via_smtp() {
+    let (port, server) = smtp_server(&[]);
+
+    let message = "Subject: New comment posted\n\nHello\n.\nWorld";
+    smtp::send(&settings(port, None), "quvyn@example.org", "joe@example.org", message).unwrap();
+
+    let transcript = server.join().unwrap();
+    assert!(transcript.starts_with("EHLO localhost\r\n"));
+    assert!(transcript.contains("MAIL FROM:<quvyn@example.org>\r\n"));
+    assert!(transcript.contains("RCPT TO:<joe@example.org>\r\n"));
+    assert!(transcript.contains("Subject: New comment posted\r\n"));
+    assert!(transcript.contains("\r\nHello\r\n..\r\nWorld\r\n.\r\nQUIT\r\n"));
+    assert!(!transcript.contains("AUTH"));
+}
+
+#[test]
+fn it_authenticates_with_smtp_server() {
+    let (port, server) = smtp_server(&[]);
+
+    smtp::send(&settings(port, Some(("joe", "s3cret"))), "quvyn@example.org", "joe@example.org", "Body").unwrap();
+
+    let transcript = server.join().unwrap();
+    assert!(transcript.contains("AUTH PLAIN AGpvZQBzM2NyZXQ=\r\nMAIL FROM:"));
+}
+
+#[test]
+fn it_reports_rejected_recipient() {
+    let (port, server) = smtp_server(&[("RCPT", "550 No such user")]);
+
+    let result = smtp::send(&settings(port, None), "quvyn@example.org", "nobody@example.org", "Body");
+
+    assert_eq!("SMTP server returned: 550 No such user", result.unwrap_err().to_string());
+    assert!(!server.join().unwrap().contains("DATA"));
+}
+
+#[test]
+fn it_refuses_addresses_that_could_inject_commands() {
+    // nothing listens on the port, so the error shows that no connection was attempted
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+    let result = smtp::send(&settings(port, None), "quvyn@example.org", "joe@example.org>\r\nRCPT TO:<ann@example.org", "Body");
+
+    assert_eq!(std::io::ErrorKind::InvalidInput, result.unwrap_err().kind());
+}
@@ -0,0 +1,126 @@
+extern crate quvyn;
+
+use std::sync::Arc;
+
+use quvyn::comment::Comment;
+use quvyn::repository::CommentRepository;
+use quvyn::storage::{CommentStore, StorageType};
+
+fn store(storage: StorageType, test_name: &str, reset: bool) -> Arc<dyn CommentStore> {
+    let path = format!("var/it/storage/{:?}/{}", storage, test_name);
+    storage.open(&path, reset)
+}
+
+fn stores_and_loads_comments(storage: StorageType) {
+    let store1 = store(storage, "stores_and_loads_comments", true);
+    let mut comment = Comment::new("/some-topic/", "Nice work!", Some("Joe Bloggs"), None);
+    comment.parent_id = Some(uuid::Uuid::new_v4());
+    store1.save(&comment);
+
+    let store2 = store(storage, "stores_and_loads_comments", false);
+    let comments = store2.load_all();
+
+    assert_eq!(1, comments.len());
+    assert_eq!(comment.id, comments[0].id);
+    assert_eq!(comment.timestamp, comments[0].timestamp);
+    assert_eq!(comment.parent_id, comments[0].parent_id);
+    assert_eq!("Nice work!", comments[0].text);
+}
+
+fn finds_comments_by_id_and_path(storage: StorageType) {
+    let store = store(storage, "finds_comments_by_id_and_path", true);
+    let comment = Comment::new("/1/", "First comment", None, None);
+    store.save(&comment);
+    store.save(&Comment::new("/2/", "Second comment", None, None));
+
+    assert_eq!("First comment", store.find_by_id(comment.id).expect("expected comment").text);
+    assert!(store.find_by_id(uuid::Uuid::new_v4()).is_none());
+    assert_eq!(1, store.find_by_path("/2/").len());
+    assert_eq!(0, store.find_by_path("/3/").len());
+}
+
+fn saving_existing_comment_replaces_it(storage: StorageType) {
+    let store = store(storage, "saving_existing_comment_replaces_it", true);
+    let mut comment = Comment::new("/1/", "First comment", None, None);
+    store.save(&comment);
+    comment.mark_deleted();
+    store.save(&comment);
+
+    let comments = store.load_all();
+
+    assert_eq!(1, comments.len());
+    assert!(comments[0].deleted);
+}
+
+fn deletes_comment(storage: StorageType) {
+    let store = store(storage, "deletes_comment", true);
+    let comment = Comment::new("/1/", "First comment", None, None);
+    store.save(&comment);
+    store.save(&Comment::new("/1/", "Second comment", None, None));
+
+    store.delete(&comment);
+
+    assert_eq!(1, store.load_all().len());
+    assert!(store.find_by_id(comment.id).is_none());
+}
+
+fn repository_uses_store(storage: StorageType) {
+    let repo1 = CommentRepository::with_store(store(storage, "repository_uses_store", true));
+    repo1.save_comment(&Comment::new("/1/", "First comment", None, None));
+
+    let repo2 = CommentRepository::with_store(store(storage, "repository_uses_store", false));
+    repo2.load_all_comments();
+
+    assert_eq!(1, repo2.comments_for_path("/1/").len());
+}
+
+
+#[test]
+fn it_json_stores_and_loads_comments() {
+    stores_and_loads_comments(StorageType::Json);
+}
+
+#[test]
+fn it_sqlite_stores_and_loads_comments() {
+    stores_and_loads_comments(StorageType::Sqlite);
+}
+
+#[test]
+fn it_json_finds_comments_by_id_and_path() {
+    finds_comments_by_id_and_path(StorageType::Json);
+}
+
+#[test]
+fn it_sqlite_finds_comments_by_id_and_path() {
+    finds_comments_by_id_and_path(StorageType::Sqlite);
+}
+
+#[test]
+fn it_json_saving_existing_comment_replaces_it() {
+    saving_existing_comment_replaces_it(StorageType::Json);
+}
+
+#[test]
+fn it_sqlite_saving_existing_comment_replaces_it() {
+    saving_existing_comment_replaces_it(StorageType::Sqlite);
+}
+
+#[test]
+fn it_json_deletes_comment() {
+    deletes_comment(StorageType::Json);
+}
+
+#[test]
+fn it_sqlite_deletes_comment() {
+    deletes_comment(StorageType::Sqlite);
+}
+
+#[test]
+fn it_json_repository_uses_store() {
+    repository_uses_store(StorageType::Json);
+}
+
+#[test]
+fn it_sqlite_repository_uses_store() {
+    repository_uses_store(StorageType::Sqlite);
+}
@@ -0,0 +1,49 @@
+extern crate quvyn;
+
+use std::fs;
+
+use quvyn::subscriptions::{Subscription, Subscriptions};
+
+fn subscriptions_file(test_name: &str) -> String {
+    let path = format!("var/it/subscriptions/{}", test_name);
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    format!("{}/subscriptions.json", path)
+}
+
+
+#[test]
+fn it_keeps_subscriptions_in_file() {
+    let filename = subscriptions_file("it_keeps_subscriptions_in_file");
+    let subscriptions = Subscriptions::open(&filename).unwrap();
+    let confirmed = Subscription::new("/a/", "joe@example.org");
+    let unconfirmed = Subscription::new("/a/", "jane@example.org");
+    subscriptions.request(confirmed.clone());
+    subscriptions.confirm(&confirmed);
+    subscriptions.request(unconfirmed.clone());
+
+    let reopened = Subscriptions::open(&filename).unwrap();
+
+    assert_eq!(vec![confirmed], reopened.subscribers("/a/"));
+    assert!(reopened.is_unconfirmed(&unconfirmed));
+}
+
+#[test]
+fn it_reads_subscriptions_written_by_earlier_versions_as_confirmed() {
+    let filename = subscriptions_file("it_reads_subscriptions_written_by_earlier_versions_as_confirmed");
+    fs::write(&filename, r#"[{"path": "/a/", "email": "joe@example.org"}]"#).unwrap();
+
+    let subscriptions = Subscriptions::open(&filename).unwrap();
+
+    assert_eq!(vec![Subscription::new("/a/", "joe@example.org")], subscriptions.subscribers("/a/"));
+}
+
+#[test]
+fn it_reports_unreadable_subscriptions_file() {
+    let filename = subscriptions_file("it_reports_unreadable_subscriptions_file");
+    fs::write(&filename, "[{\"path\": ").unwrap();
+
+    let message = Subscriptions::open(&filename).err().expect("expected an error");
+
+    assert!(message.starts_with("Failed to read subscriptions from file var/it/subscriptions/"));
+}
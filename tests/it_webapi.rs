@@ -4,22 +4,53 @@ extern crate serde_json;
 
 use std::str;
 
+use chrono::{Duration, Utc};
 use gotham::plain::test::TestConnect;
 use gotham::test::{TestClient, TestResponse, TestServer};
 use serde_json::{json, Map, Value};
 use uuid::Uuid;
 
 use quvyn::{utils, webapi};
-use quvyn::comment::Comment;
+use quvyn::webapi::ApiSettings;
+use quvyn::comment::{Comment, ModerationStatus};
+use quvyn::markdown::{self, FirstTimeLinks, MarkdownOptions};
+use quvyn::moderation_links::{self, ModerationAction};
 use quvyn::repository::CommentRepository;
+use quvyn::signing::Signer;
+use quvyn::subscriptions::{Subscription, Subscriptions};
+use quvyn::validation::CommentLimits;
 
 fn repo(test_name: &str) -> CommentRepository {
     let path = format!("var/it/webapi/{}", test_name);
     CommentRepository::new(&path, true)
 }
 
+const ADMIN_TOKEN: &str = "s3cret";
+
+fn settings() -> ApiSettings {
+    ApiSettings {
+        app_path: "vue".to_owned(),
+        site_url: Some("https://example.org".to_owned()),
+        cors_origin: None,
+        admin_token: Some(ADMIN_TOKEN.to_owned()),
+        secret: "test-secret".to_owned(),
+        edit_window: Duration::minutes(15),
+        markdown: MarkdownOptions::default(),
+        limits: CommentLimits::default(),
+        max_body_size: 64 * 1024,
+    }
+}
+
 fn client(repo: CommentRepository) -> TestClient<TestServer, TestConnect> {
-    TestServer::new(webapi::router("vue", &None, repo)).unwrap().client()
+    client_with_settings(repo, settings())
+}
+
+fn client_with_settings(repo: CommentRepository, settings: ApiSettings) -> TestClient<TestServer, TestConnect> {
+    TestServer::new(webapi::router(&settings, repo)).unwrap().client()
+}
+
+fn admin_auth() -> String {
+    format!("Bearer {}", ADMIN_TOKEN)
 }
 
 fn url(path: &str) -> String {
@@ -38,6 +69,15 @@ macro_rules! jsome {
     };
 }
 
+fn assert_problem(response: TestResponse, status: u16, code: &str) -> Map<String, Value> {
+    assert_eq!(status, response.status());
+    assert_eq!("application/problem+json", response.headers().get("content-type").unwrap().to_str().unwrap());
+    let problem = as_json_obj(response);
+    assert_eq!(jsome!(code), problem.get("code"));
+    assert_eq!(jsome!(status), problem.get("status"));
+    problem
+}
+
 
 #[test]
 fn it_ping_api() {
@@ -63,10 +103,10 @@ fn it_post_new_comment_and_retrieve_by_id() {
 
     let location = response.headers().get("Location").expect("expected location header").to_str().unwrap();
 
-    let response = client.get(&url(location)).perform().unwrap();
+    let response = client.get(&url(location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
     assert_eq!(200, response.status());
     let obj = as_json_obj(response);
-    assert_eq!(jsome!(location.split('/').last().unwrap()), obj.get("id"));
+    assert_eq!(jsome!(location.split('/').next_back().unwrap()), obj.get("id"));
     assert_eq!(jsome!("Nice work!"), obj.get("text"));
 }
 
@@ -95,7 +135,43 @@ fn it_returns_400_when_posting_invalid_json() {
 
     let response = client.post(url("/comments"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
 
-    assert_eq!(400, response.status());
+    assert_problem(response, 400, "malformed-json");
+}
+
+#[test]
+fn it_returns_400_when_body_is_not_utf8() {
+    let client = client(repo("it_returns_400_when_body_is_not_utf8"));
+    let doc = b"{ \"path\": \"/1/\", \"text\": \"\xff\" }".to_vec();
+
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+
+    assert_problem(response, 400, "invalid-utf8");
+}
+
+#[test]
+fn it_returns_400_when_document_lacks_fields() {
+    let client = client(repo("it_returns_400_when_document_lacks_fields"));
+
+    let response = client.post(url("/comments"), r#"{ "path": "/1/" }"#.to_string(), mime::APPLICATION_JSON).perform().unwrap();
+
+    let problem = assert_problem(response, 400, "invalid-document");
+    assert!(problem.get("detail").unwrap().as_str().unwrap().contains("missing field `text`"));
+}
+
+#[test]
+fn it_returns_413_when_body_is_too_large() {
+    let client = client_with_settings(repo("it_returns_413_when_body_is_too_large"), ApiSettings { max_body_size: 100, ..settings() });
+    let doc = json!({ "path": "/1/", "text": "x".repeat(100) }).to_string();
+
+    let response = client.post(url("/comments"), doc.clone(), mime::APPLICATION_JSON).perform().unwrap();
+    assert_problem(response, 413, "body-too-large");
+
+    let response = client.post(url("/preview"), doc, mime::APPLICATION_JSON).perform().unwrap();
+    assert_problem(response, 413, "body-too-large");
+
+    let doc = json!({ "path": "/1/", "text": "Short" }).to_string();
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+    assert_eq!(201, response.status());
 }
 
 #[test]
@@ -108,6 +184,46 @@ fn it_returns_400_when_text_parses_into_nothing() {
 
     let response = client.post(url("/comments"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
 
+    let problem = assert_problem(response, 400, "invalid-fields");
+    assert_eq!(Some(&json!([{ "field": "text", "message": "No visible text" }])), problem.get("errors"));
+}
+
+#[test]
+fn it_lists_each_failing_field_when_rejecting_comment() {
+    let limits = CommentLimits { text_length: 20, name_length: 10, ..CommentLimits::default() };
+    let client = client_with_settings(repo("it_lists_each_failing_field_when_rejecting_comment"), ApiSettings { limits, ..settings() });
+    let doc = json!({
+        "path": "https://example.org/1/",
+        "text": "x".repeat(21),
+        "authorName": "Joe Bloggs from Example",
+        "authorEmail": "joe at example.org"
+    }).to_string();
+
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+
+    assert_eq!(400, response.status());
+    let errors = as_json_obj(response);
+    let fields: Vec<&str> = errors["errors"].as_array().unwrap().iter().map(|e| e["field"].as_str().unwrap()).collect();
+    assert_eq!(vec!["path", "text", "authorName", "authorEmail"], fields);
+    assert_eq!(jsome!("Must not be longer than 20 characters, but is 21"), errors["errors"][1].get("message"));
+}
+
+#[test]
+fn it_rejects_edit_and_preview_with_text_over_limit() {
+    let repo = repo("it_rejects_edit_and_preview_with_text_over_limit");
+    let comment = Comment::new("/1/", "Short", None, None);
+    repo.save_comment(&comment);
+    let limits = CommentLimits { text_length: 10, ..CommentLimits::default() };
+    let client = client_with_settings(repo, ApiSettings { limits, ..settings() });
+    let doc = json!({ "text": "Far too long" }).to_string();
+
+    let response = client.patch(url(&format!("/comments/{}", comment.id)), doc.clone(), mime::APPLICATION_JSON)
+        .with_header("Authorization", admin_auth().parse().unwrap())
+        .perform().unwrap();
+    assert_eq!(400, response.status());
+    assert_eq!(jsome!("text"), as_json_obj(response)["errors"][0].get("field"));
+
+    let response = client.post(url("/preview"), doc, mime::APPLICATION_JSON).perform().unwrap();
     assert_eq!(400, response.status());
 }
 
@@ -116,9 +232,30 @@ fn it_returns_404_for_non_existing_comment() {
     let client = client(repo("it_returns_404_for_non_existing_comments"));
     let location = format!("/comments/{}", Uuid::new_v4().as_simple());
 
-    let response = client.get(url(&location)).perform().unwrap();
+    let response = client.get(url(&location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
 
-    assert_eq!(404, response.status());
+    assert_problem(response, 404, "comment-not-found");
+}
+
+#[test]
+fn it_returns_400_for_invalid_comment_id() {
+    let client = client(repo("it_returns_400_for_invalid_comment_id"));
+
+    let response = client.get(url("/comments/not-a-uuid")).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+
+    assert_problem(response, 400, "invalid-path");
+}
+
+#[test]
+fn it_returns_problems_for_unknown_paths_and_methods() {
+    let client = client(repo("it_returns_problems_for_unknown_paths_and_methods"));
+
+    let response = client.get(url("/no-such-path")).perform().unwrap();
+    assert_problem(response, 404, "not-found");
+
+    let response = client.put(url("/comments"), "{}", mime::APPLICATION_JSON).perform().unwrap();
+    assert!(response.headers().get("allow").is_some());
+    assert_problem(response, 405, "method-not-allowed");
 }
 
 #[test]
@@ -129,10 +266,10 @@ fn it_delete_comment_and_not_found_by_id() {
     let client = client(repo);
     let location = format!("/comments/{}", comment.id.as_simple());
 
-    let response = client.delete(&url(&location)).perform().unwrap();
+    let response = client.delete(&url(&location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
     assert_eq!(200, response.status());
 
-    let response = client.get(&url(&location)).perform().unwrap();
+    let response = client.get(&url(&location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
     assert_eq!(404, response.status());
 }
 
@@ -141,11 +278,30 @@ fn it_returns_404_for_non_existing_comment_when_deleting() {
     let client = client(repo("it_returns_404_for_non_existing_comment_when_deleting"));
     let location = format!("/comments/{}", Uuid::new_v4().as_simple());
 
-    let response = client.delete(url(&location)).perform().unwrap();
+    let response = client.delete(url(&location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
 
     assert_eq!(404, response.status());
 }
 
+#[test]
+fn it_requires_admin_token_for_comment_details_and_delete() {
+    let repo = repo("it_requires_admin_token_for_comment_details_and_delete");
+    let comment = &Comment::new("/", "First comment", None, Some("joe@example.org"));
+    repo.save_comment(comment);
+    let client = client(repo);
+    let location = format!("/comments/{}", comment.id.as_simple());
+
+    let response = client.get(&url(&location)).perform().unwrap();
+    assert_eq!("Bearer", response.headers().get("WWW-Authenticate").unwrap().to_str().unwrap());
+    assert_problem(response, 401, "unauthorized");
+
+    let response = client.delete(&url(&location)).with_header("Authorization", "Bearer wrong".parse().unwrap()).perform().unwrap();
+    assert_eq!(401, response.status());
+
+    let response = client.get(&url("/comments")).perform().unwrap();
+    assert_eq!(200, response.status());
+}
+
 #[test]
 fn it_get_all_comments() {
     let repo = repo("it_get_all_comments");
@@ -219,6 +375,42 @@ fn it_previews_markdown() {
     assert_eq!("<p><em>foo</em></p>", body.trim());
 }
 
+#[test]
+fn it_strips_links_from_comments_by_first_time_authors() {
+    let repo = repo("it_strips_links_from_comments_by_first_time_authors");
+    let mut earlier = Comment::new("/1/", "Hello", None, Some("joe@example.org"));
+    earlier.timestamp = earlier.timestamp - Duration::minutes(5);
+    repo.save_comment(&earlier);
+    let mut settings = settings();
+    settings.markdown = MarkdownOptions { first_time_links: FirstTimeLinks::Strip, ..MarkdownOptions::default() };
+    let client = client_with_settings(repo, settings);
+
+    let doc = json!({ "path": "/1/", "text": "[site](https://example.org/)", "authorEmail": "ann@example.org" }).to_string();
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+    assert_eq!(jsome!("<p>site</p>\n"), as_json_obj(response).get("textHtml"));
+
+    let doc = json!({ "path": "/1/", "text": "[site](https://example.org/)", "authorEmail": "joe@example.org" }).to_string();
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+    let html = as_json_obj(response).get("textHtml").unwrap().as_str().unwrap().to_owned();
+    assert!(html.contains(r#"<a href="https://example.org/" rel="nofollow ugc noopener">"#), "{}", html);
+}
+
+#[test]
+fn it_uses_configured_markdown_options_for_preview_and_comments() {
+    let mut settings = settings();
+    settings.markdown = MarkdownOptions { extensions: markdown::parse_extensions("strikethrough").unwrap(), heading_offset: 1, ..MarkdownOptions::default() };
+    let client = client_with_settings(repo("it_uses_configured_markdown_options_for_preview_and_comments"), settings);
+    let text = "# Title\n\n~~gone~~";
+
+    let response = client.post(url("/preview"), json!({ "text": text }).to_string(), mime::APPLICATION_JSON).perform().unwrap();
+    let preview = response.read_utf8_body().unwrap();
+    assert_eq!("<h2>Title</h2>\n<p><del>gone</del></p>", preview.trim());
+
+    let doc = json!({ "path": "/1/", "text": text }).to_string();
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+    assert_eq!(jsome!(preview), as_json_obj(response).get("textHtml"));
+}
+
 #[test]
 fn it_previews_malformed_markdown_without_5xx_error() {
     let doc = r#"{ "text": "*_foo<a>*_</a>![bar]bar.jpg)" }"#;
@@ -239,3 +431,567 @@ fn it_handles_cors_preflight_requests() {
     let response = client.options(url("/preview")).perform().unwrap();
     assert_eq!(204, response.status());
 }
+
+#[test]
+fn it_post_reply_to_comment() {
+    let repo = repo("it_post_reply_to_comment");
+    let parent = Comment::new("/1/", "First comment", None, None);
+    repo.save_comment(&parent);
+    let client = client(repo);
+
+    let doc = json!({ "path": "/1/", "text": "A reply", "parentIdh": parent.idh }).to_string();
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+    assert_eq!(201, response.status());
+    let obj = as_json_obj(response);
+    assert_eq!(jsome!(parent.idh), obj.get("parentIdh"));
+    assert_eq!(jsome!(1), obj.get("depth"));
+
+    let response = client.get(&url("/comments?p=%2F1%2F")).perform().unwrap();
+    let comments = as_json_obj(response)
+        .get("comments").expect("expected comments field")
+        .as_array().unwrap().clone();
+    assert_eq!(2, comments.len());
+    assert_eq!(jsome!(0), comments[0].get("depth"));
+    assert_eq!(jsome!(parent.idh), comments[1].get("parentIdh"));
+}
+
+#[test]
+fn it_returns_400_when_replying_to_comment_on_other_path() {
+    let repo = repo("it_returns_400_when_replying_to_comment_on_other_path");
+    let parent = Comment::new("/1/", "First comment", None, None);
+    repo.save_comment(&parent);
+    let client = client(repo);
+
+    let doc = json!({ "path": "/2/", "text": "A reply", "parentIdh": parent.idh }).to_string();
+    let response = client.post(url("/comments"), doc, mime::APPLICATION_JSON).perform().unwrap();
+
+    assert_eq!(400, response.status());
+}
+
+#[test]
+fn it_holds_comments_for_moderation() {
+    let mut repo = repo("it_holds_comments_for_moderation");
+    repo.set_moderated(true);
+    let client = client(repo);
+
+    let doc = r#"{ "path": "/1/", "text": "Nice work!" }"#;
+    let response = client.post(url("/comments"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
+    assert_eq!(201, response.status());
+    assert_eq!(jsome!("pending"), as_json_obj(response).get("status"));
+
+    let response = client.get(&url("/comments?p=%2F1%2F")).perform().unwrap();
+    let comments = as_json_obj(response).get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(0, comments.len());
+}
+
+#[test]
+fn it_lists_and_approves_pending_comments() {
+    let repo = repo("it_lists_and_approves_pending_comments");
+    let mut comment = Comment::new("/1/", "Nice work!", None, None);
+    comment.status = ModerationStatus::Pending;
+    repo.save_comment(&comment);
+    let client = client(repo);
+
+    let response = client.get(&url("/moderation")).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+    let comments = as_json_obj(response).get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(1, comments.len());
+    assert_eq!(jsome!(comment.id.to_string()), comments[0].get("id"));
+
+    let location = format!("/moderation/{}/approve", comment.id.as_simple());
+    let response = client.post(url(&location), "", mime::TEXT_PLAIN)
+        .with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(jsome!("approved"), as_json_obj(response).get("status"));
+
+    let response = client.get(&url("/comments?p=%2F1%2F")).perform().unwrap();
+    let comments = as_json_obj(response).get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(1, comments.len());
+}
+
+#[test]
+fn it_rejects_pending_comment() {
+    let repo = repo("it_rejects_pending_comment");
+    let mut comment = Comment::new("/1/", "Buy now!", None, None);
+    comment.status = ModerationStatus::Pending;
+    repo.save_comment(&comment);
+    let client = client(repo);
+
+    let location = format!("/moderation/{}/reject", comment.id.as_simple());
+    let response = client.post(url(&location), "", mime::TEXT_PLAIN)
+        .with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+
+    let response = client.get(&url("/moderation")).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    let comments = as_json_obj(response).get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(0, comments.len());
+}
+
+#[test]
+fn it_requires_admin_token_for_moderation() {
+    let client = client(repo("it_requires_admin_token_for_moderation"));
+
+    let response = client.get(&url("/moderation")).perform().unwrap();
+    assert_eq!(401, response.status());
+
+    let response = client.get(&url("/moderation")).with_header("Authorization", "Bearer wrong".parse().unwrap()).perform().unwrap();
+    assert_eq!(401, response.status());
+
+    let location = format!("/moderation/{}/approve", Uuid::new_v4().as_simple());
+    let response = client.post(url(&location), "", mime::TEXT_PLAIN).perform().unwrap();
+    assert_eq!(401, response.status());
+}
+
+fn post_comment_for_edit_token(client: &TestClient<TestServer, TestConnect>) -> (String, String) {
+    let doc = r#"{ "path": "/1/", "text": "Nice wrok!" }"#;
+    let response = client.post(url("/comments"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
+    let location = response.headers().get("Location").unwrap().to_str().unwrap().to_owned();
+    let obj = as_json_obj(response);
+    let token = obj.get("editToken").expect("expected edit token").as_str().unwrap().to_owned();
+    (location, token)
+}
+
+#[test]
+fn it_author_can_edit_comment_with_edit_token() {
+    let client = client(repo("it_author_can_edit_comment_with_edit_token"));
+    let (location, token) = post_comment_for_edit_token(&client);
+
+    let doc = r#"{ "text": "Nice _work_!" }"#;
+    let response = client.build_request_with_body(gotham::hyper::Method::PATCH, url(&location), doc, mime::APPLICATION_JSON)
+        .with_header("Authorization", format!("Bearer {}", token).parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+    let obj = as_json_obj(response);
+    assert_eq!(jsome!("<p>Nice <em>work</em>!</p>\n"), obj.get("textHtml"));
+    assert!(obj.get("edited").unwrap().is_string());
+
+    let response = client.get(&url(&location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    assert_eq!(jsome!("Nice _work_!"), as_json_obj(response).get("text"));
+}
+
+#[test]
+fn it_holds_edited_comment_for_moderation_again() {
+    let mut repo = repo("it_holds_edited_comment_for_moderation_again");
+    repo.set_moderated(true);
+    let client = client(repo);
+    let (location, token) = post_comment_for_edit_token(&client);
+    let approve = format!("/moderation/{}/approve", location.rsplit('/').next().unwrap());
+    let response = client.post(url(&approve), "", mime::TEXT_PLAIN)
+        .with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(1, visible_comments(&client, "%2F1%2F"));
+
+    let doc = r#"{ "text": "Buy now!" }"#;
+    let response = client.build_request_with_body(gotham::hyper::Method::PATCH, url(&location), doc, mime::APPLICATION_JSON)
+        .with_header("Authorization", format!("Bearer {}", token).parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(0, visible_comments(&client, "%2F1%2F"));
+
+    let response = client.get(&url("/moderation")).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    let comments = as_json_obj(response).get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(1, comments.len());
+    assert_eq!(jsome!("Buy now!"), comments[0].get("text"));
+}
+
+#[test]
+fn it_author_can_delete_comment_with_edit_token() {
+    let client = client(repo("it_author_can_delete_comment_with_edit_token"));
+    let (location, token) = post_comment_for_edit_token(&client);
+
+    let response = client.delete(url(&location))
+        .with_header("Authorization", format!("Bearer {}", token).parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+
+    let response = client.get(&url("/comments")).perform().unwrap();
+    assert_eq!(0, as_json_obj(response).get("comments").unwrap().as_array().unwrap().len());
+}
+
+#[test]
+fn it_rejects_edit_with_wrong_token() {
+    let client = client(repo("it_rejects_edit_with_wrong_token"));
+    let (location, _) = post_comment_for_edit_token(&client);
+    let (_, other_token) = post_comment_for_edit_token(&client);
+
+    let doc = r#"{ "text": "Spam" }"#;
+    let response = client.build_request_with_body(gotham::hyper::Method::PATCH, url(&location), doc, mime::APPLICATION_JSON)
+        .with_header("Authorization", format!("Bearer {}", other_token).parse().unwrap()).perform().unwrap();
+    assert_eq!(401, response.status());
+
+    let response = client.delete(url(&location)).perform().unwrap();
+    assert_eq!(401, response.status());
+}
+
+#[test]
+fn it_rejects_edit_after_edit_window() {
+    let mut settings = settings();
+    settings.edit_window = Duration::minutes(0);
+    let client = client_with_settings(repo("it_rejects_edit_after_edit_window"), settings);
+    let (location, token) = post_comment_for_edit_token(&client);
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    let response = client.delete(url(&location))
+        .with_header("Authorization", format!("Bearer {}", token).parse().unwrap()).perform().unwrap();
+    assert_eq!(401, response.status());
+}
+
+#[test]
+fn it_keeps_edit_history_for_admins_only() {
+    let repo = repo("it_keeps_edit_history_for_admins_only");
+    let comment = Comment::new("/1/", "Frist!", None, None);
+    repo.save_comment(&comment);
+    let client = client(repo);
+    let location = format!("/comments/{}", comment.id.as_simple());
+
+    let doc = r#"{ "text": "First!" }"#;
+    let response = client.build_request_with_body(gotham::hyper::Method::PATCH, url(&location), doc, mime::APPLICATION_JSON)
+        .with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    assert_eq!(200, response.status());
+
+    let response = client.get(&url(&location)).with_header("Authorization", admin_auth().parse().unwrap()).perform().unwrap();
+    let obj = as_json_obj(response);
+    let revisions = obj.get("revisions").expect("expected revisions").as_array().unwrap().clone();
+    assert_eq!(1, revisions.len());
+    assert_eq!(jsome!("Frist!"), revisions[0].get("text"));
+    assert_eq!(jsome!("admin"), revisions[0].get("editor"));
+
+    let response = client.get(&url("/comments")).perform().unwrap();
+    let obj = as_json_obj(response).get("comments").unwrap().as_array().unwrap()[0].clone();
+    assert_eq!(jsome!("<p>First!</p>\n"), obj.get("textHtml"));
+    assert!(obj.get("edited").unwrap().is_string());
+    assert_eq!(None, obj.get("revisions"));
+}
+
+#[test]
+fn it_get_atom_feed_for_path() {
+    let repo = repo("it_get_atom_feed_for_path");
+    let comment = Comment::new("/1/", "First comment", Some("Joe Bloggs"), None);
+    repo.save_comment(&comment);
+    repo.save_comment(&Comment::new("/2/", "Second comment", None, None));
+    let client = client(repo);
+
+    let response = client.get(&url("/feed.atom?p=%2F1%2F")).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    assert_eq!("application/atom+xml; charset=utf-8", response.headers().get("content-type").unwrap().to_str().unwrap());
+    assert!(response.headers().get("last-modified").is_some());
+    let body = response.read_utf8_body().unwrap();
+    assert_eq!(1, body.matches("<entry>").count());
+    assert!(body.contains(&format!("<id>urn:uuid:{}</id>", comment.id)));
+    assert!(body.contains(&format!("https://example.org/1/#comment-{}", comment.idh)));
+}
+
+#[test]
+fn it_get_rss_feed_for_site() {
+    let repo = repo("it_get_rss_feed_for_site");
+    repo.save_comment(&Comment::new("/1/", "First comment", None, None));
+    repo.save_comment(&Comment::new("/2/", "Second comment", None, None));
+    let client = client(repo);
+
+    let response = client.get(&url("/feed.rss")).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    assert_eq!("application/rss+xml; charset=utf-8", response.headers().get("content-type").unwrap().to_str().unwrap());
+    let body = response.read_utf8_body().unwrap();
+    assert_eq!(2, body.matches("<item>").count());
+}
+
+#[test]
+fn it_returns_304_for_unmodified_feed() {
+    let repo = repo("it_returns_304_for_unmodified_feed");
+    repo.save_comment(&Comment::new("/1/", "First comment", None, None));
+    let client = client(repo);
+
+    let response = client.get(&url("/feed.atom")).perform().unwrap();
+    let last_modified = response.headers().get("last-modified").unwrap().clone();
+
+    let response = client.get(&url("/feed.atom")).with_header("If-Modified-Since", last_modified).perform().unwrap();
+    assert_eq!(304, response.status());
+}
+
+#[test]
+fn it_bases_page_feed_last_modified_on_its_comments() {
+    let repo = repo("it_bases_page_feed_last_modified_on_its_comments");
+    let mut comment = Comment::new("/1/", "First comment", None, None);
+    comment.timestamp = comment.timestamp - Duration::days(1);
+    repo.save_comment(&comment);
+    repo.save_comment(&Comment::new("/2/", "Second comment", None, None));
+    let client = client(repo);
+
+    let response = client.get(&url("/feed.atom?p=/1/")).perform().unwrap();
+    let last_modified = response.headers().get("last-modified").unwrap().to_str().unwrap().to_owned();
+    assert_eq!(comment.timestamp.format("%a, %d %b %Y %H:%M:%S GMT").to_string(), last_modified);
+    assert!(response.read_utf8_body().unwrap().contains(&format!("<updated>{}</updated>", comment.timestamp.to_rfc3339())));
+
+    let response = client.get(&url("/feed.atom?p=/3/")).perform().unwrap();
+    assert!(response.headers().get("last-modified").is_none());
+}
+
+#[test]
+fn it_pages_through_comments() {
+    let repo = repo("it_pages_through_comments");
+    for (i, minutes) in [30, 20, 10].iter().enumerate() {
+        let mut comment = Comment::new("/1/", &format!("Comment {}", i + 1), None, None);
+        comment.timestamp = comment.timestamp - Duration::minutes(*minutes);
+        repo.save_comment(&comment);
+    }
+    let client = client(repo);
+
+    let response = client.get(&url("/comments?p=%2F1%2F&limit=2&order=desc")).perform().unwrap();
+    assert_eq!(200, response.status());
+    let obj = as_json_obj(response);
+    let comments = obj.get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(2, comments.len());
+    assert_eq!(jsome!("<p>Comment 3</p>\n"), comments[0].get("textHtml"));
+    let next = obj.get("next").unwrap().as_str().expect("expected cursor").to_owned();
+
+    let response = client.get(&url(&format!("/comments?p=%2F1%2F&limit=2&order=desc&cursor={}", next))).perform().unwrap();
+    let obj = as_json_obj(response);
+    let comments = obj.get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(1, comments.len());
+    assert_eq!(jsome!("<p>Comment 1</p>\n"), comments[0].get("textHtml"));
+    assert_eq!(Some(&Value::Null), obj.get("next"));
+}
+
+#[test]
+fn it_get_comments_since_timestamp() {
+    let repo = repo("it_get_comments_since_timestamp");
+    let mut comment = Comment::new("/1/", "Old comment", None, None);
+    comment.timestamp = comment.timestamp - Duration::hours(2);
+    repo.save_comment(&comment);
+    repo.save_comment(&Comment::new("/1/", "New comment", None, None));
+    let client = client(repo);
+
+    let since = (comment.timestamp + Duration::hours(1)).to_rfc3339().replace('+', "%2B");
+    let response = client.get(&url(&format!("/comments?since={}", since))).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    let comments = as_json_obj(response).get("comments").unwrap().as_array().unwrap().clone();
+    assert_eq!(1, comments.len());
+    assert_eq!(jsome!("<p>New comment</p>\n"), comments[0].get("textHtml"));
+}
+
+#[test]
+fn it_returns_400_for_invalid_query_parameters() {
+    let client = client(repo("it_returns_400_for_invalid_query_parameters"));
+
+    let response = client.get(&url("/comments?order=random")).perform().unwrap();
+    assert_problem(response, 400, "invalid-query");
+
+    let response = client.get(&url("/comments?cursor=foo")).perform().unwrap();
+    assert_problem(response, 400, "invalid-cursor");
+
+    let response = client.get(&url("/comments?limit=0")).perform().unwrap();
+    assert_problem(response, 400, "invalid-query");
+}
+
+#[test]
+fn it_caps_page_limit() {
+    let repo = repo("it_caps_page_limit");
+    for i in 0..101 {
+        repo.save_comment(&Comment::new("/1/", &format!("Comment {}", i), None, None));
+    }
+    let client = client(repo);
+
+    let response = client.get(&url("/comments?p=%2F1%2F&limit=1000")).perform().unwrap();
+    let obj = as_json_obj(response);
+    assert_eq!(100, obj.get("comments").unwrap().as_array().unwrap().len());
+    assert!(obj.get("next").unwrap().is_string());
+}
+
+#[test]
+fn it_get_counts_for_paths() {
+    let repo = repo("it_get_counts_for_paths");
+    repo.save_comment(&Comment::new("/1/", "First comment", None, None));
+    repo.save_comment(&Comment::new("/2/", "Second comment", None, None));
+    repo.save_comment(&Comment::new("/2/", "Third comment", None, None));
+    let client = client(repo);
+
+    let response = client.get(&url("/counts?p=%2F1%2F&p=%2F2%2F&p=%2F3%2F")).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    let counts = as_json_obj(response).get("counts").expect("expected counts").clone();
+    assert_eq!(json!({ "/1/": 1, "/2/": 2, "/3/": 0 }), counts);
+}
+
+#[test]
+fn it_post_counts_for_paths() {
+    let repo = repo("it_post_counts_for_paths");
+    repo.save_comment(&Comment::new("/1/", "First comment", None, None));
+    let client = client(repo);
+
+    let doc = r#"{ "paths": ["/1/", "/2/"] }"#;
+    let response = client.post(url("/counts"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    let counts = as_json_obj(response).get("counts").expect("expected counts").clone();
+    assert_eq!(json!({ "/1/": 1, "/2/": 0 }), counts);
+}
+
+#[test]
+fn it_get_comment_by_permalink() {
+    let repo = repo("it_get_comment_by_permalink");
+    let parent = Comment::new("/1/", "First comment", None, None);
+    let mut reply = Comment::new("/1/", "A reply", None, None);
+    reply.parent_id = Some(parent.id);
+    reply.legacy_idh = Some(1234567890);
+    repo.save_comment(&parent);
+    repo.save_comment(&reply);
+    let client = client(repo);
+
+    let response = client.get(&url(&format!("/permalinks/{}", reply.idh))).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    let obj = as_json_obj(response);
+    assert_eq!(jsome!(reply.idh), obj.get("idh"));
+    assert_eq!(jsome!("/1/"), obj.get("path"));
+    assert_eq!(jsome!(parent.idh), obj.get("parentIdh"));
+    assert_eq!(jsome!(1), obj.get("depth"));
+
+    let response = client.get(&url("/permalinks/1234567890")).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(jsome!(reply.idh), as_json_obj(response).get("idh"));
+
+    let response = client.get(&url("/permalinks/unknown")).perform().unwrap();
+    assert_eq!(404, response.status());
+}
+
+#[test]
+fn it_subscribes_to_replies_and_unsubscribes_with_token() {
+    let mut repo = repo("it_subscribes_to_replies_and_unsubscribes_with_token");
+    let subscriptions = Subscriptions::default();
+    repo.set_subscriptions(subscriptions.clone());
+    let client = client(repo);
+
+    let doc = r#"{ "path": "/1/", "text": "Let me know", "authorEmail": "Joe@example.org", "notifyReplies": true }"#;
+    let response = client.post(url("/comments"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
+
+    assert_eq!(201, response.status());
+    let subscription = Subscription::new("/1/", "joe@example.org");
+    assert!(subscriptions.is_unconfirmed(&subscription));
+    assert!(subscriptions.subscribers("/1/").is_empty());
+
+    let token = subscription.confirm_token(&Signer::new("test-secret"));
+    let response = client.get(&url(&format!("/confirm?token={}", token))).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert!(subscriptions.subscribers("/1/").is_empty());
+    let response = client.post(&url(&format!("/confirm?token={}", token)), "", mime::APPLICATION_WWW_FORM_URLENCODED).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(vec![subscription.clone()], subscriptions.subscribers("/1/"));
+
+    let token = subscription.unsubscribe_token(&Signer::new("test-secret"));
+    let response = client.get(&url(&format!("/unsubscribe?token={}", token))).perform().unwrap();
+
+    assert_eq!(200, response.status());
+    assert!(subscriptions.subscribers("/1/").is_empty());
+}
+
+#[test]
+fn it_does_not_confirm_subscription_that_was_not_requested() {
+    let mut repo = repo("it_does_not_confirm_subscription_that_was_not_requested");
+    let subscriptions = Subscriptions::default();
+    repo.set_subscriptions(subscriptions.clone());
+    let client = client(repo);
+    let token = Subscription::new("/1/", "joe@example.org").confirm_token(&Signer::new("test-secret"));
+
+    let response = client.post(&url(&format!("/confirm?token={}", token)), "", mime::APPLICATION_WWW_FORM_URLENCODED).perform().unwrap();
+
+    assert_eq!(400, response.status());
+    assert!(subscriptions.subscribers("/1/").is_empty());
+}
+
+#[test]
+fn it_rejects_reply_notifications_without_email() {
+    let client = client(repo("it_rejects_reply_notifications_without_email"));
+
+    let doc = r#"{ "path": "/1/", "text": "Let me know", "notifyReplies": true }"#;
+    let response = client.post(url("/comments"), doc.to_string(), mime::APPLICATION_JSON).perform().unwrap();
+
+    assert_eq!(400, response.status());
+}
+
+#[test]
+fn it_rejects_unsubscribe_with_forged_token() {
+    let client = client(repo("it_rejects_unsubscribe_with_forged_token"));
+    let token = Subscription::new("/1/", "joe@example.org").unsubscribe_token(&Signer::new("other-secret"));
+
+    let response = client.get(&url(&format!("/unsubscribe?token={}", token))).perform().unwrap();
+
+    assert_eq!(400, response.status());
+    assert_eq!("text/html; charset=utf-8", response.headers().get("content-type").unwrap().to_str().unwrap());
+}
+
+fn visible_comments(client: &TestClient<TestServer, TestConnect>, encoded_path: &str) -> usize {
+    let response = client.get(&url(&format!("/comments?p={}", encoded_path))).perform().unwrap();
+    as_json_obj(response).get("comments").unwrap().as_array().unwrap().len()
+}
+
+#[test]
+fn it_approves_comment_with_moderation_link_after_confirmation() {
+    let repo = repo("it_approves_comment_with_moderation_link_after_confirmation");
+    let mut comment = Comment::new("/1/", "Nice work!", None, None);
+    comment.status = ModerationStatus::Pending;
+    repo.save_comment(&comment);
+    let client = client(repo);
+    let token = moderation_links::token(&Signer::new("test-secret"), ModerationAction::Approve, comment.id, Utc::now() + Duration::days(1));
+
+    let response = client.get(&url(&format!("/moderate?token={}", token))).perform().unwrap();
+    assert_eq!(200, response.status());
+    let body = response.read_utf8_body().unwrap();
+    assert!(body.contains("<form method=\"post\""));
+    assert!(body.contains("Nice work!"));
+    assert_eq!(0, visible_comments(&client, "%2F1%2F"));
+
+    let response = client.post(url(&format!("/moderate?token={}", token)), "", mime::TEXT_PLAIN).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(1, visible_comments(&client, "%2F1%2F"));
+}
+
+#[test]
+fn it_does_not_approve_rejected_comment_with_moderation_link() {
+    let repo = repo("it_does_not_approve_rejected_comment_with_moderation_link");
+    let mut comment = Comment::new("/1/", "Buy stuff!", None, None);
+    comment.status = ModerationStatus::Rejected;
+    repo.save_comment(&comment);
+    let client = client(repo);
+    let token = moderation_links::token(&Signer::new("test-secret"), ModerationAction::Approve, comment.id, Utc::now() + Duration::days(1));
+
+    let response = client.get(&url(&format!("/moderate?token={}", token))).perform().unwrap();
+    assert_eq!(409, response.status());
+    assert!(response.read_utf8_body().unwrap().contains("already been moderated"));
+
+    let response = client.post(url(&format!("/moderate?token={}", token)), "", mime::TEXT_PLAIN).perform().unwrap();
+    assert_eq!(409, response.status());
+    assert_eq!(0, visible_comments(&client, "%2F1%2F"));
+}
+
+#[test]
+fn it_deletes_comment_with_moderation_link() {
+    let repo = repo("it_deletes_comment_with_moderation_link");
+    let comment = Comment::new("/1/", "Buy stuff!", None, None);
+    repo.save_comment(&comment);
+    let client = client(repo);
+    let token = moderation_links::token(&Signer::new("test-secret"), ModerationAction::Delete, comment.id, Utc::now() + Duration::days(1));
+
+    let response = client.post(url(&format!("/moderate?token={}", token)), "", mime::TEXT_PLAIN).perform().unwrap();
+    assert_eq!(200, response.status());
+    assert_eq!(0, visible_comments(&client, "%2F1%2F"));
+
+    let response = client.get(&url(&format!("/moderate?token={}", token))).perform().unwrap();
+    assert_eq!(400, response.status());
+}
+
+#[test]
+fn it_rejects_expired_or_forged_moderation_links() {
+    let repo = repo("it_rejects_expired_or_forged_moderation_links");
+    let mut comment = Comment::new("/1/", "Nice work!", None, None);
+    comment.status = ModerationStatus::Pending;
+    repo.save_comment(&comment);
+    let client = client(repo);
+    let expired = moderation_links::token(&Signer::new("test-secret"), ModerationAction::Approve, comment.id, Utc::now() - Duration::days(1));
+    let forged = moderation_links::token(&Signer::new("other-secret"), ModerationAction::Approve, comment.id, Utc::now() + Duration::days(1));
+
+    for token in [expired, forged] {
+        let response = client.post(url(&format!("/moderate?token={}", token)), "", mime::TEXT_PLAIN).perform().unwrap();
+        assert_eq!(400, response.status());
+    }
+    assert_eq!(0, visible_comments(&client, "%2F1%2F"));
+}
@@ -0,0 +1,112 @@
+extern crate quvyn;
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use quvyn::comment::Comment;
+use quvyn::notifier::{Event, Notifier};
+use quvyn::webhook::{WebhookNotifier, WebhookSettings};
+
+struct Request {
+    head: String,
+    body: String,
+}
+
+/// Starts a stand-in HTTP server on localhost that answers one request for each of the given
+/// status codes. Returns the URL and a handle that yields the requests it received.
+fn http_server(statuses: &'static [u16]) -> (String, JoinHandle<Vec<Request>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for status in statuses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+            let length: usize = head.lines()
+                .find_map(|l| l.to_lowercase().strip_prefix("content-length: ").map(|v| v.parse().unwrap()))
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            requests.push(Request { head, body: String::from_utf8(body).unwrap() });
+            let mut writer = stream;
+            write!(writer, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+        }
+        requests
+    });
+    (url, handle)
+}
+
+fn notifier(url: &str, secret: Option<&str>) -> WebhookNotifier {
+    let settings = WebhookSettings::new(url, secret.map(|s| s.to_owned()));
+    WebhookNotifier::new(settings, &Some("https://example.org".to_owned()))
+}
+
+fn header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request.head.lines()
+        .find(|l| l.to_lowercase().starts_with(&format!("{}: ", name.to_lowercase())))
+        .map(|l| &l[name.len() + 2..])
+}
+
+
+#[test]
+fn it_posts_signed_payload_to_webhook() {
+    let (url, server) = http_server(&[200]);
+    let comment = Comment::new("/1/", "First comment", Some("Joe Bloggs"), Some("joe@example.org"));
+
+    notifier(&url, Some("hook-secret")).notify(Event::Posted, &comment).unwrap();
+
+    let requests = server.join().unwrap();
+    assert!(requests[0].head.starts_with("POST /hook HTTP/1.1"));
+    assert_eq!(Some("application/json"), header(&requests[0], "Content-Type"));
+    let payload: Value = serde_json::from_str(&requests[0].body).unwrap();
+    assert_eq!("posted", payload["event"]);
+    assert_eq!(comment.idh, payload["idh"]);
+    assert_eq!(format!("https://example.org/1/#comment-{}", comment.idh), payload["url"]);
+    assert_eq!("Joe Bloggs", payload["authorName"]);
+    assert!(payload.get("authorEmail").is_none());
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"hook-secret").unwrap();
+    mac.update(requests[0].body.as_bytes());
+    let expected: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(Some(format!("sha256={}", expected).as_str()), header(&requests[0], "X-Quvyn-Signature"));
+}
+
+#[test]
+fn it_reports_server_error_so_that_webhook_is_retried() {
+    let (url, server) = http_server(&[503, 200]);
+    let notifier = notifier(&url, None);
+    let comment = Comment::new("/1/", "First comment", None, None);
+
+    let result = notifier.notify(Event::Deleted, &comment);
+    assert!(result.unwrap_err().contains("503"));
+    notifier.notify(Event::Deleted, &comment).unwrap();
+
+    let requests = server.join().unwrap();
+    assert_eq!(2, requests.len());
+    assert_eq!(requests[0].body, requests[1].body);
+    assert_eq!(None, header(&requests[0], "X-Quvyn-Signature"));
+}
+
+#[test]
+fn it_does_not_retry_webhook_rejected_by_server() {
+    let (url, server) = http_server(&[400]);
+
+    let result = notifier(&url, None).notify(Event::Approved, &Comment::new("/1/", "First comment", None, None));
+
+    assert!(result.is_ok());
+    assert_eq!(1, server.join().unwrap().len());
+}
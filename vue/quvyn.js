@@ -16,20 +16,28 @@ Vue.component('qv-comment-section', {
             })
                 .then(response => {
                     location = response.headers.get("location")
-                    return response.json()
+                    return response.json().then(json => ({ok: response.ok, json: json}))
                 })
-                .then(json => {
+                .then(({ok, json}) => {
+                    if (!ok) {
+                        this.postError = json.errors ? json.errors.map(e => e.message + " (" + e.field + ")").join(". ") : json.detail
+                        return
+                    }
+                    this.postError = null
                     localStorage.setItem(json.idh, location)
+                    localStorage.setItem(json.idh + '-token', json.editToken)
                     this.comments.push(json)
                 })
         },
         deleteComment(idh) {
             let location = localStorage.getItem(idh)
-            if (!location) {
+            let token = localStorage.getItem(idh + '-token')
+            if (!location || !token) {
                 return
             }
             fetch(this.baseurl + location, {
                 method: 'DELETE',
+                headers: {'Authorization': 'Bearer ' + token}
             })
                 .then(response => {
                     if (response.status === 200) {
@@ -60,7 +68,8 @@ Vue.component('qv-comment-section', {
     data() {
         return {
             comments: [],
-            preview: ''
+            preview: '',
+            postError: null
         }
     },
     template: `
@@ -68,7 +77,7 @@ Vue.component('qv-comment-section', {
             <qv-heading :comments="comments"></qv-heading>
             <p v-if="this.comments.length === 0">No comments yet</p>
             <qv-list :comments="comments" @delete-comment="deleteComment"></qv-list>
-            <qv-comment-editor :preview="preview" @post-comment="postComment" @get-preview="getPreview"></qv-comment-editor>
+            <qv-comment-editor :preview="preview" :post-error="postError" @post-comment="postComment" @get-preview="getPreview"></qv-comment-editor>
         </section>
     `
 })
@@ -149,6 +158,10 @@ Vue.component('qv-comment-editor', {
         preview: {
             type: String,
             required: true
+        },
+        postError: {
+            type: String,
+            default: null
         }
     },
     methods: {
@@ -170,12 +183,14 @@ Vue.component('qv-comment-editor', {
                     authorName: this.name,
                     authorEmail: this.email,
                     text: this.markdown,
+                    notifyReplies: this.notifyReplies,
                 }
                 this.$emit('post-comment', comment)
                 // TODO: nulling here will cause an issue for the user if the post fails
                 this.name = null
                 this.email = null
                 this.markdown = null
+                this.notifyReplies = false
                 this.error = null
                 this.showingPreview = false
             } else {
@@ -188,6 +203,7 @@ Vue.component('qv-comment-editor', {
             name: null,
             email: null,
             markdown: null,
+            notifyReplies: false,
             showingPreview: false,
             previewStyle: { width: "100%", height: "100px" },
             error: null
@@ -213,8 +229,11 @@ Vue.component('qv-comment-editor', {
                         <div class="qv-text-preview" v-html="preview" v-bind:style="previewStyle"></div>
                     </div>
                 </div>
-                <div class="qv-submit-error" v-if="error">
-                    {{ error }}
+                <label class="qv-notify-replies" v-if="email">
+                    <input type="checkbox" v-model="notifyReplies"> Email me when someone else comments on this page
+                </label>
+                <div class="qv-submit-error" v-if="error || postError">
+                    {{ error || postError }}
                 </div>
                 <input class="qv-submit" type="submit" value="Post comment">
             </form>